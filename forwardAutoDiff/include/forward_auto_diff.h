@@ -0,0 +1,37 @@
+/* C API of forward_auto_diff, built with `cargo build --release --features ffi` */
+#ifndef FORWARD_AUTO_DIFF_H
+#define FORWARD_AUTO_DIFF_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* An expression, owned by the caller until it is passed to expr_free */
+typedef struct Expr Expr;
+
+/* Parse infix notation such as "3*x + sin(y)", or return NULL if it is not valid */
+Expr *expr_parse(const char *input);
+
+/* The simplified partial derivative with respect to variable, as a new expression */
+Expr *expr_diff(const Expr *expr, const char *variable);
+
+/* Evaluate with names[i] set to values[i] for i < n and store the value in *result.
+   Returns 0 on success and -1 if a variable is missing or the result is not finite. */
+int expr_eval(const Expr *expr, const char *const *names, const double *values, size_t n,
+              double *result);
+
+/* Store the partial derivatives with respect to names[0..n] at the same point in gradient.
+   Returns 0 on success and -1 otherwise. */
+int expr_gradient(const Expr *expr, const char *const *names, const double *values, size_t n,
+                  double *gradient);
+
+/* Free an expression from expr_parse or expr_diff; NULL is ignored */
+void expr_free(Expr *expr);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
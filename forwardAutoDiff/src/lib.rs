@@ -0,0 +1,7673 @@
+//! Symbolic expressions with exact derivatives, simplification, parsing and printing
+
+#[cfg(feature = "ndarray")]
+use ndarray::{Array1, ArrayView1, Zip};
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::rc::Rc;
+
+/// Runtime settings of the expression simplifier
+#[derive(Debug, Clone, Copy)]
+pub struct SimplifyConfig {
+    /// Constants within this distance of 0 or 1 are treated as exactly 0 or 1
+    pub tolerance: f64,
+    /// Build expressions exactly as written
+    pub disabled: bool,
+    /// Print every step of `simplified`
+    pub debug: bool,
+}
+impl Default for SimplifyConfig {
+    fn default() -> Self {
+        Self {
+            tolerance: 1e-5,
+            disabled: false,
+            debug: false,
+        }
+    }
+}
+
+thread_local! {
+    static SIMPLIFY_CONFIG: Cell<SimplifyConfig> = Cell::new(SimplifyConfig::default());
+}
+
+/// Simplifier settings used by expressions built on this thread
+pub fn simplify_config() -> SimplifyConfig {
+    SIMPLIFY_CONFIG.get()
+}
+pub fn set_simplify_config(config: SimplifyConfig) {
+    SIMPLIFY_CONFIG.set(config);
+}
+
+/// Value of `f(args)`
+type EvaluationRule = dyn Fn(&[f64]) -> f64;
+/// Derivative of `f(args)`, given the arguments and their derivatives
+type DerivativeRule = dyn Fn(&[Node], &[Node]) -> Node;
+
+/// A function added with `register_function`, usable in expressions through `user_fn`
+#[derive(Clone)]
+struct UserFunction {
+    arity: usize,
+    evaluate: Rc<EvaluationRule>,
+    derivative: Rc<DerivativeRule>,
+}
+
+thread_local! {
+    static USER_FUNCTIONS: RefCell<HashMap<String, UserFunction>> = RefCell::new(HashMap::new());
+}
+
+/// Make `name` available to `user_fn` and the parser on this thread, replacing any earlier
+/// function of the same name
+pub fn register_function(
+    name: &str,
+    arity: usize,
+    evaluate: impl Fn(&[f64]) -> f64 + 'static,
+    derivative: impl Fn(&[Node], &[Node]) -> Node + 'static,
+) {
+    let function = UserFunction {
+        arity,
+        evaluate: Rc::new(evaluate),
+        derivative: Rc::new(derivative),
+    };
+    USER_FUNCTIONS.with_borrow_mut(|functions| functions.insert(name.to_string(), function));
+}
+fn user_function(name: &str) -> Option<UserFunction> {
+    USER_FUNCTIONS.with_borrow(|functions| functions.get(name).cloned())
+}
+
+// Gradients shorter than this are treated as zero when normalizing
+const GRADIENT_NORM_THRESHOLD: f64 = 1e-12;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Operation {
+    Add,
+    Mul,
+    Pow,
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Sinh,
+    Cosh,
+    Tanh,
+    Log,
+    Exp,
+    Sqrt,
+    Atan,
+    Atan2,
+    Abs,
+    Sign,
+    Min,
+    Max,
+    Relu,
+    Sigmoid,
+    Softplus,
+    Erf,
+    Gamma,
+    /// ln|Γ(x)|
+    LnGamma,
+    /// The n-th derivative of the digamma function ψ = Γ'/Γ, taking n and then x
+    Polygamma,
+    Clamp,
+    InRange,
+    /// 1 if the first argument is less than the second, 0 otherwise
+    Lt,
+    /// 1 if the first argument is greater than the second, 0 otherwise
+    Gt,
+    /// The second argument where the first is nonzero, the third otherwise
+    If,
+    /// A function from `register_function`, looked up by name
+    UserFn(String),
+    Var(String),
+    Const(f64),
+    /// A named constant that `partial_derivative_param` can differentiate by
+    Param(String, f64),
+}
+
+/// The bits a constant compares and hashes by: all NaNs are one value, so that equality is
+/// reflexive, and 0 and -0 differ because `1 / 0` and `1 / -0` do
+fn constant_bits(value: f64) -> u64 {
+    if value.is_nan() {
+        f64::NAN.to_bits()
+    } else {
+        value.to_bits()
+    }
+}
+
+/// Constants are equal if they are the same value, with the exceptions of `constant_bits`.
+/// There is no tolerance, since equality within one is not transitive; `tree_diff` compares
+/// constants within the simplifier's tolerance instead.
+impl PartialEq for Operation {
+    fn eq(&self, other: &Operation) -> bool {
+        match (self, other) {
+            (Operation::Const(a), Operation::Const(b)) => constant_bits(*a) == constant_bits(*b),
+            (Operation::Param(a, x), Operation::Param(b, y)) => {
+                a == b && constant_bits(*x) == constant_bits(*y)
+            }
+            (Operation::Var(a), Operation::Var(b))
+            | (Operation::UserFn(a), Operation::UserFn(b)) => a == b,
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+impl Eq for Operation {}
+impl Hash for Operation {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Operation::Const(value) => constant_bits(*value).hash(state),
+            Operation::Param(name, value) => {
+                name.hash(state);
+                constant_bits(*value).hash(state);
+            }
+            Operation::Var(name) | Operation::UserFn(name) => name.hash(state),
+            _ => {}
+        }
+    }
+}
+
+impl Operation {
+    /// Name of the function as accepted by the parsers, e.g. `sin`; `None` for operators and leaves
+    pub fn function_name(&self) -> Option<&str> {
+        match self {
+            Operation::UserFn(name) => Some(name),
+            Operation::Sin => Some("sin"),
+            Operation::Cos => Some("cos"),
+            Operation::Tan => Some("tan"),
+            Operation::Asin => Some("asin"),
+            Operation::Acos => Some("acos"),
+            Operation::Sinh => Some("sinh"),
+            Operation::Cosh => Some("cosh"),
+            Operation::Tanh => Some("tanh"),
+            Operation::Log => Some("log"),
+            Operation::Exp => Some("exp"),
+            Operation::Sqrt => Some("sqrt"),
+            Operation::Atan => Some("atan"),
+            Operation::Atan2 => Some("atan2"),
+            Operation::Abs => Some("abs"),
+            Operation::Sign => Some("sign"),
+            Operation::Min => Some("min"),
+            Operation::Max => Some("max"),
+            Operation::Relu => Some("relu"),
+            Operation::Sigmoid => Some("sigmoid"),
+            Operation::Softplus => Some("softplus"),
+            Operation::Erf => Some("erf"),
+            Operation::Gamma => Some("gamma"),
+            Operation::LnGamma => Some("ln_gamma"),
+            Operation::Polygamma => Some("polygamma"),
+            Operation::Clamp => Some("clamp"),
+            Operation::InRange => Some("in_range"),
+            Operation::Lt => Some("lt"),
+            Operation::Gt => Some("gt"),
+            Operation::If => Some("if"),
+            Operation::Add | Operation::Mul | Operation::Pow => None,
+            Operation::Var(_) | Operation::Const(_) | Operation::Param(..) => None,
+        }
+    }
+
+    /// Name of the operation: `function_name` for functions, and `add`, `mul`, `pow`, `var`,
+    /// `const` or `param` for operators and leaves
+    pub fn name(&self) -> &str {
+        match self {
+            Operation::Add => "add",
+            Operation::Mul => "mul",
+            Operation::Pow => "pow",
+            Operation::Var(_) => "var",
+            Operation::Const(_) => "const",
+            Operation::Param(..) => "param",
+            op => op.function_name().unwrap(),
+        }
+    }
+
+    /// Number of arguments the operation takes; `None` for user functions, which are looked up
+    /// by name
+    fn arity(&self) -> Option<usize> {
+        match self {
+            Operation::Var(_) | Operation::Const(_) | Operation::Param(..) => Some(0),
+            Operation::Add | Operation::Mul | Operation::Pow => Some(2),
+            Operation::UserFn(_) => None,
+            op => function_arity(op.function_name().unwrap()),
+        }
+    }
+
+    /// Rough cost of evaluating this operation, in floating-point operations
+    pub fn flop_cost(&self) -> usize {
+        match self {
+            Operation::Var(_) | Operation::Const(_) | Operation::Param(..) => 0,
+            Operation::Add | Operation::Mul => 1,
+            Operation::Abs | Operation::Sign | Operation::Min | Operation::Max => 1,
+            Operation::Relu => 1,
+            Operation::Clamp | Operation::InRange => 2,
+            Operation::Lt | Operation::Gt | Operation::If => 1,
+            Operation::Sqrt => 5,
+            Operation::Pow => 10,
+            Operation::Sin | Operation::Cos | Operation::Tan | Operation::Exp | Operation::Atan => {
+                15
+            }
+            Operation::Asin | Operation::Acos => 20,
+            Operation::Sinh | Operation::Cosh | Operation::Tanh => 20,
+            Operation::Sigmoid | Operation::Softplus => 20,
+            Operation::Erf | Operation::Gamma | Operation::LnGamma | Operation::Polygamma => 40,
+            Operation::Log | Operation::Atan2 | Operation::UserFn(_) => 20,
+        }
+    }
+
+    /// Why the already evaluated arguments are outside the domain of the operation, if they are.
+    /// Division is a power with exponent -1, so it shows up as a power of zero
+    fn domain_violation(&self, args: &[f64]) -> Option<&'static str> {
+        match self {
+            Operation::Log if args[1] <= 0.0 => Some("logarithm of a non-positive number"),
+            Operation::Log if args[0] <= 0.0 || args[0] == 1.0 => {
+                Some("logarithm to an invalid base")
+            }
+            Operation::Pow if args[0] == 0.0 && args[1] == -1.0 => Some("division by zero"),
+            Operation::Pow if args[0] == 0.0 && args[1] < 0.0 => Some("zero to a negative power"),
+            Operation::Pow if args[0] < 0.0 && float::fract(args[1]) != 0.0 => {
+                Some("negative number to a fractional power")
+            }
+            Operation::Sqrt if args[0] < 0.0 => Some("square root of a negative number"),
+            Operation::Asin | Operation::Acos if float::abs(args[0]) > 1.0 => {
+                Some("inverse sine or cosine outside [-1, 1]")
+            }
+            Operation::Gamma | Operation::LnGamma | Operation::Polygamma
+                if args[args.len() - 1] <= 0.0 && float::fract(args[args.len() - 1]) == 0.0 =>
+            {
+                Some("pole of the gamma function")
+            }
+            Operation::Clamp if args[1] > args[2] || args[1].is_nan() || args[2].is_nan() => {
+                Some("clamp to an empty or NaN range")
+            }
+            _ => None,
+        }
+    }
+
+    /// Value of the operation applied to already evaluated arguments
+    fn apply(&self, args: &[f64]) -> f64 {
+        match self {
+            Operation::Var(name) => panic!("Variable {} has no value without a lookup", name),
+            Operation::Const(value) | Operation::Param(_, value) => *value,
+            Operation::Add => args[0] + args[1],
+            Operation::Mul => args[0] * args[1],
+            Operation::Pow => {
+                if float::fract(args[1]) == 0.0 && float::abs(args[1]) <= i32::MAX as f64 {
+                    float::powi(args[0], args[1] as i32)
+                } else {
+                    float::powf(args[0], args[1])
+                }
+            }
+            Operation::Sin => float::sin(args[0]),
+            Operation::Cos => float::cos(args[0]),
+            Operation::Tan => float::tan(args[0]),
+            Operation::Asin => float::asin(args[0]),
+            Operation::Acos => float::acos(args[0]),
+            Operation::Sinh => float::sinh(args[0]),
+            Operation::Cosh => float::cosh(args[0]),
+            Operation::Tanh => float::tanh(args[0]),
+            Operation::Log => float::log(args[1], args[0]),
+            Operation::Exp => float::exp(args[0]),
+            Operation::Sqrt => float::sqrt(args[0]),
+            Operation::Atan => float::atan(args[0]),
+            Operation::Atan2 => float::atan2(args[0], args[1]),
+            Operation::Abs => float::abs(args[0]),
+            Operation::Sign => signum(args[0]),
+            Operation::Min => args[0].min(args[1]),
+            Operation::Max => args[0].max(args[1]),
+            Operation::Relu => args[0].max(0.0),
+            Operation::Sigmoid => sigmoid_value(args[0]),
+            Operation::Softplus => softplus_value(args[0]),
+            Operation::Erf => erf_value(args[0]),
+            Operation::Gamma => gamma_value(args[0]),
+            Operation::LnGamma => ln_gamma_value(args[0]),
+            Operation::Polygamma => polygamma_value(args[0], args[1]),
+            Operation::Clamp if args[1] <= args[2] => args[0].clamp(args[1], args[2]),
+            Operation::Clamp => f64::NAN,
+            Operation::InRange => {
+                if args[1] < args[0] && args[0] < args[2] {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Operation::Lt => {
+                if args[0] < args[1] {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Operation::Gt => {
+                if args[0] > args[1] {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Operation::If => {
+                if args[0] != 0.0 {
+                    args[1]
+                } else {
+                    args[2]
+                }
+            }
+            Operation::UserFn(name) => match user_function(name) {
+                Some(function) => (function.evaluate)(args),
+                None => panic!("Function {} is not registered", name),
+            },
+        }
+    }
+}
+
+/// The offending subexpression of something that is not a polynomial
+#[derive(Debug)]
+pub struct NotPolynomial(pub String);
+impl fmt::Display for NotPolynomial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is not a polynomial", self.0)
+    }
+}
+
+/// Size of an expression, made by `Node::stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExprStats {
+    /// Nodes in the tree, counting a shared subtree once per use
+    pub nodes: usize,
+    /// Structurally distinct subexpressions, the size of the expression as a graph
+    pub distinct: usize,
+    /// Nodes on the longest path from the root to a leaf
+    pub depth: usize,
+    /// How many nodes there are of each operation, by `Operation::name`, e.g. `mul` or `var`
+    pub operations: BTreeMap<String, usize>,
+}
+
+/// Why `try_evaluate` could not produce a finite value
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    /// A variable with this name has no value
+    MissingVariable(String),
+    /// A subexpression was evaluated outside its domain, e.g. `ln(-1)` or `1 / 0`
+    Domain {
+        expression: String,
+        reason: &'static str,
+    },
+    /// The result is infinite or NaN although no subexpression left its domain
+    NonFinite(f64),
+}
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::MissingVariable(name) => write!(f, "Variable {} not found", name),
+            EvalError::Domain { expression, reason } => write!(f, "{} in {}", reason, expression),
+            EvalError::NonFinite(value) => write!(f, "the result {} is not finite", value),
+        }
+    }
+}
+
+/// With the `serde` feature, nodes serialize as `{"op": ..., "args": [...]}`. Deserializing
+/// restores the saved tree as is, without simplifying it again.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Node {
+    pub op: Operation,
+    /// Subtrees are reference counted, so cloning a node only copies the pointers to its
+    /// arguments, and the copies of `a` and `b` made by the derivative rules share their trees
+    pub args: Vec<Rc<Node>>,
+}
+// Dropping a deep tree recursively would overflow the stack, so take the arguments apart first.
+// Subtrees still shared with another expression are only released, not taken apart.
+impl Drop for Node {
+    fn drop(&mut self) {
+        let mut pending = std::mem::take(&mut self.args);
+        while let Some(node) = pending.pop() {
+            if let Ok(mut node) = Rc::try_unwrap(node) {
+                pending.append(&mut node.args);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.op {
+            Operation::Var(name) | Operation::Param(name, _) => write!(f, "{}", name),
+            Operation::Const(value) => write!(f, "{}", format_constant(*value)),
+            op => {
+                let arguments = self
+                    .args
+                    .iter()
+                    .map(|arg| format!("{:?}", arg))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                match op {
+                    Operation::UserFn(name) => write!(f, "{}({})", name, arguments),
+                    _ => write!(f, "{:?}({})", op, arguments),
+                }
+            }
+        }
+    }
+}
+
+/// Structural equality: the same operations, by the rules of `Operation`'s equality, in the same
+/// shape. It runs without recursion, so deep trees do not overflow the stack, and subtrees the
+/// two expressions share through `Rc` are not compared.
+impl PartialEq for Node {
+    fn eq(&self, other: &Node) -> bool {
+        let mut pending = vec![(self, other)];
+        while let Some((a, b)) = pending.pop() {
+            if a.op != b.op || a.args.len() != b.args.len() {
+                return false;
+            }
+            let args = a.args.iter().zip(&b.args);
+            pending.extend(
+                args.filter(|(x, y)| !Rc::ptr_eq(x, y))
+                    .map(|(x, y)| (&**x, &**y)),
+            );
+        }
+        true
+    }
+}
+impl Eq for Node {}
+impl Hash for Node {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut pending = vec![self];
+        while let Some(node) = pending.pop() {
+            node.op.hash(state);
+            node.args.len().hash(state);
+            pending.extend(node.args.iter().rev().map(|arg| &**arg));
+        }
+    }
+}
+
+/// The order the operands of sums and products are sorted in: constants first, then variables
+/// and parameters by name, then other operations in the order of `BINARY_OPCODES`, then calls
+/// of registered functions by name. Ties are broken by the arguments, from left to right.
+/// A power sorts by its base, right after the base itself, so that `x < x^2 < y`.
+fn canonical_order(a: &Node, b: &Node) -> Ordering {
+    let rank = |op: &Operation| match op {
+        Operation::Const(_) => 0,
+        Operation::Var(_) => 1,
+        Operation::Param(..) => 2,
+        Operation::UserFn(_) => 3 + BINARY_OPCODES.len(),
+        op => 3 + BINARY_OPCODES.iter().position(|code| code == op).unwrap(),
+    };
+    let mut pending = vec![(a, b)];
+    while let Some((a, b)) = pending.pop() {
+        match (&a.op, &b.op) {
+            (Operation::Pow, Operation::Pow) => {}
+            (Operation::Pow, _) => return canonical_order(&a.args[0], b).then(Ordering::Greater),
+            (_, Operation::Pow) => return canonical_order(a, &b.args[0]).then(Ordering::Less),
+            _ => {}
+        }
+        let order = rank(&a.op)
+            .cmp(&rank(&b.op))
+            .then_with(|| match (&a.op, &b.op) {
+                (Operation::Const(x), Operation::Const(y)) => x.total_cmp(y),
+                (Operation::Var(x), Operation::Var(y))
+                | (Operation::Param(x, _), Operation::Param(y, _))
+                | (Operation::UserFn(x), Operation::UserFn(y)) => natural_order(x, y),
+                _ => Ordering::Equal,
+            })
+            .then(a.args.len().cmp(&b.args.len()));
+        if order != Ordering::Equal {
+            return order;
+        }
+        let args = a.args.iter().zip(&b.args).rev();
+        pending.extend(
+            args.filter(|(x, y)| !Rc::ptr_eq(x, y))
+                .map(|(x, y)| (&**x, &**y)),
+        );
+    }
+    Ordering::Equal
+}
+
+/// Names in natural order, comparing runs of digits by their value so that `x_2` < `x_10`
+fn natural_order(a: &str, b: &str) -> Ordering {
+    let digits = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        let mut digits = String::new();
+        while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+            digits.push(digit);
+        }
+        digits
+    };
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        let order = match (a.peek(), b.peek()) {
+            (None, None) => return Ordering::Equal,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, y) = (digits(&mut a), digits(&mut b));
+                let (x_value, y_value) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                (x_value.len().cmp(&y_value.len()))
+                    .then(x_value.cmp(y_value))
+                    .then(x.len().cmp(&y.len()))
+            }
+            (x, y) => x.cmp(&y).then_with(|| {
+                a.next();
+                b.next();
+                Ordering::Equal
+            }),
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+}
+
+/// The order of the operands of a canonical sum or product. Terms of a sum are sorted by their
+/// factors other than a constant coefficient, so that `3 * x` comes next to `x`, and then by the
+/// coefficient. Factors of a product are sorted by `canonical_order`, except that reciprocals
+/// such as `y^(-1)` go last for the product to print as a fraction.
+fn operand_order(op: &Operation, a: &Node, b: &Node) -> Ordering {
+    match op {
+        Operation::Add => term_order(&a.factors(), &b.factors()),
+        _ => (a.is_reciprocal().cmp(&b.is_reciprocal())).then_with(|| canonical_order(a, b)),
+    }
+}
+
+/// `operand_order` of two terms of a sum, given as their `factors`
+fn term_order((ka, a): &(f64, Vec<&Node>), (kb, b): &(f64, Vec<&Node>)) -> Ordering {
+    let factors = a.iter().zip(b).map(|(x, y)| canonical_order(x, y));
+    factors
+        .chain([a.len().cmp(&b.len())])
+        .find(|order| *order != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+        .then(ka.total_cmp(kb))
+}
+
+/// Nodes of an expression with each node before its arguments, made by `Node::iter`
+pub struct PreOrder<'a> {
+    stack: Vec<&'a Node>,
+}
+impl<'a> Iterator for PreOrder<'a> {
+    type Item = &'a Node;
+
+    fn next(&mut self) -> Option<&'a Node> {
+        let node = self.stack.pop()?;
+        self.stack.extend(node.args.iter().rev().map(|arg| &**arg));
+        Some(node)
+    }
+}
+
+/// Nodes of an expression with each node after its arguments, made by `Node::iter_post`
+pub struct PostOrder<'a> {
+    stack: Vec<(&'a Node, bool)>,
+}
+impl<'a> Iterator for PostOrder<'a> {
+    type Item = &'a Node;
+
+    fn next(&mut self) -> Option<&'a Node> {
+        while let Some((node, expanded)) = self.stack.pop() {
+            if expanded {
+                return Some(node);
+            }
+            self.stack.push((node, true));
+            self.stack
+                .extend(node.args.iter().rev().map(|arg| (&**arg, false)));
+        }
+        None
+    }
+}
+
+/// Infix notation with parentheses only where precedence needs them, e.g. `3 * x + 5`
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.infix(false))
+    }
+}
+
+impl Node {
+    pub fn new(op: Operation, args: Vec<Rc<Node>>) -> Self {
+        if simplify_config().disabled {
+            Self { op, args }
+        } else {
+            let node = Self { op, args };
+            node.simplify()
+        }
+    }
+
+    /// Simplify every node, arguments before the operations using them, and repeat until
+    /// the expression stops changing. This simplifies even if the simplifier is disabled.
+    pub fn simplified(&self) -> Node {
+        let config = simplify_config();
+        set_simplify_config(SimplifyConfig {
+            disabled: false,
+            ..config
+        });
+        let mut node = self.simplify_pass();
+        loop {
+            let next = node.simplify_pass();
+            if next == node {
+                break;
+            }
+            node = next;
+        }
+        set_simplify_config(config);
+        node
+    }
+
+    /// One bottom-up pass of `simplified`, without recursion. A subtree shared through `Rc` is
+    /// simplified once, and the result is shared in the same way.
+    fn simplify_pass(&self) -> Node {
+        let debug = simplify_config().debug;
+        let mut done: HashMap<*const Node, Rc<Node>> = HashMap::new();
+        let mut stack = vec![(self, false)];
+        while let Some((node, expanded)) = stack.pop() {
+            if expanded {
+                let args = node
+                    .args
+                    .iter()
+                    .map(|arg| Rc::clone(&done[&Rc::as_ptr(arg)]))
+                    .collect();
+                let simplified = Node::new(node.op.clone(), args);
+                if debug && simplified != *node {
+                    println!("Simplified {:?} to {:?}", node, simplified);
+                }
+                done.insert(node, Rc::new(simplified));
+            } else {
+                stack.push((node, true));
+                let pending = node
+                    .args
+                    .iter()
+                    .filter(|arg| !done.contains_key(&Rc::as_ptr(arg)));
+                stack.extend(pending.map(|arg| (&**arg, false)));
+            }
+        }
+        Rc::unwrap_or_clone(done.remove(&(self as *const Node)).unwrap())
+    }
+
+    /// Simplify by equality saturation: rewrite the expression with the rewrite rules and with
+    /// identities such as distributivity in every possible way, for up to `iterations` rounds,
+    /// then pick the smallest expression found. The result is never larger than the input.
+    pub fn simplify_egraph(&self, iterations: usize) -> Node {
+        let tolerance = simplify_config().tolerance;
+        let mut rules = rewrite_rules();
+        rules.extend(equalities());
+        let mut graph = EGraph::default();
+        let root = self.fold(|node, args| graph.add(node.op.clone(), args));
+        graph.rebuild();
+        for _ in 0..iterations {
+            let best = graph.extract();
+            let mut matches = vec![];
+            for rule in &rules {
+                for class in 0..graph.classes.len() {
+                    if graph.find(class) != class {
+                        continue;
+                    }
+                    for bindings in graph.ematch(&rule.pattern, class, vec![], tolerance) {
+                        if let Some(condition) = rule.condition {
+                            let terms = bindings
+                                .iter()
+                                .map(|(_, class)| graph.term(&best, *class))
+                                .collect::<Vec<Node>>();
+                            let names = bindings.iter().map(|(name, _)| *name);
+                            if !condition(&Bindings(names.zip(&terms).collect())) {
+                                continue;
+                            }
+                        }
+                        matches.push((rule, class, bindings));
+                    }
+                }
+            }
+            let mut changed = false;
+            for (rule, class, bindings) in matches {
+                let rewritten = graph.instantiate(&rule.template, &bindings);
+                changed |= graph.union(class, rewritten);
+            }
+            graph.rebuild();
+            if !changed || graph.memo.len() > EGRAPH_NODE_LIMIT {
+                break;
+            }
+        }
+        graph.term(&graph.extract(), root)
+    }
+
+    /// Simplify the root of the tree: an operation on constants is evaluated, and otherwise the
+    /// first rewrite rule whose pattern matches replaces the node. Replacements are built with
+    /// `Node::new`, so rules keep applying to the new root until none matches
+    fn simplify(self) -> Node {
+        if let Some(value) = self.constant_value() {
+            return c(value);
+        }
+        let tolerance = simplify_config().tolerance;
+        let node = match self.op {
+            Operation::Add | Operation::Mul => self.normalize_chain(tolerance),
+            _ => self,
+        };
+        let rules = REWRITE_RULES.with_borrow(Rc::clone);
+        rules
+            .iter()
+            .find_map(|rule| rule.apply(&node, tolerance))
+            .unwrap_or(node)
+    }
+
+    /// A sum or product in canonical form: nested sums (products) are flattened into one list
+    /// of operands, like operands collected by `collect_like`, the constants among them
+    /// combined into one, and the operands sorted by `operand_order` and nested to the left
+    /// again, so `x * 3 * y` and `y * (3 * x)` are both `3 * x * y`. The constant goes first in
+    /// a product and last in a sum, as in `2 * x + 1`. A constant within the tolerance of 0 (1)
+    /// is dropped, and a product with a constant within the tolerance of 0 is 0.
+    fn normalize_chain(mut self, tolerance: f64) -> Node {
+        let op = self.op.clone();
+        let identity = if let Operation::Add = op { 0.0 } else { 1.0 };
+        let flatten = |node: Rc<Node>| {
+            let mut operands = vec![];
+            let mut pending = vec![node];
+            while let Some(node) = pending.pop() {
+                if node.op == op {
+                    pending.extend(node.args.iter().rev().cloned());
+                } else {
+                    operands.push(node);
+                }
+            }
+            operands
+        };
+        let build = |first: Rc<Node>, rest: Vec<Rc<Node>>| {
+            let chain = rest.into_iter().fold(first, |chain, operand| {
+                Rc::new(Node {
+                    op: op.clone(),
+                    args: vec![chain, operand],
+                })
+            });
+            Rc::unwrap_or_clone(chain)
+        };
+        // A chain with the operand inserted in its place, keeping the part of the chain before
+        // it, or None if the operand is like one of its neighbours there
+        let insert = |chain: Rc<Node>, operand: &Rc<Node>| {
+            let after = |existing: &Node| match existing.op {
+                Operation::Const(_) => matches!(op, Operation::Mul),
+                _ => operand_order(&op, existing, operand) != Ordering::Greater,
+            };
+            let mut passed = vec![];
+            let mut prefix = chain;
+            while prefix.op == op && !after(&prefix.args[1]) {
+                passed.push(Rc::clone(&prefix.args[1]));
+                prefix = Rc::clone(&prefix.args[0]);
+            }
+            let (before, next) = if prefix.op == op {
+                (Some(&prefix.args[1]), passed.last())
+            } else if after(&prefix) {
+                (Some(&prefix), passed.last())
+            } else {
+                (None, Some(&prefix))
+            };
+            if before.into_iter().chain(next).any(|x| x.like(operand, &op)) {
+                return None;
+            }
+            let (first, second) = match before {
+                None => (Rc::clone(operand), prefix),
+                Some(_) => (prefix, Rc::clone(operand)),
+            };
+            passed.push(second);
+            passed.reverse();
+            Some(Rc::new(build(first, passed)))
+        };
+        let mut args = std::mem::take(&mut self.args);
+        if args[0].op != op {
+            args.swap(0, 1);
+        }
+        let other = flatten(args.pop().unwrap());
+        let chain = args.pop().unwrap();
+
+        // Both sides are already canonical, so without constants to combine, the terms of one
+        // side are inserted into the sum on the other. This keeps building a sum term by term
+        // from sorting the whole sum again for every term.
+        if let Operation::Add = op
+            && chain.op == op
+            && !other
+                .iter()
+                .any(|operand| matches!(operand.op, Operation::Const(_)))
+            && let Some(sum) = other.iter().try_fold(Rc::clone(&chain), insert)
+        {
+            return Rc::unwrap_or_clone(sum);
+        }
+
+        let mut operands = vec![];
+        let mut constant = identity;
+        let collected = Node::collect_like(&op, flatten(chain).into_iter().chain(other).collect());
+        for operand in collected
+            .into_iter()
+            .flat_map(|operand| flatten(Rc::new(operand)))
+        {
+            match operand.op {
+                Operation::Const(value) => constant = op.apply(&[constant, value]),
+                _ => operands.push(operand),
+            }
+        }
+        if let Operation::Mul = op
+            && constant.abs() < tolerance
+        {
+            return c(0.0);
+        }
+        // Collected terms of a sum are already in order
+        if let Operation::Mul = op {
+            operands.sort_by(|a, b| operand_order(&op, a, b));
+        }
+        if (constant - identity).abs() >= tolerance || constant.is_nan() || operands.is_empty() {
+            let position = if let Operation::Add = op {
+                operands.len()
+            } else {
+                0
+            };
+            operands.insert(position, Rc::new(c(constant)));
+        }
+        let rest = operands.split_off(1);
+        build(operands.pop().unwrap(), rest)
+    }
+
+    /// The operands of a sum (product) with like operands combined: terms with the same
+    /// `factors` are added up by their coefficients, as in `3 * x + 5 * x = 8 * x`, and factors
+    /// with the same base by their exponents, as in `x * x^2 = x^3`. Operands come out sorted
+    /// so that like ones are next to each other, and terms of a sum by `operand_order`
+    fn collect_like(op: &Operation, operands: Vec<Rc<Node>>) -> Vec<Node> {
+        let mut collected: Vec<Node> = vec![];
+        let mut push =
+            |operand: Node, combine: &dyn Fn(&Node, &Node) -> Node| match collected.last_mut() {
+                Some(last) if last.like(&operand, op) => *last = combine(last, &operand),
+                _ => collected.push(operand),
+            };
+        if let Operation::Add = op {
+            let mut terms = operands
+                .iter()
+                .map(|operand| (operand.factors(), operand))
+                .collect::<Vec<_>>();
+            terms.sort_by(|(a, _), (b, _)| term_order(a, b));
+            // k1 * a + k2 * a = (k1 + k2) * a
+            let add = |a: &Node, b: &Node| {
+                let ((ka, factors), (kb, _)) = (a.factors(), b.factors());
+                (factors.into_iter().cloned()).fold(c(ka + kb), |product, factor| product * factor)
+            };
+            for (_, operand) in terms {
+                push((**operand).clone(), &add);
+            }
+        } else {
+            let mut factors = operands;
+            factors.sort_by(|a, b| canonical_order(a, b));
+            // a^b * a^p = a^(b + p), with a = a^1
+            let multiply = |a: &Node, b: &Node| {
+                let (base, ea) = a.power();
+                pow(base.clone(), ea + b.power().1)
+            };
+            for factor in factors {
+                push(Rc::unwrap_or_clone(factor), &multiply);
+            }
+        }
+        collected
+    }
+
+    /// Value of an operation whose arguments are all constants. Calls of registered functions
+    /// are kept, since the function may be registered again with another definition
+    fn constant_value(&self) -> Option<f64> {
+        if self.args.is_empty() || matches!(self.op, Operation::UserFn(_)) {
+            return None;
+        }
+        let values = self
+            .args
+            .iter()
+            .map(|arg| match arg.op {
+                Operation::Const(value) => Some(value),
+                _ => None,
+            })
+            .collect::<Option<Vec<f64>>>()?;
+        Some(self.op.apply(&values))
+    }
+
+    /// Replace every occurrence of `variable` by `replacement` and simplify the result
+    pub fn substitute(&self, variable: &str, replacement: &Node) -> Node {
+        self.substitute_all(&HashMap::from([(
+            variable.to_string(),
+            replacement.clone(),
+        )]))
+    }
+
+    /// Replace variables by expressions, all at once: a variable introduced by one
+    /// replacement is not replaced again by another
+    pub fn substitute_all(&self, mapping: &HashMap<String, Node>) -> Node {
+        fn replace(node: &Node, mapping: &HashMap<String, Node>) -> Node {
+            match &node.op {
+                Operation::Var(name) if mapping.contains_key(name) => mapping[name].clone(),
+                _ => Node {
+                    op: node.op.clone(),
+                    args: node
+                        .args
+                        .iter()
+                        .map(|arg| Rc::new(replace(arg, mapping)))
+                        .collect(),
+                },
+            }
+        }
+        replace(self, mapping).simplified()
+    }
+
+    /// Compose with inner functions: for `f(u, v)`, binding `u` to `g` and `v` to `h` gives
+    /// `f(g, h)`. Unbound variables are left as they are, so the inner functions may share
+    /// variables with each other and with `f`
+    pub fn compose(&self, bindings: &HashMap<String, Node>) -> Node {
+        self.substitute_all(bindings)
+    }
+
+    /// Structural equality: same operations, constants and variables in the same shape.
+    /// The same as `==`.
+    pub fn same_as(&self, other: &Node) -> bool {
+        self == other
+    }
+
+    /// Probabilistic equivalence: evaluate both expressions at `trials` pseudo-random points with
+    /// every variable in [-10, 10] and check that they agree to a relative 1e-9. Points outside
+    /// the domain of either expression are skipped, so `x/x` and `1` are equivalent. False if
+    /// no point inside both domains is found. A true result is evidence, not a proof.
+    pub fn equiv(&self, other: &Node, trials: usize) -> bool {
+        let mut names = self.free_variables();
+        names.extend(other.free_variables());
+        let mut seed: u64 = 1;
+        let mut random = move || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let mut compared = 0;
+        for _ in 0..trials * 100 {
+            if compared == trials {
+                break;
+            }
+            let at = names
+                .iter()
+                .map(|name| (name.clone(), 20.0 * random() - 10.0))
+                .collect::<HashMap<String, f64>>();
+            if let (Ok(a), Ok(b)) = (self.try_evaluate(&at), other.try_evaluate(&at)) {
+                if (a - b).abs() > 1e-9 * a.abs().max(b.abs()).max(1.0) {
+                    return false;
+                }
+                compared += 1;
+            }
+        }
+        compared > 0
+    }
+
+    /// Where two expressions first differ structurally, or `None` if they have the same shape.
+    /// The path lists argument indices from the root, e.g. `root.1.0`. Constants within the
+    /// simplification tolerance count as equal.
+    pub fn tree_diff(&self, other: &Node) -> Option<String> {
+        fn walk(a: &Node, b: &Node, path: &str, tolerance: f64) -> Option<String> {
+            let differs = match (&a.op, &b.op) {
+                (Operation::Const(x), Operation::Const(y)) => (x - y).abs() >= tolerance,
+                (x, y) => x != y || a.args.len() != b.args.len(),
+            };
+            if differs {
+                return Some(format!("at {}: {:?} vs {:?}", path, a, b));
+            }
+            a.args
+                .iter()
+                .zip(&b.args)
+                .enumerate()
+                .find_map(|(i, (x, y))| walk(x, y, &format!("{}.{}", path, i), tolerance))
+        }
+        walk(self, other, "root", simplify_config().tolerance)
+    }
+
+    /// Pull a factor shared by both products of a sum out of it: `k*a + k*b` becomes `k*(a + b)`.
+    /// The factor may be a constant or any subexpression. `simplify` never distributes, so this
+    /// is not undone by it; an `expand` pass would, so don't run the two to a fixed point.
+    pub fn factor_common(&self) -> Node {
+        let args = self
+            .args
+            .iter()
+            .map(|arg| Rc::new(arg.factor_common()))
+            .collect::<Vec<Rc<Node>>>();
+        let node = Node::new(self.op.clone(), args);
+        if let Operation::Add = node.op
+            && let (Operation::Mul, Operation::Mul) = (&node.args[0].op, &node.args[1].op)
+        {
+            let (left, right) = (&node.args[0].args, &node.args[1].args);
+            for (i, j) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                if left[i].same_as(&right[j]) {
+                    let sum = (*left[1 - i]).clone() + (*right[1 - j]).clone();
+                    return (*left[i]).clone() * sum;
+                }
+            }
+        }
+        node
+    }
+
+    /// Partial derivative wrt. variable, like `partial_derivative` but taking a `&str`
+    pub fn diff(&mut self, variable: &str) -> Node {
+        self.partial_derivative(&variable.to_string())
+    }
+
+    /// The n-th derivative wrt. variable, fully simplified after every step so that repeated
+    /// differentiation does not blow up; the 0-th derivative is the expression itself
+    pub fn nth_derivative(&self, variable: &str, n: usize) -> Node {
+        (0..n).fold(self.clone(), |mut derivative, _| derivative.diff(variable))
+    }
+
+    /// Compute partial derivative wrt. variable
+    #[allow(clippy::ptr_arg)]
+    pub fn partial_derivative(&mut self, variable: &String) -> Node {
+        self.derivative(&Operation::Var(variable.clone()))
+    }
+
+    /// Compute partial derivative wrt. a parameter made with `param`, treating variables as constants
+    pub fn partial_derivative_param(&mut self, param_name: &str) -> Node {
+        self.derivative(&Operation::Param(param_name.to_string(), 0.0))
+    }
+
+    /// The derivative from `derivative_wrt`, `simplified` unless the simplifier is disabled
+    fn derivative(&self, target: &Operation) -> Node {
+        let derivative = self.derivative_wrt(target);
+        if simplify_config().disabled {
+            derivative
+        } else {
+            derivative.simplified()
+        }
+    }
+
+    /// Derivative wrt. the variable or parameter (matched by name) in `target`
+    fn derivative_wrt(&self, target: &Operation) -> Node {
+        match &self.op {
+            Operation::Var(name) => match target {
+                Operation::Var(wrt) if wrt == name => one(),
+                _ => zero(),
+            },
+            Operation::Param(name, _) => match target {
+                Operation::Param(wrt, _) if wrt == name => one(),
+                _ => zero(),
+            },
+            Operation::Const(_) => zero(),
+            // piecewise constant
+            Operation::Sign | Operation::InRange | Operation::Lt | Operation::Gt => zero(),
+            _ => {
+                let derivatives = self
+                    .args
+                    .iter()
+                    .map(|arg| arg.derivative_wrt(target))
+                    .collect::<Vec<Node>>();
+                self.chain_rule(derivatives)
+            }
+        }
+    }
+
+    /// All partial derivatives, keyed by variable name, from a single pass over the tree.
+    /// Each node combines its arguments' derivatives for every variable at once, so shared
+    /// subresults are computed only once rather than once per variable. The pass runs without
+    /// recursion, and subtrees that are shared in memory are differentiated once. Like
+    /// `partial_derivative`, the partials are simplified unless simplification is disabled.
+    pub fn gradient(&self) -> HashMap<String, Node> {
+        let mut known: HashMap<*const Node, Rc<HashMap<String, Node>>> = HashMap::new();
+        let mut gradients: Vec<Rc<HashMap<String, Node>>> = vec![];
+        let mut pending = vec![(self, false)];
+        while let Some((node, expanded)) = pending.pop() {
+            if let Some(gradient) = known.get(&(node as *const Node)) {
+                gradients.push(Rc::clone(gradient));
+                continue;
+            }
+            let gradient = match &node.op {
+                Operation::Var(name) => HashMap::from([(name.clone(), one())]),
+                Operation::Sign | Operation::InRange | Operation::Lt | Operation::Gt => {
+                    HashMap::new()
+                }
+                _ if !expanded => {
+                    pending.push((node, true));
+                    pending.extend(node.args.iter().rev().map(|arg| (&**arg, false)));
+                    continue;
+                }
+                _ => {
+                    let arg_gradients = gradients.split_off(gradients.len() - node.args.len());
+                    let mut names = arg_gradients
+                        .iter()
+                        .flat_map(|gradient| gradient.keys())
+                        .collect::<Vec<&String>>();
+                    names.sort();
+                    names.dedup();
+                    names
+                        .into_iter()
+                        .map(|name| {
+                            let derivatives = arg_gradients
+                                .iter()
+                                .map(|gradient| gradient.get(name).cloned().unwrap_or_else(zero))
+                                .collect::<Vec<Node>>();
+                            (name.clone(), node.chain_rule(derivatives))
+                        })
+                        .collect()
+                }
+            };
+            let gradient = Rc::new(gradient);
+            known.insert(node, Rc::clone(&gradient));
+            gradients.push(gradient);
+        }
+        drop(known);
+        let simplify = !simplify_config().disabled;
+        let mut gradient = Rc::unwrap_or_clone(gradients.pop().unwrap())
+            .into_iter()
+            .map(|(name, derivative)| match simplify {
+                true => (name, derivative.simplified()),
+                false => (name, derivative),
+            })
+            .collect::<HashMap<String, Node>>();
+        for name in self.variables() {
+            gradient.entry(name).or_insert_with(zero);
+        }
+        gradient
+    }
+
+    /// Directional derivative ∇f · direction from a single forward pass, each node's tangent
+    /// built from its arguments' tangents. Variables missing from `direction` are held fixed.
+    pub fn directional_derivative(&self, direction: &HashMap<String, f64>) -> Node {
+        match &self.op {
+            Operation::Var(name) => c(direction.get(name).copied().unwrap_or(0.0)),
+            Operation::Param(..) | Operation::Const(_) => zero(),
+            Operation::Sign | Operation::InRange | Operation::Lt | Operation::Gt => zero(),
+            _ => {
+                let tangents = self
+                    .args
+                    .iter()
+                    .map(|arg| arg.directional_derivative(direction))
+                    .collect::<Vec<Node>>();
+                self.chain_rule(tangents)
+            }
+        }
+    }
+
+    /// Derivative of an operation node, given the derivatives `d` of its arguments
+    fn chain_rule(&self, mut d: Vec<Node>) -> Node {
+        // Move each derivative out instead of copying it; they can be as large as the tree
+        let mut take = |i: usize| std::mem::replace(&mut d[i], zero());
+        match &self.op {
+            Operation::Var(_) | Operation::Param(..) | Operation::Const(_) => zero(),
+            Operation::Add => {
+                // (a + b)' = a' + b'
+                let da = take(0);
+                let db = take(1);
+                da + db
+            }
+            Operation::Mul => {
+                // (a * b)' = a' * b + a * b'
+                let da = take(0);
+                let db = take(1);
+                let a = (*self.args[0]).clone();
+                let b = (*self.args[1]).clone();
+                da * b + a * db
+            }
+            Operation::Pow => {
+                // (a ^ n)' = n * a ^ (n - 1) * a' for a constant n; keeps ln(a) out of the result
+                if let Operation::Const(n) = self.args[1].op {
+                    let da = take(0);
+                    let a = (*self.args[0]).clone();
+                    return n * pow(a, c(n - 1.0)) * da;
+                }
+                // (a ^ b)' = a ^ b * (b' * ln(a) + b * a' * a^-1)
+                let da = take(0);
+                let db = take(1);
+                let a = (*self.args[0]).clone();
+                let b = (*self.args[1]).clone();
+                pow(a.clone(), b.clone()) * (db * ln(a.clone()) + b * da / a)
+            }
+            Operation::Sin => {
+                // (sin(a))' = cos(a) * a'
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                cos(a) * da
+            }
+            Operation::Cos => {
+                // (cos(a))' = -sin(a) * a'
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                -sin(a) * da
+            }
+            Operation::Tan => {
+                // (tan(a))' = a' / cos(a)^2
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                da / powi(cos(a), 2)
+            }
+            Operation::Asin => {
+                // (asin(a))' = a' / sqrt(1 - a^2)
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                da / sqrt(1.0 - powi(a, 2))
+            }
+            Operation::Acos => {
+                // (acos(a))' = -a' / sqrt(1 - a^2)
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                -da / sqrt(1.0 - powi(a, 2))
+            }
+            Operation::Sinh => {
+                // (sinh(a))' = cosh(a) * a'
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                cosh(a) * da
+            }
+            Operation::Cosh => {
+                // (cosh(a))' = sinh(a) * a'
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                sinh(a) * da
+            }
+            Operation::Tanh => {
+                // (tanh(a))' = (1 - tanh(a)^2) * a'
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                (1.0 - powi(tanh(a), 2)) * da
+            }
+            Operation::Log => {
+                // (log_a(b))' = (b'/b * ln(a) - a'/a * ln(b)) / ln(a)^2
+                let da = take(0);
+                let db = take(1);
+                let a = (*self.args[0]).clone();
+                let b = (*self.args[1]).clone();
+                (db / b.clone() * ln(a.clone()) - da / a.clone() * ln(b)) * pow(ln(a), c(-2.0))
+            }
+            Operation::Exp => {
+                // (exp(a))' = exp(a) * a'
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                exp(a) * da
+            }
+            Operation::Sqrt => {
+                // (sqrt(a))' = a' / (2 * sqrt(a))
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                da / (2.0 * sqrt(a))
+            }
+            Operation::Atan => {
+                // (atan(a))' = a' / (1 + a^2)
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                da / (1.0 + pow(a, c(2.0)))
+            }
+            Operation::Atan2 => {
+                // (atan2(a, b))' = (b * a' - a * b') / (a^2 + b^2)
+                let da = take(0);
+                let db = take(1);
+                let a = (*self.args[0]).clone();
+                let b = (*self.args[1]).clone();
+                (b.clone() * da - a.clone() * db) / (pow(a, c(2.0)) + pow(b, c(2.0)))
+            }
+            Operation::Abs => {
+                // (|a|)' = sign(a) * a', using the subgradient 0 at a = 0
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                sign(a) * da
+            }
+            Operation::Sign => zero(),
+            Operation::Relu => {
+                // (relu(a))' = a' where a > 0, and 0 elsewhere, including at a = 0
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                in_range(a, zero(), c(f64::INFINITY)) * da
+            }
+            Operation::Sigmoid => {
+                // (sigmoid(a))' = sigmoid(a) * (1 - sigmoid(a)) * a'
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                sigmoid(a.clone()) * (1.0 - sigmoid(a)) * da
+            }
+            Operation::Softplus => {
+                // (softplus(a))' = sigmoid(a) * a'
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                sigmoid(a) * da
+            }
+            Operation::Erf => {
+                // (erf(a))' = 2/√π * exp(-a^2) * a'
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                std::f64::consts::FRAC_2_SQRT_PI * exp(-powi(a, 2)) * da
+            }
+            Operation::Gamma => {
+                // (Γ(a))' = Γ(a) * ψ(a) * a'
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                gamma(a.clone()) * digamma(a) * da
+            }
+            Operation::LnGamma => {
+                // (ln|Γ(a)|)' = ψ(a) * a'
+                let da = take(0);
+                let a = (*self.args[0]).clone();
+                digamma(a) * da
+            }
+            Operation::Polygamma => {
+                // (ψ^(n)(a))' = ψ^(n+1)(a) * a', for a constant order n
+                let da = take(1);
+                let n = (*self.args[0]).clone();
+                let a = (*self.args[1]).clone();
+                polygamma(n + 1.0, a) * da
+            }
+            Operation::Min | Operation::Max => {
+                // (max(a, b))' = a' where a > b and b' where a < b; (min(a, b))' the other way
+                // round. Where a = b both get weight 1/2, the average of the one-sided
+                // derivatives, like the 0 chosen for |a| at a = 0.
+                let da = take(0);
+                let db = take(1);
+                let a = (*self.args[0]).clone();
+                let b = (*self.args[1]).clone();
+                let s = match self.op {
+                    Operation::Max => sign(a - b),
+                    _ => sign(b - a),
+                };
+                0.5 * (1.0 + s.clone()) * da + 0.5 * (1.0 - s) * db
+            }
+            Operation::Clamp => {
+                // (clamp(a, lo, hi))' = a' inside (lo, hi), lo' below it and hi' above it.
+                // Exactly on a boundary the derivative is 0.
+                let da = take(0);
+                let dlo = take(1);
+                let dhi = take(2);
+                let a = (*self.args[0]).clone();
+                let lo = (*self.args[1]).clone();
+                let hi = (*self.args[2]).clone();
+                in_range(a.clone(), lo.clone(), hi.clone()) * da
+                    + in_range(a.clone(), c(f64::NEG_INFINITY), lo.clone()) * dlo
+                    + in_range(a, hi, c(f64::INFINITY)) * dhi
+            }
+            Operation::UserFn(name) => {
+                // (f(a, b, ...))' by the rule registered for f
+                let Some(function) = user_function(name) else {
+                    panic!("Function {} is not registered", name);
+                };
+                let args = self
+                    .args
+                    .iter()
+                    .map(|arg| (**arg).clone())
+                    .collect::<Vec<Node>>();
+                let derivatives = (0..args.len()).map(&mut take).collect::<Vec<Node>>();
+                (function.derivative)(&args, &derivatives)
+            }
+            Operation::InRange | Operation::Lt | Operation::Gt => zero(),
+            Operation::If => {
+                // (if(cond, a, b))' = if(cond, a', b'); the condition only selects a branch
+                let da = take(1);
+                let db = take(2);
+                if_then_else((*self.args[0]).clone(), da, db)
+            }
+        }
+    }
+
+    pub fn evaluate(&self, variables: &HashMap<String, f64>) -> f64 {
+        let lookup = |name: &str| {
+            variables
+                .get(name)
+                .copied()
+                .ok_or_else(|| EvalError::MissingVariable(name.to_string()))
+        };
+        match self.evaluate_checked(&lookup, false, false) {
+            Ok(value) => value,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    /// Like `evaluate`, but every sum is added up with compensated summation, which keeps the
+    /// rounding error of sums with many terms of varying magnitude independent of their number.
+    /// The other evaluators, e.g. `compile` and `Tape`, add naively like `evaluate`.
+    pub fn evaluate_compensated(&self, variables: &HashMap<String, f64>) -> f64 {
+        let lookup = |name: &str| {
+            variables
+                .get(name)
+                .copied()
+                .ok_or_else(|| EvalError::MissingVariable(name.to_string()))
+        };
+        match self.evaluate_checked(&lookup, false, true) {
+            Ok(value) => value,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    /// Like `evaluate`, but a missing variable, a subexpression outside its domain or a
+    /// non-finite result is reported instead of panicking or returning NaN/inf.
+    /// Check derivatives the same way, e.g. `f.diff("x").try_evaluate(...)`
+    pub fn try_evaluate(&self, variables: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        let lookup = |name: &str| {
+            variables
+                .get(name)
+                .copied()
+                .ok_or_else(|| EvalError::MissingVariable(name.to_string()))
+        };
+        let value = self.evaluate_checked(&lookup, true, false)?;
+        if value.is_finite() {
+            Ok(value)
+        } else {
+            Err(EvalError::NonFinite(value))
+        }
+    }
+
+    /// Specialize for the variables in `bindings`: every subtree whose variables are all bound
+    /// is folded into a constant, and the rest stays symbolic. Parameters are never folded
+    pub fn partial_evaluate(&self, bindings: &HashMap<String, f64>) -> Node {
+        match &self.op {
+            Operation::Var(name) => match bindings.get(name) {
+                Some(value) => c(*value),
+                None => self.clone(),
+            },
+            Operation::Const(_) | Operation::Param(..) => self.clone(),
+            _ => {
+                let args = self
+                    .args
+                    .iter()
+                    .map(|arg| arg.partial_evaluate(bindings))
+                    .collect::<Vec<Node>>();
+                let values = args
+                    .iter()
+                    .map(|arg| match arg.op {
+                        Operation::Const(value) => Some(value),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<f64>>>();
+                match values {
+                    Some(values) => c(self.op.apply(&values)),
+                    None => Node::new(self.op.clone(), args.into_iter().map(Rc::new).collect()),
+                }
+            }
+        }
+    }
+
+    /// Like `evaluate`, but variables missing from the map take the value `default`.
+    /// Note that this also hides typos: a misspelled variable silently gets `default`.
+    pub fn evaluate_with_default(&self, variables: &HashMap<String, f64>, default: f64) -> f64 {
+        self.evaluate_with(&|name| variables.get(name).copied().unwrap_or(default))
+    }
+
+    /// Evaluate, looking up the value of each variable with `lookup`
+    /// Works through the tree with an explicit stack, so deep trees don't overflow the call stack.
+    pub fn evaluate_with(&self, lookup: &dyn Fn(&str) -> f64) -> f64 {
+        match self.evaluate_checked(&|name| Ok(lookup(name)), false, false) {
+            Ok(value) => value,
+            Err(error) => unreachable!("{}", error),
+        }
+    }
+
+    /// Evaluate with a fallible `lookup`; with `check_domain`, stop at the first subexpression
+    /// outside its domain or that turns non-NaN arguments into NaN. With `compensated`, the
+    /// terms of each sum are added up by `compensated_sum`
+    fn evaluate_checked(
+        &self,
+        lookup: &dyn Fn(&str) -> Result<f64, EvalError>,
+        check_domain: bool,
+        compensated: bool,
+    ) -> Result<f64, EvalError> {
+        // Enter a node to schedule its arguments, exit it once their values are computed
+        // An `if` selects its branch once the condition is known, so only one branch is evaluated
+        // A compensated sum adds up the values of its terms at once
+        // The value of a node that may be used more than once is remembered by its address, so
+        // subtrees shared through `Rc`, like those of a derivative, are evaluated once
+        enum Visit<'a> {
+            Enter(&'a Node, bool),
+            Exit(&'a Node),
+            Select(&'a Node),
+            Sum(usize),
+            Remember(&'a Node),
+        }
+        fn enter(arg: &Rc<Node>) -> Visit<'_> {
+            Visit::Enter(arg, Rc::strong_count(arg) > 1)
+        }
+        let mut known: HashMap<*const Node, f64> = HashMap::new();
+        let mut work = vec![Visit::Enter(self, false)];
+        let mut values: Vec<f64> = vec![];
+        while let Some(visit) = work.pop() {
+            match visit {
+                Visit::Enter(node, shared) => {
+                    if let Operation::Var(name) = &node.op {
+                        values.push(lookup(name)?);
+                        continue;
+                    }
+                    if shared {
+                        if let Some(&value) = known.get(&(node as *const Node)) {
+                            values.push(value);
+                            continue;
+                        }
+                        work.push(Visit::Remember(node));
+                    }
+                    if let Operation::Add = node.op
+                        && compensated
+                    {
+                        // Terms are not entered through their `Rc`, so any of them may be shared
+                        let terms = node.summands();
+                        work.push(Visit::Sum(terms.len()));
+                        work.extend(terms.into_iter().rev().map(|term| Visit::Enter(term, true)));
+                        continue;
+                    }
+                    if let Operation::If = node.op {
+                        work.push(Visit::Select(node));
+                        work.push(enter(&node.args[0]));
+                        continue;
+                    }
+                    work.push(Visit::Exit(node));
+                    work.extend(node.args.iter().rev().map(enter));
+                }
+                Visit::Exit(node) => {
+                    let args = values.split_off(values.len() - node.args.len());
+                    let value = node.op.apply(&args);
+                    if check_domain {
+                        let reason = node.op.domain_violation(&args).or_else(|| {
+                            let nan = value.is_nan() && !args.iter().any(|arg| arg.is_nan());
+                            nan.then_some("undefined result")
+                        });
+                        if let Some(reason) = reason {
+                            return Err(EvalError::Domain {
+                                expression: node.to_infix(),
+                                reason,
+                            });
+                        }
+                    }
+                    values.push(value);
+                }
+                Visit::Select(node) => {
+                    let condition = values.pop().unwrap();
+                    let branch = if condition != 0.0 { 1 } else { 2 };
+                    work.push(enter(&node.args[branch]));
+                }
+                Visit::Sum(terms) => {
+                    let terms = values.split_off(values.len() - terms);
+                    values.push(compensated_sum(terms.into_iter()));
+                }
+                Visit::Remember(node) => {
+                    known.insert(node, *values.last().unwrap());
+                }
+            }
+        }
+        Ok(values.pop().unwrap())
+    }
+
+    /// Evaluate lazily for each incoming row, where `var_order` names the variable in each column.
+    /// Variables are resolved to column indices once, before the first row is read.
+    pub fn evaluate_stream<I>(&self, var_order: &[String], rows: I) -> impl Iterator<Item = f64>
+    where
+        I: Iterator<Item = Vec<f64>>,
+    {
+        let bound = BoundExpr {
+            steps: self.plan(var_order),
+        };
+        rows.map(move |row| bound.evaluate(&row))
+    }
+
+    /// Flatten into bytecode for a stack machine, with variables resolved to positions in
+    /// `order`. Common operations get their own instructions, constant integer powers and natural
+    /// logarithms are specialized, and constants are inlined. Panics if a variable is missing.
+    pub fn compile_bytecode(&self, order: &[&str]) -> Program {
+        fn emit(node: &Node, order: &[&str], program: &mut Program, depth: usize) {
+            let mut push = |instruction: Instruction, args: &[&Node]| {
+                for (i, arg) in args.iter().enumerate() {
+                    emit(arg, order, program, depth + i);
+                }
+                program.code.push(instruction);
+                program.stack_size = program.stack_size.max(depth + 1);
+            };
+            let args = node.args.iter().map(|arg| &**arg).collect::<Vec<&Node>>();
+            match (&node.op, args.as_slice()) {
+                (Operation::Var(name), _) => match order.iter().position(|v| v == name) {
+                    Some(index) => push(Instruction::Load(index), &[]),
+                    None => panic!("Variable {} not found", name),
+                },
+                (Operation::Const(value) | Operation::Param(_, value), _) => {
+                    push(Instruction::Const(*value), &[])
+                }
+                (Operation::Add, _) => push(Instruction::Add, &args),
+                (Operation::Mul, _) => push(Instruction::Mul, &args),
+                (Operation::Pow, [base, exponent]) => match exponent.op {
+                    Operation::Const(n)
+                        if float::fract(n) == 0.0 && float::abs(n) <= i32::MAX as f64 =>
+                    {
+                        push(Instruction::Powi(n as i32), &[base])
+                    }
+                    _ => push(Instruction::Apply(Operation::Pow, 2), &args),
+                },
+                (Operation::Log, [base, value]) if matches!(base.op, Operation::Const(b) if b == std::f64::consts::E) => {
+                    push(Instruction::Ln, &[value])
+                }
+                (Operation::Sin, _) => push(Instruction::Sin, &args),
+                (Operation::Cos, _) => push(Instruction::Cos, &args),
+                (Operation::Exp, _) => push(Instruction::Exp, &args),
+                (Operation::Sqrt, _) => push(Instruction::Sqrt, &args),
+                (op, _) => push(Instruction::Apply(op.clone(), args.len()), &args),
+            }
+        }
+        let mut program = Program {
+            code: vec![],
+            stack_size: 0,
+        };
+        emit(self, order, &mut program, 0);
+        program
+    }
+
+    /// Compile into a closure of the values of `variables()`, in that (sorted) order. The
+    /// evaluation order and variable positions are planned once and the stack is reused between
+    /// calls, so the closure can be handed to numerical methods that call it many times.
+    pub fn compile(&self) -> impl Fn(&[f64]) -> f64 + use<> {
+        let names = self.variables();
+        let order = names.iter().map(String::as_str).collect::<Vec<&str>>();
+        let program = self.compile_bytecode(&order);
+        let stack = RefCell::new(Vec::with_capacity(program.stack_size));
+        move |x: &[f64]| program.run_with(x, &mut stack.borrow_mut())
+    }
+
+    /// Resolve variable names to positions in `order` once, for repeated evaluation at points
+    /// given as slices. Panics if a variable is missing from `order`.
+    pub fn bind(&self, order: &[&str]) -> BoundExpr {
+        let order = order
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<String>>();
+        BoundExpr {
+            steps: self.plan(&order),
+        }
+    }
+
+    /// Evaluate at N points given as one column of N values per variable, visiting each node
+    /// once for all points instead of once per point. Panics if the columns differ in length.
+    pub fn evaluate_batch(&self, columns: &HashMap<String, &[f64]>) -> Vec<f64> {
+        let mut lengths = columns.values().map(|values| values.len());
+        let len = lengths.next().unwrap_or(1);
+        assert!(
+            lengths.all(|other| other == len),
+            "all variables need the same number of values"
+        );
+        let var_order = columns.keys().cloned().collect::<Vec<String>>();
+        let mut stack: Vec<Vec<f64>> = vec![];
+        let mut args = vec![];
+        for step in self.plan(&var_order) {
+            let values = match step {
+                PlanStep::Load(column) => columns[&var_order[column]].to_vec(),
+                PlanStep::Apply(op, arity) => {
+                    let inputs = stack.split_off(stack.len() - arity);
+                    (0..len)
+                        .map(|i| {
+                            args.clear();
+                            args.extend(inputs.iter().map(|input| input[i]));
+                            op.apply(&args)
+                        })
+                        .collect()
+                }
+            };
+            stack.push(values);
+        }
+        stack.pop().unwrap()
+    }
+
+    /// The expression as a postfix program reading variables from the columns in `var_order`
+    fn plan(&self, var_order: &[String]) -> Vec<PlanStep> {
+        fn plan(node: &Node, var_order: &[String], steps: &mut Vec<PlanStep>) {
+            for arg in &node.args {
+                plan(arg, var_order, steps);
+            }
+            steps.push(match &node.op {
+                Operation::Var(name) => match var_order.iter().position(|v| v == name) {
+                    Some(column) => PlanStep::Load(column),
+                    None => panic!("Variable {} not found", name),
+                },
+                op => PlanStep::Apply(op.clone(), node.args.len()),
+            });
+        }
+        let mut steps = vec![];
+        plan(self, var_order, &mut steps);
+        steps
+    }
+
+    /// Evaluate elementwise over columns of values, one array per variable.
+    /// Panics if the arrays differ in length.
+    #[cfg(feature = "ndarray")]
+    pub fn evaluate_ndarray(&self, variables: &HashMap<String, ArrayView1<f64>>) -> Array1<f64> {
+        fn eval(
+            node: &Node,
+            variables: &HashMap<String, ArrayView1<f64>>,
+            len: usize,
+        ) -> Array1<f64> {
+            let args = node
+                .args
+                .iter()
+                .map(|arg| eval(arg, variables, len))
+                .collect::<Vec<Array1<f64>>>();
+            match (&node.op, args.as_slice()) {
+                (Operation::Var(name), _) => match variables.get(name) {
+                    Some(values) => values.to_owned(),
+                    None => panic!("Variable {} not found", name),
+                },
+                (op, []) => Array1::from_elem(len, op.apply(&[])),
+                (Operation::Add, [a, b]) => a + b,
+                (Operation::Mul, [a, b]) => a * b,
+                (Operation::Sin, [a]) => a.mapv(f64::sin),
+                (Operation::Cos, [a]) => a.mapv(f64::cos),
+                (op, [a]) => a.mapv(|a| op.apply(&[a])),
+                (op, [a, b]) => Zip::from(a).and(b).map_collect(|&a, &b| op.apply(&[a, b])),
+                (op, [a, b, c]) => Zip::from(a)
+                    .and(b)
+                    .and(c)
+                    .map_collect(|&a, &b, &c| op.apply(&[a, b, c])),
+                (op, args) => panic!("{:?} does not take {} arguments", op, args.len()),
+            }
+        }
+        let mut lengths = variables.values().map(|values| values.len());
+        let len = lengths.next().unwrap_or(1);
+        assert!(
+            lengths.all(|other| other == len),
+            "all variables need the same number of values"
+        );
+        eval(self, variables, len)
+    }
+
+    /// Terms of a (nested) sum, from left to right
+    fn summands(&self) -> Vec<&Node> {
+        let mut terms = vec![];
+        let mut pending = vec![self];
+        while let Some(node) = pending.pop() {
+            match node.op {
+                Operation::Add => pending.extend(node.args.iter().rev().map(|arg| arg.as_ref())),
+                _ => terms.push(node),
+            }
+        }
+        terms
+    }
+
+    /// Whether this is `-1 * a`, printed as `-a`
+    fn is_negation(&self) -> bool {
+        matches!(self.op, Operation::Mul)
+            && matches!(self.args[0].op, Operation::Const(k) if k == -1.0)
+    }
+
+    /// Whether this is `a^-1`, printed as the right-hand side of a division
+    fn is_reciprocal(&self) -> bool {
+        matches!(self.op, Operation::Pow)
+            && matches!(self.args[1].op, Operation::Const(k) if k == -1.0)
+    }
+
+    /// The constant coefficient of a product and its other factors, e.g. `(3, [x, y])` for
+    /// `3 * x * y`; `(1, [a])` for anything else
+    fn factors(&self) -> (f64, Vec<&Node>) {
+        let mut coefficient = 1.0;
+        let mut factors = vec![];
+        let mut pending = vec![self];
+        while let Some(node) = pending.pop() {
+            match node.op {
+                Operation::Mul => pending.extend(node.args.iter().rev().map(|arg| &**arg)),
+                Operation::Const(value) => coefficient *= value,
+                _ => factors.push(node),
+            }
+        }
+        (coefficient, factors)
+    }
+
+    /// A power as its base and exponent, e.g. `(x, 2)` for `x^2`; `(a, 1)` for anything else
+    fn power(&self) -> (&Node, Node) {
+        match self.op {
+            Operation::Pow => (&self.args[0], (*self.args[1]).clone()),
+            _ => (self, c(1.0)),
+        }
+    }
+
+    /// Whether two terms of a sum (`op` is `Add`) have the same `factors`, or two factors of a
+    /// product the same base, so that `collect_like` combines them
+    fn like(&self, other: &Node, op: &Operation) -> bool {
+        match op {
+            Operation::Add => self.factors().1 == other.factors().1,
+            _ => self.power().0 == other.power().0,
+        }
+    }
+
+    /// Whether this is the natural logarithm `ln(a)`, a logarithm to the constant base e
+    fn is_ln(&self) -> bool {
+        matches!(self.op, Operation::Log)
+            && matches!(self.args[0].op, Operation::Const(base) if base == std::f64::consts::E)
+    }
+
+    /// For a term with a negative sign (`-k`, `-a` or `-k * a`), the term without it,
+    /// so that sums print as `x - 2 * y` rather than `x + -2 * y`
+    fn negated_term(&self) -> Option<Node> {
+        match &self.op {
+            Operation::Const(value) if *value < 0.0 => Some(c(-value)),
+            // The coefficient leads the chain of a product, as in (-2 * x) * y
+            Operation::Mul => match self.args[0].negated_term()? {
+                Node {
+                    op: Operation::Const(1.0),
+                    ..
+                } => Some((*self.args[1]).clone()),
+                factor => Some(Node {
+                    op: Operation::Mul,
+                    args: vec![Rc::new(factor), Rc::clone(&self.args[1])],
+                }),
+            },
+            _ => None,
+        }
+    }
+
+    /// Render in infix notation accepted by `parse`, e.g. `3 * x + sin(y)`.
+    /// Parentheses follow the tree shape, so parsing gives back the same tree.
+    pub fn to_infix(&self) -> String {
+        self.infix(true)
+    }
+
+    /// Infix notation, keeping the parentheses that only group sums and products
+    /// like `a + (b + c)` if `exact`
+    fn infix(&self, exact: bool) -> String {
+        // Binding strength of the outermost operator, used to decide on parentheses
+        fn precedence(node: &Node) -> u8 {
+            match &node.op {
+                Operation::Add => 1,
+                Operation::Mul if node.is_negation() => 3,
+                Operation::Mul => 2,
+                Operation::Const(value) if *value < 0.0 => 3,
+                Operation::Pow => 4,
+                _ => 5,
+            }
+        }
+        let wrap = |node: &Node, min_precedence: u8| {
+            if precedence(node) < min_precedence {
+                format!("({})", node.infix(exact))
+            } else {
+                node.infix(exact)
+            }
+        };
+        // Sums and products are associative, so only an exact rendering groups their right side
+        let grouped = u8::from(exact);
+        let args = &self.args;
+        match &self.op {
+            Operation::Var(name) | Operation::Param(name, _) => name.clone(),
+            Operation::Const(value) => format_constant(*value),
+            Operation::Add => match args[1].negated_term() {
+                Some(term) => format!("{} - {}", wrap(&args[0], 1), wrap(&term, 2)),
+                None => format!("{} + {}", wrap(&args[0], 1), wrap(&args[1], 1 + grouped)),
+            },
+            Operation::Mul if self.is_negation() => format!("-{}", wrap(&args[1], 3)),
+            Operation::Mul if args[1].is_reciprocal() => {
+                format!("{} / {}", wrap(&args[0], 2), wrap(&args[1].args[0], 3))
+            }
+            Operation::Mul => format!("{} * {}", wrap(&args[0], 2), wrap(&args[1], 2 + grouped)),
+            Operation::Pow => format!("{}^{}", wrap(&args[0], 5), wrap(&args[1], 4)),
+            Operation::Log if self.is_ln() => {
+                format!("ln({})", args[1].infix(exact))
+            }
+            op => {
+                let arguments = args
+                    .iter()
+                    .map(|arg| arg.infix(exact))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                format!("{}({})", op.function_name().unwrap(), arguments)
+            }
+        }
+    }
+
+    /// Render as space-separated postfix tokens accepted by `parse_postfix`, e.g. `3 x * 5 +`
+    pub fn to_postfix(&self) -> String {
+        let mut tokens = self
+            .args
+            .iter()
+            .map(|arg| arg.to_postfix())
+            .collect::<Vec<String>>();
+        tokens.push(self.symbol());
+        tokens.join(" ")
+    }
+
+    /// The operation on its own: a name, a constant, an operator or a function name
+    fn symbol(&self) -> String {
+        match &self.op {
+            Operation::Var(name) | Operation::Param(name, _) => name.clone(),
+            Operation::Const(value) => format_constant(*value),
+            Operation::Add => "+".to_string(),
+            Operation::Mul => "*".to_string(),
+            Operation::Pow => "^".to_string(),
+            op => op.function_name().unwrap().to_string(),
+        }
+    }
+
+    /// The distinct subexpressions, arguments before their users and `self` last, each with
+    /// the indices of its arguments. Equal subtrees are merged into one entry.
+    fn shared_graph(&self) -> Vec<(&Node, Vec<usize>)> {
+        fn add<'a>(
+            node: &'a Node,
+            graph: &mut Vec<(&'a Node, Vec<usize>)>,
+            ids: &mut HashMap<(Operation, Vec<usize>), usize>,
+        ) -> usize {
+            let args = node
+                .args
+                .iter()
+                .map(|arg| add(arg, graph, ids))
+                .collect::<Vec<usize>>();
+            let key = (node.op.clone(), args.clone());
+            *ids.entry(key).or_insert_with(|| {
+                graph.push((node, args));
+                graph.len() - 1
+            })
+        }
+        let mut graph = vec![];
+        add(self, &mut graph, &mut HashMap::new());
+        graph
+    }
+
+    /// A Graphviz digraph of the computation, with edges from arguments to the operations
+    /// using them. Equal subexpressions are drawn once, so the sharing in a derivative shows.
+    pub fn to_dot(&self) -> String {
+        let graph = self.shared_graph();
+        let mut dot = "digraph {\n    rankdir=BT;\n".to_string();
+        for (id, (node, args)) in graph.iter().enumerate() {
+            let shape = if args.is_empty() { "box" } else { "ellipse" };
+            let label = node.symbol().replace('\\', "\\\\").replace('"', "\\\"");
+            dot += &format!("    n{} [label=\"{}\", shape={}];\n", id, label, shape);
+        }
+        for (id, (node, args)) in graph.iter().enumerate() {
+            // Argument order only matters for operations other than sums and products
+            let ordered = args.len() > 1 && !matches!(node.op, Operation::Add | Operation::Mul);
+            for (i, arg) in args.iter().enumerate() {
+                if ordered {
+                    dot += &format!("    n{} -> n{} [label=\"{}\"];\n", arg, id, i);
+                } else {
+                    dot += &format!("    n{} -> n{};\n", arg, id);
+                }
+            }
+        }
+        dot + "}\n"
+    }
+
+    /// A Mermaid flowchart of the expression tree, for embedding in markdown as a
+    /// ```` ```mermaid ```` block. Unlike `to_dot`, repeated subexpressions are drawn each time.
+    pub fn to_mermaid(&self) -> String {
+        fn add(node: &Node, lines: &mut Vec<String>, next_id: &mut usize) -> usize {
+            let id = *next_id;
+            *next_id += 1;
+            let label = node.symbol().replace('"', "#quot;");
+            if node.args.is_empty() {
+                lines.push(format!("    n{}[\"{}\"]", id, label));
+            } else {
+                lines.push(format!("    n{}((\"{}\"))", id, label));
+            }
+            let ordered =
+                node.args.len() > 1 && !matches!(node.op, Operation::Add | Operation::Mul);
+            for (i, arg) in node.args.iter().enumerate() {
+                let arg = add(arg, lines, next_id);
+                if ordered {
+                    lines.push(format!("    n{} -->|{}| n{}", arg, i, id));
+                } else {
+                    lines.push(format!("    n{} --> n{}", arg, id));
+                }
+            }
+            id
+        }
+        let mut lines = vec!["flowchart BT".to_string()];
+        add(self, &mut lines, &mut 0);
+        lines.join("\n") + "\n"
+    }
+
+    /// Emit a standalone Rust function `fn name(x: f64, y: f64, ...) -> f64` taking the
+    /// variables in sorted order. Erf, the gamma functions and registered functions are emitted
+    /// as calls to free functions of the same name, which the surrounding code has to provide.
+    pub fn to_rust_fn(&self, name: &str) -> String {
+        let variables = self.variables();
+        format!(
+            "fn {}({}) -> f64 {{\n    {}\n}}\n",
+            name,
+            rust_params(&variables),
+            self.to_rust_expr()
+        )
+    }
+
+    /// Like `to_rust_fn`, followed by `fn name_gradient(...) -> [f64; n]` returning the partial
+    /// derivatives in the same order as the parameters
+    pub fn to_rust_fn_with_gradient(&self, name: &str) -> String {
+        let variables = self.variables();
+        let gradient = self.gradient();
+        let partials = variables
+            .iter()
+            .map(|v| format!("        {},\n", gradient[v].simplified().to_rust_expr()))
+            .collect::<String>();
+        format!(
+            "{}\nfn {}_gradient({}) -> [f64; {}] {{\n    [\n{}    ]\n}}\n",
+            self.to_rust_fn(name),
+            name,
+            rust_params(&variables),
+            variables.len(),
+            partials
+        )
+    }
+
+    /// The body of `to_rust_fn`
+    fn to_rust_expr(&self) -> String {
+        // Binding strength of the outermost operator, used to decide on parentheses
+        fn precedence(node: &Node) -> u8 {
+            match &node.op {
+                Operation::Add => 1,
+                Operation::Mul if node.is_negation() => 3,
+                Operation::Mul => 2,
+                Operation::Const(value) if *value < 0.0 => 3,
+                _ => 5,
+            }
+        }
+        fn wrap(node: &Node, min_precedence: u8) -> String {
+            if precedence(node) < min_precedence {
+                format!("({})", node.to_rust_expr())
+            } else {
+                node.to_rust_expr()
+            }
+        }
+        // Float literals need a type to have methods called on them
+        fn receiver(node: &Node) -> String {
+            match node.op {
+                Operation::Const(value) if value.is_finite() && value >= 0.0 => {
+                    format!("{}_f64", node.to_rust_expr())
+                }
+                _ => wrap(node, 5),
+            }
+        }
+        let args = &self.args;
+        let method = |name: &str| format!("{}.{}()", receiver(&args[0]), name);
+        let binary = |name: &str| {
+            let other = args[1].to_rust_expr();
+            format!("{}.{}({})", receiver(&args[0]), name, other)
+        };
+        let indicator = |condition: String| format!("(if {} {{ 1.0 }} else {{ 0.0 }})", condition);
+        match &self.op {
+            Operation::Var(name) => name.clone(),
+            Operation::Const(value) | Operation::Param(_, value) => match *value {
+                std::f64::consts::PI => "std::f64::consts::PI".to_string(),
+                std::f64::consts::E => "std::f64::consts::E".to_string(),
+                value if value.is_nan() => "f64::NAN".to_string(),
+                f64::INFINITY => "f64::INFINITY".to_string(),
+                f64::NEG_INFINITY => "f64::NEG_INFINITY".to_string(),
+                value => format!("{:?}", value),
+            },
+            Operation::Add => match args[1].negated_term() {
+                Some(term) => format!("{} - {}", wrap(&args[0], 1), wrap(&term, 2)),
+                None => format!("{} + {}", wrap(&args[0], 1), wrap(&args[1], 2)),
+            },
+            Operation::Mul if self.is_negation() => format!("-{}", wrap(&args[1], 3)),
+            Operation::Mul if args[1].is_reciprocal() => {
+                format!("{} / {}", wrap(&args[0], 2), wrap(&args[1].args[0], 3))
+            }
+            Operation::Mul => format!("{} * {}", wrap(&args[0], 2), wrap(&args[1], 3)),
+            Operation::Pow => match args[1].op {
+                Operation::Const(-1.0) => method("recip"),
+                Operation::Const(n)
+                    if float::fract(n) == 0.0 && float::abs(n) <= i32::MAX as f64 =>
+                {
+                    format!("{}.powi({})", receiver(&args[0]), n as i32)
+                }
+                _ => binary("powf"),
+            },
+            Operation::Log => match args[0].op {
+                Operation::Const(base) if base == std::f64::consts::E => {
+                    format!("{}.ln()", receiver(&args[1]))
+                }
+                _ => format!("{}.log({})", receiver(&args[1]), args[0].to_rust_expr()),
+            },
+            Operation::Exp => method("exp"),
+            Operation::Sqrt => method("sqrt"),
+            Operation::Sin => method("sin"),
+            Operation::Cos => method("cos"),
+            Operation::Tan => method("tan"),
+            Operation::Asin => method("asin"),
+            Operation::Acos => method("acos"),
+            Operation::Atan => method("atan"),
+            Operation::Sinh => method("sinh"),
+            Operation::Cosh => method("cosh"),
+            Operation::Tanh => method("tanh"),
+            Operation::Abs => method("abs"),
+            Operation::Atan2 => binary("atan2"),
+            Operation::Min => binary("min"),
+            Operation::Max => binary("max"),
+            Operation::Relu => format!("{}.max(0.0)", receiver(&args[0])),
+            Operation::Sigmoid => format!("(1.0 + (-{}).exp()).recip()", wrap(&args[0], 3)),
+            Operation::Sign => format!(
+                "({{ let v = {}; if v > 0.0 {{ 1.0 }} else if v < 0.0 {{ -1.0 }} else {{ 0.0 }} }})",
+                args[0].to_rust_expr()
+            ),
+            Operation::Softplus => format!(
+                "({{ let v = {}; v.max(0.0) + (-v.abs()).exp().ln_1p() }})",
+                args[0].to_rust_expr()
+            ),
+            Operation::Clamp => format!(
+                "({{ let (v, lo, hi): (f64, f64, f64) = ({}, {}, {}); if lo <= hi {{ v.clamp(lo, hi) }} else {{ f64::NAN }} }})",
+                args[0].to_rust_expr(),
+                args[1].to_rust_expr(),
+                args[2].to_rust_expr()
+            ),
+            Operation::InRange => format!(
+                "({{ let v = {}; if {} < v && v < {} {{ 1.0 }} else {{ 0.0 }} }})",
+                args[0].to_rust_expr(),
+                wrap(&args[1], 2),
+                wrap(&args[2], 2)
+            ),
+            Operation::Lt => indicator(format!("{} < {}", wrap(&args[0], 1), wrap(&args[1], 1))),
+            Operation::Gt => indicator(format!("{} > {}", wrap(&args[0], 1), wrap(&args[1], 1))),
+            Operation::If => format!(
+                "(if {} != 0.0 {{ {} }} else {{ {} }})",
+                wrap(&args[0], 1),
+                args[1].to_rust_expr(),
+                args[2].to_rust_expr()
+            ),
+            op => {
+                let arguments = args
+                    .iter()
+                    .map(|arg| arg.to_rust_expr())
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                format!("{}({})", op.function_name().unwrap(), arguments)
+            }
+        }
+    }
+
+    /// Binding strength of the outermost operator in `to_latex` and `to_mathml`, used to decide
+    /// on parentheses
+    fn math_precedence(&self) -> u8 {
+        match &self.op {
+            Operation::Add => 1,
+            Operation::Const(value) if *value < 0.0 => 1,
+            Operation::Mul if self.is_negation() => 1,
+            Operation::Mul => 2,
+            Operation::Lt | Operation::Gt => 0,
+            Operation::Pow | Operation::Exp => 3,
+            _ => 4,
+        }
+    }
+
+    /// Render as LaTeX, e.g. `3 x + \frac{\sin\left(y\right)}{x^{2}}`. Negative powers print
+    /// as fractions, Greek variable names as letters and `x_1` or `x1` with a subscript.
+    pub fn to_latex(&self) -> String {
+        fn wrap(node: &Node, min_precedence: u8) -> String {
+            if node.math_precedence() < min_precedence {
+                format!("\\left({}\\right)", node.to_latex())
+            } else {
+                node.to_latex()
+            }
+        }
+        // `b^-k` as the denominator `b^k`, for negative constant exponents
+        fn denominator(node: &Node) -> Option<String> {
+            match node.op {
+                Operation::Pow => match node.args[1].op {
+                    Operation::Const(-1.0) => Some(node.args[0].to_latex()),
+                    Operation::Const(-0.5) => {
+                        Some(format!("\\sqrt{{{}}}", node.args[0].to_latex()))
+                    }
+                    Operation::Const(k) if k < 0.0 => Some(format!(
+                        "{}^{{{}}}",
+                        wrap(&node.args[0], 4),
+                        format_constant(-k)
+                    )),
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+        let args = &self.args;
+        let function = |name: &str| {
+            let arguments = args
+                .iter()
+                .map(|arg| arg.to_latex())
+                .collect::<Vec<String>>()
+                .join(", ");
+            format!("{}\\left({}\\right)", name, arguments)
+        };
+        match &self.op {
+            Operation::Var(name) | Operation::Param(name, _) => latex_name(name),
+            Operation::Const(value) if *value == std::f64::consts::PI => "\\pi".to_string(),
+            Operation::Const(value) => format_constant(*value),
+            Operation::Add => match args[1].negated_term() {
+                Some(term) => format!("{} - {}", args[0].to_latex(), wrap(&term, 2)),
+                None => format!("{} + {}", args[0].to_latex(), args[1].to_latex()),
+            },
+            Operation::Mul if self.is_negation() => format!("-{}", wrap(&args[1], 2)),
+            Operation::Mul => match denominator(&args[1]) {
+                Some(denominator) => {
+                    format!("\\frac{{{}}}{{{}}}", args[0].to_latex(), denominator)
+                }
+                None => {
+                    let rhs = wrap(&args[1], 2);
+                    // Juxtapose factors, `3 x y`, unless the right one would run into the left
+                    let juxtapose = !rhs.starts_with("\\frac")
+                        && rhs.starts_with(|ch: char| ch.is_alphabetic() || ch == '\\');
+                    if juxtapose {
+                        format!("{} {}", args[0].to_latex(), rhs)
+                    } else {
+                        format!("{} \\cdot {}", wrap(&args[0], 2), rhs)
+                    }
+                }
+            },
+            Operation::Pow => match (denominator(self), &args[1].op) {
+                (Some(denominator), _) => format!("\\frac{{1}}{{{}}}", denominator),
+                (None, Operation::Const(0.5)) => format!("\\sqrt{{{}}}", args[0].to_latex()),
+                (None, _) => format!("{}^{{{}}}", wrap(&args[0], 4), args[1].to_latex()),
+            },
+            Operation::Sin => function("\\sin"),
+            Operation::Cos => function("\\cos"),
+            Operation::Tan => function("\\tan"),
+            Operation::Asin => function("\\arcsin"),
+            Operation::Acos => function("\\arccos"),
+            Operation::Sinh => function("\\sinh"),
+            Operation::Cosh => function("\\cosh"),
+            Operation::Tanh => function("\\tanh"),
+            Operation::Exp => format!("e^{{{}}}", args[0].to_latex()),
+            Operation::Sqrt => format!("\\sqrt{{{}}}", args[0].to_latex()),
+            Operation::Atan => function("\\arctan"),
+            Operation::Atan2 => function("\\operatorname{atan2}"),
+            Operation::Abs => format!("\\left|{}\\right|", args[0].to_latex()),
+            Operation::Sign => function("\\operatorname{sgn}"),
+            Operation::Min => function("\\min"),
+            Operation::Max => function("\\max"),
+            Operation::Relu => function("\\operatorname{ReLU}"),
+            Operation::Sigmoid => function("\\sigma"),
+            Operation::Softplus => function("\\operatorname{softplus}"),
+            Operation::Erf => function("\\operatorname{erf}"),
+            Operation::Gamma => function("\\Gamma"),
+            Operation::LnGamma => format!("\\ln\\Gamma\\left({}\\right)", args[0].to_latex()),
+            Operation::Polygamma => format!(
+                "\\psi^{{({})}}\\left({}\\right)",
+                args[0].to_latex(),
+                args[1].to_latex()
+            ),
+            Operation::UserFn(name) => function(&format!("\\operatorname{{{}}}", name)),
+            Operation::Clamp => function("\\operatorname{clamp}"),
+            Operation::InRange => format!(
+                "\\mathbb{{1}}_{{({}, {})}}\\left({}\\right)",
+                args[1].to_latex(),
+                args[2].to_latex(),
+                args[0].to_latex()
+            ),
+            Operation::Lt => format!("{} < {}", args[0].to_latex(), args[1].to_latex()),
+            Operation::Gt => format!("{} > {}", args[0].to_latex(), args[1].to_latex()),
+            Operation::If => format!(
+                "\\begin{{cases}} {} & \\text{{if }} {} \\\\ {} & \\text{{otherwise}} \\end{{cases}}",
+                args[1].to_latex(),
+                args[0].to_latex(),
+                args[2].to_latex()
+            ),
+            Operation::Log => match args[0].op {
+                Operation::Const(base) if base == std::f64::consts::E => {
+                    format!("\\ln\\left({}\\right)", args[1].to_latex())
+                }
+                _ => format!(
+                    "\\log_{{{}}}\\left({}\\right)",
+                    args[0].to_latex(),
+                    args[1].to_latex()
+                ),
+            },
+        }
+    }
+
+    /// Render as a presentation MathML `<math>` element, laid out like `to_latex`:
+    /// fractions for negative powers and `log` with its base as a subscript
+    pub fn to_mathml(&self) -> String {
+        format!(
+            "<math xmlns=\"http://www.w3.org/1998/Math/MathML\">{}</math>",
+            self.mathml()
+        )
+    }
+
+    /// The contents of `to_mathml`
+    fn mathml(&self) -> String {
+        fn parenthesized(contents: String) -> String {
+            format!("<mrow><mo>(</mo>{}<mo>)</mo></mrow>", contents)
+        }
+        fn wrap(node: &Node, min_precedence: u8) -> String {
+            if node.math_precedence() < min_precedence {
+                parenthesized(node.mathml())
+            } else {
+                node.mathml()
+            }
+        }
+        // `b^-k` as the denominator `b^k`, for negative constant exponents
+        fn denominator(node: &Node) -> Option<String> {
+            match node.op {
+                Operation::Pow => match node.args[1].op {
+                    Operation::Const(-1.0) => Some(node.args[0].mathml()),
+                    Operation::Const(-0.5) => {
+                        Some(format!("<msqrt>{}</msqrt>", node.args[0].mathml()))
+                    }
+                    Operation::Const(k) if k < 0.0 => Some(format!(
+                        "<msup>{}<mn>{}</mn></msup>",
+                        wrap(&node.args[0], 4),
+                        format_constant(-k)
+                    )),
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+        let args = &self.args;
+        // A named function applied to the arguments, `name` already being MathML
+        let function = |name: String| {
+            let arguments = args
+                .iter()
+                .map(|arg| arg.mathml())
+                .collect::<Vec<String>>()
+                .join("<mo>,</mo>");
+            format!(
+                "<mrow>{}<mo>&#x2061;</mo>{}</mrow>",
+                name,
+                parenthesized(arguments)
+            )
+        };
+        let named = |name: &str| function(format!("<mi>{}</mi>", name));
+        match &self.op {
+            Operation::Var(name) | Operation::Param(name, _) => mathml_name(name),
+            Operation::Const(value) if *value == std::f64::consts::PI => "<mi>π</mi>".to_string(),
+            Operation::Const(value) if *value == std::f64::consts::E => "<mi>e</mi>".to_string(),
+            Operation::Const(value) if value.is_nan() => "<mi>NaN</mi>".to_string(),
+            Operation::Const(f64::INFINITY) => "<mi>∞</mi>".to_string(),
+            Operation::Const(f64::NEG_INFINITY) => "<mrow><mo>-</mo><mi>∞</mi></mrow>".to_string(),
+            Operation::Const(value) if *value < 0.0 => {
+                format!(
+                    "<mrow><mo>-</mo><mn>{}</mn></mrow>",
+                    format_constant(-value)
+                )
+            }
+            Operation::Const(value) => format!("<mn>{}</mn>", format_constant(*value)),
+            Operation::Add => match args[1].negated_term() {
+                Some(term) => format!(
+                    "<mrow>{}<mo>-</mo>{}</mrow>",
+                    args[0].mathml(),
+                    wrap(&term, 2)
+                ),
+                None => format!(
+                    "<mrow>{}<mo>+</mo>{}</mrow>",
+                    args[0].mathml(),
+                    args[1].mathml()
+                ),
+            },
+            Operation::Mul if self.is_negation() => {
+                format!("<mrow><mo>-</mo>{}</mrow>", wrap(&args[1], 2))
+            }
+            Operation::Mul => match denominator(&args[1]) {
+                Some(denominator) => format!("<mfrac>{}{}</mfrac>", args[0].mathml(), denominator),
+                None => {
+                    // Juxtapose factors unless a number on the right would run into the left
+                    let times = match args[1].op {
+                        Operation::Const(_) => "&#x22C5;",
+                        _ => "&#x2062;",
+                    };
+                    format!(
+                        "<mrow>{}<mo>{}</mo>{}</mrow>",
+                        wrap(&args[0], 2),
+                        times,
+                        wrap(&args[1], 2)
+                    )
+                }
+            },
+            Operation::Pow => match (denominator(self), &args[1].op) {
+                (Some(denominator), _) => format!("<mfrac><mn>1</mn>{}</mfrac>", denominator),
+                (None, Operation::Const(0.5)) => format!("<msqrt>{}</msqrt>", args[0].mathml()),
+                (None, _) => format!("<msup>{}{}</msup>", wrap(&args[0], 4), args[1].mathml()),
+            },
+            Operation::Exp => format!("<msup><mi>e</mi>{}</msup>", args[0].mathml()),
+            Operation::Sqrt => format!("<msqrt>{}</msqrt>", args[0].mathml()),
+            Operation::Abs => format!("<mrow><mo>|</mo>{}<mo>|</mo></mrow>", args[0].mathml()),
+            Operation::Log if self.is_ln() => format!(
+                "<mrow><mi>ln</mi><mo>&#x2061;</mo>{}</mrow>",
+                parenthesized(args[1].mathml())
+            ),
+            Operation::Log => format!(
+                "<mrow><msub><mi>log</mi>{}</msub><mo>&#x2061;</mo>{}</mrow>",
+                args[0].mathml(),
+                parenthesized(args[1].mathml())
+            ),
+            Operation::Asin => named("arcsin"),
+            Operation::Acos => named("arccos"),
+            Operation::Atan => named("arctan"),
+            Operation::Sign => named("sgn"),
+            Operation::Sigmoid => named("σ"),
+            Operation::Gamma => named("Γ"),
+            Operation::LnGamma => {
+                format!("<mrow><mi>ln</mi><mo>&#x2061;</mo>{}</mrow>", named("Γ"))
+            }
+            Operation::Polygamma => format!(
+                "<mrow><msup><mi>ψ</mi>{}</msup><mo>&#x2061;</mo>{}</mrow>",
+                parenthesized(args[0].mathml()),
+                parenthesized(args[1].mathml())
+            ),
+            Operation::Lt => format!(
+                "<mrow>{}<mo>&lt;</mo>{}</mrow>",
+                args[0].mathml(),
+                args[1].mathml()
+            ),
+            Operation::Gt => format!(
+                "<mrow>{}<mo>&gt;</mo>{}</mrow>",
+                args[0].mathml(),
+                args[1].mathml()
+            ),
+            Operation::If => format!(
+                "<mrow><mo>{{</mo><mtable><mtr><mtd>{}</mtd><mtd><mtext>if&#xA0;</mtext>{}</mtd></mtr>\
+                 <mtr><mtd>{}</mtd><mtd><mtext>otherwise</mtext></mtd></mtr></mtable></mrow>",
+                args[1].mathml(),
+                args[0].mathml(),
+                args[2].mathml()
+            ),
+            op => named(op.function_name().unwrap()),
+        }
+    }
+
+    /// Node count, depth and a histogram of operations, e.g. to see how much differentiation
+    /// grows an expression and how much `simplified` takes back
+    pub fn stats(&self) -> ExprStats {
+        let mut operations = BTreeMap::new();
+        for node in self.iter() {
+            *operations.entry(node.op.name().to_string()).or_insert(0) += 1;
+        }
+        ExprStats {
+            nodes: operations.values().sum(),
+            distinct: self.shared_graph().len(),
+            depth: self.fold(|_, args: Vec<usize>| 1 + args.into_iter().max().unwrap_or(0)),
+            operations,
+        }
+    }
+
+    /// Estimated floating-point operations needed by `evaluate`. Subtrees that are shared in
+    /// memory, like those of a derivative, are counted once.
+    pub fn flop_count(&self) -> usize {
+        let mut counted: HashSet<*const Node> = HashSet::new();
+        let mut pending = vec![self];
+        let mut flops = 0;
+        while let Some(node) = pending.pop() {
+            if counted.insert(node) {
+                flops += node.op.flop_cost();
+                pending.extend(node.args.iter().map(|arg| &**arg));
+            }
+        }
+        flops
+    }
+
+    /// Coefficients `[a0, a1, a2, ...]` of an expression polynomial in `variable`.
+    /// Other variables, non-integer or negative powers and functions like `sin` are rejected.
+    pub fn poly_coeffs(&self, variable: &str) -> Result<Vec<f64>, NotPolynomial> {
+        fn multiply(a: &[f64], b: &[f64]) -> Vec<f64> {
+            let mut product = vec![0.0; a.len() + b.len() - 1];
+            for (i, x) in a.iter().enumerate() {
+                for (j, y) in b.iter().enumerate() {
+                    product[i + j] += x * y;
+                }
+            }
+            product
+        }
+        let not_polynomial = || NotPolynomial(format!("{:?}", self));
+        let mut coeffs = match &self.op {
+            Operation::Const(value) | Operation::Param(_, value) => vec![*value],
+            Operation::Var(name) if name == variable => vec![0.0, 1.0],
+            Operation::Add => {
+                let a = self.args[0].poly_coeffs(variable)?;
+                let b = self.args[1].poly_coeffs(variable)?;
+                (0..a.len().max(b.len()))
+                    .map(|i| a.get(i).unwrap_or(&0.0) + b.get(i).unwrap_or(&0.0))
+                    .collect()
+            }
+            Operation::Mul => multiply(
+                &self.args[0].poly_coeffs(variable)?,
+                &self.args[1].poly_coeffs(variable)?,
+            ),
+            Operation::Pow => match self.args[1].op {
+                Operation::Const(n) if n >= 0.0 && n.fract() == 0.0 => {
+                    let base = self.args[0].poly_coeffs(variable)?;
+                    (0..n as usize).fold(vec![1.0], |acc, _| multiply(&acc, &base))
+                }
+                _ => return Err(not_polynomial()),
+            },
+            _ => return Err(not_polynomial()),
+        };
+        while coeffs.len() > 1 && coeffs.last() == Some(&0.0) {
+            coeffs.pop();
+        }
+        Ok(coeffs)
+    }
+
+    /// Call `f` on every node of the expression, each node before its arguments. A subtree
+    /// shared through `Rc` is visited once per use, as if the expression were a plain tree
+    pub fn visit(&self, f: impl FnMut(&Node)) {
+        self.iter().for_each(f);
+    }
+
+    /// Iterate over the nodes of the expression in pre-order, starting with the root. Like
+    /// `visit`, shared subtrees come up once per use
+    pub fn iter(&self) -> PreOrder<'_> {
+        PreOrder { stack: vec![self] }
+    }
+
+    /// Iterate over the nodes of the expression in post-order, so every argument comes before
+    /// its operation and the root is last
+    pub fn iter_post(&self) -> PostOrder<'_> {
+        PostOrder {
+            stack: vec![(self, false)],
+        }
+    }
+
+    /// Rebuild the expression bottom-up: `f` gets each node with its arguments already mapped
+    /// and returns its replacement. Rebuilt nodes go through `Node::new`, so replacing a
+    /// variable by a constant folds the operations above it
+    pub fn map(&self, mut f: impl FnMut(Node) -> Node) -> Node {
+        self.fold(|node, args| {
+            f(Node::new(
+                node.op.clone(),
+                args.into_iter().map(Rc::new).collect(),
+            ))
+        })
+    }
+
+    /// Reduce the expression bottom-up: `f` gets each node and the results for its arguments,
+    /// in order. Runs without recursion, so deep trees do not overflow the stack
+    pub fn fold<T>(&self, mut f: impl FnMut(&Node, Vec<T>) -> T) -> T {
+        let mut stack = vec![(self, false)];
+        let mut results: Vec<T> = vec![];
+        while let Some((node, expanded)) = stack.pop() {
+            if expanded {
+                let args = results.split_off(results.len() - node.args.len());
+                results.push(f(node, args));
+            } else {
+                stack.push((node, true));
+                stack.extend(node.args.iter().rev().map(|arg| (&**arg, false)));
+            }
+        }
+        results.pop().unwrap()
+    }
+
+    /// Names of all variables in the expression, sorted and without duplicates
+    pub fn variables(&self) -> Vec<String> {
+        self.free_variables().into_iter().collect()
+    }
+
+    /// The set of variables the expression depends on. Parameters are not included. Subtrees
+    /// that are shared in memory are looked at once
+    pub fn free_variables(&self) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        let mut seen: HashSet<*const Node> = HashSet::new();
+        let mut pending = vec![self];
+        while let Some(node) = pending.pop() {
+            match &node.op {
+                Operation::Var(name) => {
+                    names.insert(name.clone());
+                }
+                _ if seen.insert(node) => pending.extend(node.args.iter().map(|arg| &**arg)),
+                _ => {}
+            }
+        }
+        names
+    }
+
+    /// L2 norm of the gradient at a point
+    pub fn gradient_norm(&mut self, at: &HashMap<String, f64>) -> f64 {
+        self.gradient()
+            .values()
+            .map(|derivative| derivative.evaluate(at).powi(2))
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    /// Unit-length gradient at a point, or all zeros if the gradient (nearly) vanishes
+    pub fn normalized_gradient(&mut self, at: &HashMap<String, f64>) -> HashMap<String, f64> {
+        let gradient = self
+            .gradient()
+            .into_iter()
+            .map(|(name, derivative)| (name, derivative.evaluate(at)))
+            .collect::<HashMap<String, f64>>();
+        let norm = gradient.values().map(|g| g.powi(2)).sum::<f64>().sqrt();
+        gradient
+            .into_iter()
+            .map(|(name, g)| {
+                if norm < GRADIENT_NORM_THRESHOLD {
+                    (name, 0.0)
+                } else {
+                    (name, g / norm)
+                }
+            })
+            .collect()
+    }
+}
+
+/// A vector-valued function, one expression per component
+#[derive(Debug, Clone)]
+pub struct VectorExpr {
+    pub components: Vec<Node>,
+}
+
+impl VectorExpr {
+    pub fn new(components: Vec<Node>) -> VectorExpr {
+        VectorExpr { components }
+    }
+
+    /// Names of the variables of all components, sorted and without duplicates. These are the
+    /// columns of the `jacobian`
+    pub fn variables(&self) -> Vec<String> {
+        let mut names = self
+            .components
+            .iter()
+            .flat_map(|component| component.variables())
+            .collect::<Vec<String>>();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Value of each component
+    pub fn evaluate(&self, variables: &HashMap<String, f64>) -> Vec<f64> {
+        self.components
+            .iter()
+            .map(|component| component.evaluate(variables))
+            .collect()
+    }
+
+    /// Partial derivatives of each component (rows) with respect to each of the `variables`
+    /// (columns). A component that does not depend on a variable has 0 in its column
+    pub fn jacobian(&mut self) -> Vec<Vec<Node>> {
+        let names = self.variables();
+        self.components
+            .iter_mut()
+            .map(|component| {
+                names
+                    .iter()
+                    .map(|name| component.partial_derivative(name))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Forward evaluation trace of an expression at one point, for repeated reverse-mode sweeps.
+/// Entries are in post-order, so every argument comes before its operation and the root is last.
+pub struct Tape {
+    entries: Vec<TapeEntry>,
+}
+
+struct TapeEntry {
+    op: Operation,
+    args: Vec<usize>,
+    value: f64,
+}
+
+impl Tape {
+    /// Evaluate `node` at `at` once, keeping the value of every subexpression. Records without
+    /// recursion, and subtrees that are shared in memory, like those of a derivative, get a
+    /// single entry, so the backward sweep also visits them once.
+    pub fn record(node: &Node, at: &HashMap<String, f64>) -> Tape {
+        let mut recorded: HashMap<*const Node, usize> = HashMap::new();
+        let mut entries: Vec<TapeEntry> = Vec::new();
+        let mut pending = vec![(node, false)];
+        while let Some((node, expanded)) = pending.pop() {
+            if recorded.contains_key(&(node as *const Node)) {
+                continue;
+            }
+            if !expanded {
+                pending.push((node, true));
+                pending.extend(node.args.iter().rev().map(|arg| (&**arg, false)));
+                continue;
+            }
+            let args = node
+                .args
+                .iter()
+                .map(|arg| recorded[&Rc::as_ptr(arg)])
+                .collect::<Vec<usize>>();
+            let values = args.iter().map(|&i| entries[i].value).collect::<Vec<f64>>();
+            let value = match &node.op {
+                Operation::Var(_) => node.evaluate(at),
+                op => op.apply(&values),
+            };
+            entries.push(TapeEntry {
+                op: node.op.clone(),
+                args,
+                value,
+            });
+            recorded.insert(node, entries.len() - 1);
+        }
+        Tape { entries }
+    }
+
+    /// Value of the recorded expression
+    pub fn value(&self) -> f64 {
+        self.entries.last().unwrap().value
+    }
+
+    /// Gradient by a single backward sweep over the recorded values; nothing is re-evaluated
+    pub fn grad(&self) -> HashMap<String, f64> {
+        let mut gradient = HashMap::new();
+        for (entry, adjoint) in self.entries.iter().zip(self.backward()) {
+            if let Operation::Var(name) = &entry.op {
+                *gradient.entry(name.clone()).or_insert(0.0) += adjoint;
+            }
+        }
+        gradient
+    }
+
+    /// Reverse sweep: the derivative of the output with respect to every recorded entry,
+    /// in recording order. Costs a small multiple of one evaluation, however many inputs there are.
+    pub fn backward(&self) -> Vec<f64> {
+        let mut adjoints = vec![0.0; self.entries.len()];
+        *adjoints.last_mut().unwrap() = 1.0;
+        for (i, entry) in self.entries.iter().enumerate().rev() {
+            let adjoint = adjoints[i];
+            let args = entry
+                .args
+                .iter()
+                .map(|&arg| self.entries[arg].value)
+                .collect::<Vec<f64>>();
+            // Nothing flows out of an entry with a zero adjoint, such as the branch an `if` did not
+            // take, even where its own derivatives are infinite or undefined
+            if adjoint == 0.0 {
+                continue;
+            }
+            for (arg, local) in entry.args.iter().zip(self.local_derivatives(entry, &args)) {
+                adjoints[*arg] += adjoint * local;
+            }
+        }
+        adjoints
+    }
+
+    /// Derivatives of one operation with respect to each of its arguments
+    fn local_derivatives(&self, entry: &TapeEntry, args: &[f64]) -> Vec<f64> {
+        let indicator = |condition: bool| if condition { 1.0 } else { 0.0 };
+        match &entry.op {
+            Operation::Var(_) | Operation::Const(_) | Operation::Param(..) => vec![],
+            Operation::Add => vec![1.0, 1.0],
+            Operation::Mul => vec![args[1], args[0]],
+            Operation::Pow => {
+                let (a, b) = (args[0], args[1]);
+                // A constant exponent has no derivative; skip ln(a), which may not exist
+                let db = match self.entries[entry.args[1]].op {
+                    Operation::Const(_) => 0.0,
+                    _ => entry.value * a.ln(),
+                };
+                vec![b * a.powf(b - 1.0), db]
+            }
+            Operation::Sin => vec![args[0].cos()],
+            Operation::Cos => vec![-args[0].sin()],
+            Operation::Tan => vec![1.0 + entry.value.powi(2)],
+            Operation::Asin => vec![1.0 / (1.0 - args[0].powi(2)).sqrt()],
+            Operation::Acos => vec![-1.0 / (1.0 - args[0].powi(2)).sqrt()],
+            Operation::Sinh => vec![args[0].cosh()],
+            Operation::Cosh => vec![args[0].sinh()],
+            Operation::Tanh => vec![1.0 - entry.value.powi(2)],
+            Operation::Log => {
+                let (a, b) = (args[0], args[1]);
+                vec![-entry.value / (a * a.ln()), 1.0 / (b * a.ln())]
+            }
+            Operation::Exp => vec![entry.value],
+            Operation::Sqrt => vec![0.5 / entry.value],
+            Operation::Atan => vec![1.0 / (1.0 + args[0].powi(2))],
+            Operation::Atan2 => {
+                let (y, x) = (args[0], args[1]);
+                let r2 = x.powi(2) + y.powi(2);
+                vec![x / r2, -y / r2]
+            }
+            Operation::Abs => vec![signum(args[0])],
+            Operation::Relu => vec![if args[0] > 0.0 { 1.0 } else { 0.0 }],
+            Operation::Sigmoid => vec![entry.value * (1.0 - entry.value)],
+            Operation::Softplus => vec![sigmoid_value(args[0])],
+            Operation::Erf => {
+                vec![std::f64::consts::FRAC_2_SQRT_PI * float::exp(-args[0].powi(2))]
+            }
+            Operation::Gamma => vec![entry.value * polygamma_value(0.0, args[0])],
+            Operation::LnGamma => vec![polygamma_value(0.0, args[0])],
+            Operation::Polygamma => vec![0.0, polygamma_value(args[0] + 1.0, args[1])],
+            Operation::Min | Operation::Max => {
+                let s = match entry.op {
+                    Operation::Max => signum(args[0] - args[1]),
+                    _ => signum(args[1] - args[0]),
+                };
+                vec![0.5 * (1.0 + s), 0.5 * (1.0 - s)]
+            }
+            Operation::Sign | Operation::InRange => vec![0.0; args.len()],
+            Operation::Lt | Operation::Gt => vec![0.0; args.len()],
+            Operation::If => {
+                let taken = args[0] != 0.0;
+                vec![0.0, indicator(taken), indicator(!taken)]
+            }
+            Operation::UserFn(name) => {
+                // The registered rule with one argument's derivative set to 1 at a time
+                let function = user_function(name).unwrap();
+                let at = args.iter().map(|&value| c(value)).collect::<Vec<Node>>();
+                (0..args.len())
+                    .map(|i| {
+                        let seeds = (0..args.len())
+                            .map(|j| if i == j { one() } else { zero() })
+                            .collect::<Vec<Node>>();
+                        (function.derivative)(&at, &seeds).evaluate(&HashMap::new())
+                    })
+                    .collect()
+            }
+            Operation::Clamp => {
+                let (a, lo, hi) = (args[0], args[1], args[2]);
+                vec![
+                    indicator(lo < a && a < hi),
+                    indicator(a < lo),
+                    indicator(a > hi),
+                ]
+            }
+        }
+    }
+}
+
+/// `x: f64, y: f64, ...` for the parameter list of generated Rust functions
+fn rust_params(variables: &[String]) -> String {
+    variables
+        .iter()
+        .map(|v| format!("{}: f64", v))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// An expression with its variables resolved to slice indices, made by `Node::bind`
+pub struct BoundExpr {
+    steps: Vec<PlanStep>,
+}
+
+impl BoundExpr {
+    /// Value at `x`, where `x[i]` is the value of the i-th variable in the binding order
+    pub fn evaluate(&self, x: &[f64]) -> f64 {
+        let mut stack: Vec<f64> = Vec::with_capacity(self.steps.len());
+        for step in &self.steps {
+            let value = match step {
+                PlanStep::Load(index) => x[*index],
+                PlanStep::Apply(op, arity) => {
+                    let start = stack.len() - arity;
+                    let value = op.apply(&stack[start..]);
+                    stack.truncate(start);
+                    value
+                }
+            };
+            stack.push(value);
+        }
+        stack[0]
+    }
+}
+
+/// Index of a node in an `ExprArena`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NodeId(usize);
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct ArenaNode {
+    op: Operation,
+    /// Where the arguments start in `ExprArena::args`
+    first_arg: usize,
+    arity: usize,
+}
+
+/// Expression graph stored in two flat vectors, the nodes and their arguments as `NodeId`s,
+/// instead of a node per allocation. Arguments always come before the nodes using them, so
+/// algorithms over the graph are plain loops rather than recursion, and the arena serializes
+/// as is with the `serde` feature.
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExprArena {
+    nodes: Vec<ArenaNode>,
+    args: Vec<NodeId>,
+}
+
+impl ExprArena {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an operation on nodes already in the arena
+    pub fn push(&mut self, op: Operation, args: &[NodeId]) -> NodeId {
+        assert!(
+            args.iter().all(|arg| arg.0 < self.nodes.len()),
+            "the arguments must already be in the arena"
+        );
+        self.nodes.push(ArenaNode {
+            op,
+            first_arg: self.args.len(),
+            arity: args.len(),
+        });
+        self.args.extend_from_slice(args);
+        NodeId(self.nodes.len() - 1)
+    }
+
+    /// Copy a tree into the arena without recursion. Subtrees that are shared in memory,
+    /// like those of a derivative, are added once.
+    pub fn add(&mut self, node: &Node) -> NodeId {
+        let mut added: HashMap<*const Node, NodeId> = HashMap::new();
+        let mut ids = vec![];
+        let mut pending = vec![(node, false)];
+        while let Some((node, expanded)) = pending.pop() {
+            if let Some(&id) = added.get(&(node as *const Node)) {
+                ids.push(id);
+            } else if expanded {
+                let start = ids.len() - node.args.len();
+                let id = self.push(node.op.clone(), &ids[start..]);
+                ids.truncate(start);
+                ids.push(id);
+                added.insert(node, id);
+            } else {
+                pending.push((node, true));
+                pending.extend(node.args.iter().rev().map(|arg| (&**arg, false)));
+            }
+        }
+        ids[0]
+    }
+
+    pub fn op(&self, id: NodeId) -> &Operation {
+        &self.nodes[id.0].op
+    }
+
+    pub fn args(&self, id: NodeId) -> &[NodeId] {
+        let node = &self.nodes[id.0];
+        &self.args[node.first_arg..node.first_arg + node.arity]
+    }
+
+    /// All nodes in order, each after its arguments
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &Operation, &[NodeId])> {
+        (0..self.nodes.len()).map(|i| (NodeId(i), self.op(NodeId(i)), self.args(NodeId(i))))
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Which nodes the roots depend on, found in one backward pass
+    fn reachable(&self, roots: &[NodeId]) -> Vec<bool> {
+        let last = roots.iter().map(|id| id.0 + 1).max().unwrap_or(0);
+        let mut reachable = vec![false; last];
+        for id in roots {
+            reachable[id.0] = true;
+        }
+        for i in (0..last).rev() {
+            if reachable[i] {
+                for arg in self.args(NodeId(i)) {
+                    reachable[arg.0] = true;
+                }
+            }
+        }
+        reachable
+    }
+
+    /// The node as a tree, in which each node of the arena is a single `Node` shared by all
+    /// of its uses
+    pub fn to_node(&self, id: NodeId) -> Node {
+        let mut nodes: Vec<Option<Rc<Node>>> = vec![None; id.0 + 1];
+        for (i, reachable) in self.reachable(&[id]).into_iter().enumerate() {
+            if reachable {
+                let args = self.args(NodeId(i)).iter();
+                nodes[i] = Some(Rc::new(Node {
+                    op: self.nodes[i].op.clone(),
+                    args: args.map(|arg| nodes[arg.0].clone().unwrap()).collect(),
+                }));
+            }
+        }
+        Rc::unwrap_or_clone(nodes.swap_remove(id.0).unwrap())
+    }
+
+    /// Values of several nodes, e.g. the components of a gradient, computing every node they
+    /// depend on a single time
+    pub fn evaluate(&self, roots: &[NodeId], vars: &HashMap<String, f64>) -> Vec<f64> {
+        let reachable = self.reachable(roots);
+        let mut values: Vec<f64> = Vec::with_capacity(reachable.len());
+        let mut args = vec![];
+        for (i, reachable) in reachable.into_iter().enumerate() {
+            let value = match &self.nodes[i].op {
+                _ if !reachable => f64::NAN,
+                Operation::Var(name) => match vars.get(name) {
+                    Some(value) => *value,
+                    None => panic!("{}", EvalError::MissingVariable(name.clone())),
+                },
+                op => {
+                    args.clear();
+                    args.extend(self.args(NodeId(i)).iter().map(|arg| values[arg.0]));
+                    op.apply(&args)
+                }
+            };
+            values.push(value);
+        }
+        roots.iter().map(|id| values[id.0]).collect()
+    }
+}
+
+/// Hash-consing table: structurally identical subexpressions are stored once, so the copies of
+/// `a` and `b` made by the product and power rules collapse into a single node each.
+#[derive(Default)]
+pub struct Interner {
+    arena: ExprArena,
+    ids: HashMap<(Operation, Vec<NodeId>), NodeId>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an expression without recursion, reusing the nodes of the subexpressions seen
+    /// before. Subtrees that are shared in memory are hashed once, however often they are used.
+    pub fn intern(&mut self, node: &Node) -> NodeId {
+        let mut interned: HashMap<*const Node, NodeId> = HashMap::new();
+        let mut ids = vec![];
+        let mut pending = vec![(node, false)];
+        while let Some((node, expanded)) = pending.pop() {
+            if let Some(&id) = interned.get(&(node as *const Node)) {
+                ids.push(id);
+            } else if expanded {
+                let args = ids.split_off(ids.len() - node.args.len());
+                let id = *self
+                    .ids
+                    .entry((node.op.clone(), args))
+                    .or_insert_with_key(|(op, args)| self.arena.push(op.clone(), args));
+                ids.push(id);
+                interned.insert(node, id);
+            } else {
+                pending.push((node, true));
+                pending.extend(node.args.iter().rev().map(|arg| (&**arg, false)));
+            }
+        }
+        ids[0]
+    }
+
+    /// The distinct subexpressions, which evaluate each one once
+    pub fn arena(&self) -> &ExprArena {
+        &self.arena
+    }
+
+    /// Number of distinct subexpressions
+    pub fn len(&self) -> usize {
+        self.arena.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.arena.is_empty()
+    }
+
+    /// The expression as a tree again, in which each distinct subexpression is a single node
+    /// shared by all of its uses
+    pub fn get(&self, id: NodeId) -> Node {
+        self.arena.to_node(id)
+    }
+
+    /// Values of several expressions, e.g. the components of a gradient, computing every
+    /// subexpression they share a single time
+    pub fn evaluate(&self, roots: &[NodeId], vars: &HashMap<String, f64>) -> Vec<f64> {
+        self.arena.evaluate(roots, vars)
+    }
+}
+
+/// Bytecode for a stack machine, made by `Node::compile_bytecode`
+pub struct Program {
+    code: Vec<Instruction>,
+    /// The most values on the stack at any point while running
+    stack_size: usize,
+}
+
+/// One bytecode instruction: each pops its arguments and pushes its result
+#[derive(Debug)]
+enum Instruction {
+    Const(f64),
+    /// Push the value at this index of the input
+    Load(usize),
+    Add,
+    Mul,
+    Powi(i32),
+    Ln,
+    Sin,
+    Cos,
+    Exp,
+    Sqrt,
+    /// Any other operation, with its number of arguments
+    Apply(Operation, usize),
+}
+
+impl Program {
+    /// Run the program with `x[i]` as the value of the i-th variable in the compilation order
+    pub fn run(&self, x: &[f64]) -> f64 {
+        self.run_with(x, &mut Vec::with_capacity(self.stack_size))
+    }
+
+    /// Like `run`, with `stack` as scratch space
+    fn run_with(&self, x: &[f64], stack: &mut Vec<f64>) -> f64 {
+        stack.clear();
+        for instruction in &self.code {
+            let value = match instruction {
+                Instruction::Const(value) => *value,
+                Instruction::Load(index) => x[*index],
+                Instruction::Add => {
+                    let b = stack.pop().unwrap();
+                    stack.pop().unwrap() + b
+                }
+                Instruction::Mul => {
+                    let b = stack.pop().unwrap();
+                    stack.pop().unwrap() * b
+                }
+                Instruction::Powi(n) => float::powi(stack.pop().unwrap(), *n),
+                Instruction::Ln => float::ln(stack.pop().unwrap()),
+                Instruction::Sin => float::sin(stack.pop().unwrap()),
+                Instruction::Cos => float::cos(stack.pop().unwrap()),
+                Instruction::Exp => float::exp(stack.pop().unwrap()),
+                Instruction::Sqrt => float::sqrt(stack.pop().unwrap()),
+                Instruction::Apply(op, arity) => {
+                    let start = stack.len() - arity;
+                    let value = op.apply(&stack[start..]);
+                    stack.truncate(start);
+                    value
+                }
+            };
+            stack.push(value);
+        }
+        stack[0]
+    }
+
+    /// One instruction per line
+    pub fn disassemble(&self) -> String {
+        self.code
+            .iter()
+            .map(|instruction| format!("{:?}", instruction))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/// One instruction of the postfix program run by `BoundExpr` and `evaluate_batch`
+enum PlanStep {
+    /// Push the value in this column of the current row
+    Load(usize),
+    /// Pop this many arguments and push the result of the operation
+    Apply(Operation, usize),
+}
+
+/// Hessian-vector product H·v of `f` at `x`, where `vars` names the entries of `x` and `v`.
+/// A forward tangent pass in direction v gives ∇f · v, and a reverse sweep over its tape
+/// differentiates that once more, so the n×n Hessian is never built.
+pub fn hvp(f: &Node, vars: &[String], x: &[f64], v: &[f64]) -> Vec<f64> {
+    let direction = vars.iter().cloned().zip(v.iter().copied()).collect();
+    let at = vars.iter().cloned().zip(x.iter().copied()).collect();
+    let gradient = Tape::record(&f.directional_derivative(&direction), &at).grad();
+    vars.iter()
+        .map(|name| gradient.get(name).copied().unwrap_or(0.0))
+        .collect()
+}
+
+/// Jacobian-vector product J·t of `exprs` at `x`, where `vars` names the entries of `x` and
+/// `tangent`; one directional derivative per expression, no Jacobian
+pub fn jvp(exprs: &[Node], vars: &[String], x: &[f64], tangent: &[f64]) -> Vec<f64> {
+    let at = vars.iter().cloned().zip(x.iter().copied()).collect();
+    jvp_symbolic(exprs, vars, tangent)
+        .iter()
+        .map(|derivative| derivative.evaluate(&at))
+        .collect()
+}
+
+/// J·t as expressions, one per entry of `exprs`
+pub fn jvp_symbolic(exprs: &[Node], vars: &[String], tangent: &[f64]) -> Vec<Node> {
+    let direction = vars.iter().cloned().zip(tangent.iter().copied()).collect();
+    exprs
+        .iter()
+        .map(|expr| expr.directional_derivative(&direction))
+        .collect()
+}
+
+/// Vector-Jacobian product uᵀJ of `exprs` at `x`, where `vars` names the entries of `x`
+/// and `cotangent` weighs the expressions; a single reverse sweep over Σ u_k f_k
+pub fn vjp(exprs: &[Node], vars: &[String], x: &[f64], cotangent: &[f64]) -> Vec<f64> {
+    let at = vars.iter().cloned().zip(x.iter().copied()).collect();
+    let gradient = Tape::record(&weighted_sum(exprs, cotangent), &at).grad();
+    vars.iter()
+        .map(|name| gradient.get(name).copied().unwrap_or(0.0))
+        .collect()
+}
+
+/// uᵀJ as expressions, one per entry of `vars`
+pub fn vjp_symbolic(exprs: &[Node], vars: &[String], cotangent: &[f64]) -> Vec<Node> {
+    let mut gradient = weighted_sum(exprs, cotangent).gradient();
+    vars.iter()
+        .map(|name| gradient.remove(name).unwrap_or_else(zero))
+        .collect()
+}
+
+/// Σ u_k f_k, whose gradient is uᵀJ
+fn weighted_sum(exprs: &[Node], weights: &[f64]) -> Node {
+    exprs
+        .iter()
+        .zip(weights)
+        .fold(zero(), |sum, (expr, weight)| sum + *weight * expr.clone())
+}
+
+/// Stationary points of `f` inside `bounds`, found by Newton's method on the symbolic gradient
+/// from `starts` pseudo-random starting points. Points closer than 1e-6 are reported once.
+pub fn critical_points(
+    f: &mut Node,
+    bounds: &HashMap<String, (f64, f64)>,
+    starts: usize,
+) -> Vec<HashMap<String, f64>> {
+    let mut names = bounds.keys().cloned().collect::<Vec<String>>();
+    names.sort();
+    let mut gradient = names.iter().map(|name| f.diff(name)).collect::<Vec<Node>>();
+    let hessian = gradient
+        .iter_mut()
+        .map(|g| names.iter().map(|name| g.diff(name)).collect())
+        .collect::<Vec<Vec<Node>>>();
+
+    let mut seed: u64 = 1;
+    let mut random = move || {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 11) as f64 / (1u64 << 53) as f64
+    };
+    let inside = |x: &[f64]| {
+        names.iter().zip(x).all(|(name, x)| {
+            let (lo, hi) = bounds[name];
+            lo <= *x && *x <= hi
+        })
+    };
+    let mut points: Vec<Vec<f64>> = vec![];
+    for _ in 0..starts {
+        let mut x = names
+            .iter()
+            .map(|name| {
+                let (lo, hi) = bounds[name];
+                lo + (hi - lo) * random()
+            })
+            .collect::<Vec<f64>>();
+        for _ in 0..50 {
+            let at = names.iter().cloned().zip(x.iter().copied()).collect();
+            let g = gradient
+                .iter()
+                .map(|g| g.evaluate(&at))
+                .collect::<Vec<f64>>();
+            if g.iter().map(|g| g * g).sum::<f64>().sqrt() < 1e-10 {
+                if inside(&x)
+                    && !points.iter().any(|p| {
+                        p.iter().zip(&x).map(|(p, x)| (p - x).powi(2)).sum::<f64>() < 1e-12
+                    })
+                {
+                    points.push(x);
+                }
+                break;
+            }
+            let h = hessian
+                .iter()
+                .map(|row| row.iter().map(|h| h.evaluate(&at)).collect())
+                .collect::<Vec<Vec<f64>>>();
+            let Some(step) = solve_linear(h, g) else {
+                break;
+            };
+            x.iter_mut().zip(step).for_each(|(x, step)| *x -= step);
+        }
+    }
+    points.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    points
+        .into_iter()
+        .map(|x| names.iter().cloned().zip(x).collect())
+        .collect()
+}
+
+/// Solve `a x = b` by Gaussian elimination with partial pivoting, or `None` if `a` is singular
+fn solve_linear(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-14 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let pivot_row = a[col].clone();
+        for row in col + 1..n {
+            let factor = a[row][col] / pivot_row[col];
+            for (a, p) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *a -= factor * p;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let rest = (row + 1..n).map(|k| a[row][k] * x[k]).sum::<f64>();
+        x[row] = (b[row] - rest) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Sum with a running compensation for the rounding error of each addition
+/// (Neumaier's variant of Kahan summation, which also handles terms larger than the sum)
+fn compensated_sum(terms: impl Iterator<Item = f64>) -> f64 {
+    let mut sum = 0.0;
+    let mut compensation = 0.0;
+    for term in terms {
+        let next = sum + term;
+        if f64::abs(sum) >= f64::abs(term) {
+            compensation += (sum - next) + term;
+        } else {
+            compensation += (term - next) + sum;
+        }
+        sum = next;
+    }
+    sum + compensation
+}
+
+/// Sign of `x` with `signum(0) = 0`, unlike `f64::signum`
+fn signum(x: f64) -> f64 {
+    if x == 0.0 { 0.0 } else { x.signum() }
+}
+
+/// 1 / (1 + e^-x), without overflowing `exp` for large negative x
+fn sigmoid_value(x: f64) -> f64 {
+    if x >= 0.0 {
+        1.0 / (1.0 + float::exp(-x))
+    } else {
+        let e = float::exp(x);
+        e / (1.0 + e)
+    }
+}
+
+/// ln(1 + e^x), as max(x, 0) + ln(1 + e^-|x|) so that large x neither overflows nor loses digits
+fn softplus_value(x: f64) -> f64 {
+    x.max(0.0) + float::ln_1p(float::exp(-float::abs(x)))
+}
+
+/// Error function: its Taylor series near 0, and a continued fraction for erfc further out
+fn erf_value(x: f64) -> f64 {
+    if float::abs(x) < 3.0 {
+        // erf(x) = 2/√π Σ (-1)^n x^(2n+1) / (n! (2n + 1))
+        let mut sum = 0.0;
+        let mut power = x;
+        let mut n = 0.0;
+        while float::abs(power) > 1e-17 * float::abs(sum) || n == 0.0 {
+            sum += power / (2.0 * n + 1.0);
+            n += 1.0;
+            power *= -x * x / n;
+        }
+        std::f64::consts::FRAC_2_SQRT_PI * sum
+    } else {
+        // erfc(|x|) = exp(-x^2) / √π / (|x| + (1/2) / (|x| + 1 / (|x| + (3/2) / (|x| + ...))))
+        let a = float::abs(x);
+        let fraction = (1..=60).rev().fold(a, |t, k| a + k as f64 / 2.0 / t);
+        let erfc = float::exp(-a * a) / (std::f64::consts::PI.sqrt() * fraction);
+        (1.0 - erfc).copysign(x)
+    }
+}
+
+// Lanczos approximation of Γ with g = 7, good to about 15 digits
+const LANCZOS_G: f64 = 7.0;
+const LANCZOS_COEFFICIENTS: [f64; 9] = [
+    0.9999999999998099,
+    676.5203681218851,
+    -1259.1392167224028,
+    771.3234287776531,
+    -176.6150291621406,
+    12.507343278686905,
+    -0.13857109526572012,
+    9.984369578019572e-6,
+    1.5056327351493116e-7,
+];
+
+/// The series `a` and shifted argument `t` of the Lanczos approximation
+/// Γ(x + 1) = √(2π) t^(x + 1/2) e^-t a, for x >= -1/2
+fn lanczos(x: f64) -> (f64, f64) {
+    let a = LANCZOS_COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(LANCZOS_COEFFICIENTS[0], |a, (i, coefficient)| {
+            a + coefficient / (x + i as f64 + 1.0)
+        });
+    (a, x + LANCZOS_G + 0.5)
+}
+
+/// Γ(x), using the reflection formula Γ(x) Γ(1 - x) = π / sin(πx) below 1/2
+fn gamma_value(x: f64) -> f64 {
+    use std::f64::consts::PI;
+    if x < 0.5 {
+        return PI / (float::sin(PI * x) * gamma_value(1.0 - x));
+    }
+    let (a, t) = lanczos(x - 1.0);
+    (2.0 * PI).sqrt() * float::powf(t, x - 0.5) * float::exp(-t) * a
+}
+
+/// ln|Γ(x)|, which stays finite where Γ(x) itself overflows
+fn ln_gamma_value(x: f64) -> f64 {
+    use std::f64::consts::PI;
+    if x < 0.5 {
+        return float::ln(PI / float::abs(float::sin(PI * x))) - ln_gamma_value(1.0 - x);
+    }
+    let (a, t) = lanczos(x - 1.0);
+    0.5 * float::ln(2.0 * PI) + (x - 0.5) * float::ln(t) - t + float::ln(a)
+}
+
+/// Highest order `polygamma_value` computes; its series needs (n + 13)!, which overflows f64 for
+/// n above 157
+const MAX_POLYGAMMA_ORDER: f64 = 150.0;
+
+/// ψ^(n)(x), the n-th derivative of the digamma function, for a whole number n up to
+/// `MAX_POLYGAMMA_ORDER`. Negative x is reflected to 1 - x, and the recurrence
+/// ψ^(n)(x) = ψ^(n)(x + 1) + (-1)^(n+1) n! / x^(n+1) moves x up until the asymptotic series holds.
+/// NaN for other orders and for non-finite x.
+fn polygamma_value(n: f64, x: f64) -> f64 {
+    use std::f64::consts::PI;
+    // B_2, B_4, ..., B_14
+    const BERNOULLI: [f64; 7] = [
+        1.0 / 6.0,
+        -1.0 / 30.0,
+        1.0 / 42.0,
+        -1.0 / 30.0,
+        5.0 / 66.0,
+        -691.0 / 2730.0,
+        7.0 / 6.0,
+    ];
+    if !(0.0..=MAX_POLYGAMMA_ORDER).contains(&n) || float::fract(n) != 0.0 || !x.is_finite() {
+        return f64::NAN;
+    }
+    if x < 0.0 {
+        // ψ^(n)(x) = (-1)^n ψ^(n)(1 - x) - π dⁿ/dxⁿ cot(πx). The derivatives of cot(πx) are
+        // polynomials in c = cot(πx): P_0 = c and P_k+1 = -π (1 + c²) P_k'(c)
+        let r = float::fract(x);
+        if r == 0.0 {
+            // A pole, which every f64 beyond 2^52 in magnitude is
+            return f64::NAN;
+        }
+        let c = float::cos(PI * r) / float::sin(PI * r);
+        let mut coefficients = vec![0.0, 1.0];
+        for _ in 0..n as usize {
+            let mut next = vec![0.0; coefficients.len() + 1];
+            for (k, a) in coefficients.iter().enumerate().skip(1) {
+                next[k - 1] -= PI * k as f64 * a;
+                next[k + 1] -= PI * k as f64 * a;
+            }
+            coefficients = next;
+        }
+        let cot_derivative = coefficients.iter().rev().fold(0.0, |sum, a| sum * c + a);
+        let sign = if n % 2.0 == 0.0 { 1.0 } else { -1.0 };
+        return sign * polygamma_value(n, 1.0 - x) - PI * cot_derivative;
+    }
+    let factorial = |k: f64| (1..=k as u64).map(|i| i as f64).product::<f64>();
+    // (-1)^(n+1)
+    let sign = if n % 2.0 == 0.0 { -1.0 } else { 1.0 };
+    let mut x = x;
+    let mut shift = 0.0;
+    while x < 10.0 + n {
+        shift += sign * factorial(n) / float::powf(x, n + 1.0);
+        x += 1.0;
+    }
+    let series = if n == 0.0 {
+        // ψ(x) ~ ln(x) - 1/(2x) - Σ B_2k / (2k x^2k)
+        float::ln(x)
+            - 0.5 / x
+            - BERNOULLI.iter().enumerate().fold(0.0, |sum, (i, b)| {
+                let k2 = 2.0 * (i as f64 + 1.0);
+                sum + b / (k2 * float::powf(x, k2))
+            })
+    } else {
+        // ψ^(n)(x) ~ (-1)^(n+1) ((n-1)!/x^n + n!/(2x^(n+1)) + Σ B_2k (2k+n-1)! / ((2k)! x^(2k+n)))
+        let tail = BERNOULLI.iter().enumerate().fold(0.0, |sum, (i, b)| {
+            let k2 = 2.0 * (i as f64 + 1.0);
+            sum + b * factorial(k2 + n - 1.0) / (factorial(k2) * float::powf(x, k2 + n))
+        });
+        sign * (factorial(n - 1.0) / float::powf(x, n)
+            + factorial(n) / (2.0 * float::powf(x, n + 1.0))
+            + tail)
+    };
+    series + shift
+}
+
+////////////////////
+//  Dual numbers  //
+////////////////////
+
+/// Numbers that `Dual` can carry: `f32`, `f64`, and `Dual` itself, which nests into higher
+/// derivatives
+pub trait Scalar:
+    Copy
+    + PartialEq
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+{
+    fn zero() -> Self;
+    fn one() -> Self;
+    fn sin(self) -> Self;
+    fn cos(self) -> Self;
+    fn ln(self) -> Self;
+    fn powf(self, exponent: Self) -> Self;
+}
+
+macro_rules! impl_scalar {
+    ($t:ty) => {
+        impl Scalar for $t {
+            fn zero() -> Self {
+                0.0
+            }
+            fn one() -> Self {
+                1.0
+            }
+            fn sin(self) -> Self {
+                <$t>::sin(self)
+            }
+            fn cos(self) -> Self {
+                <$t>::cos(self)
+            }
+            fn ln(self) -> Self {
+                <$t>::ln(self)
+            }
+            fn powf(self, exponent: Self) -> Self {
+                <$t>::powf(self, exponent)
+            }
+        }
+    };
+}
+impl_scalar!(f32);
+impl_scalar!(f64);
+
+/// Dual number `value + deriv ε` with `ε² = 0`, carrying a derivative through arithmetic in a
+/// single forward pass, without building a `Node` tree
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dual<T> {
+    pub value: T,
+    pub deriv: T,
+}
+
+impl<T: Scalar> Dual<T> {
+    /// A value that does not depend on the input being differentiated
+    pub fn constant(value: T) -> Self {
+        Self {
+            value,
+            deriv: T::zero(),
+        }
+    }
+
+    /// The input being differentiated, with derivative 1
+    pub fn variable(value: T) -> Self {
+        Self {
+            value,
+            deriv: T::one(),
+        }
+    }
+
+    /// Apply a scalar function given its value and derivative at `value`
+    fn apply(self, value: T, d: T) -> Self {
+        Self {
+            value,
+            deriv: d * self.deriv,
+        }
+    }
+}
+
+impl<T: Scalar> Scalar for Dual<T> {
+    fn zero() -> Self {
+        Self::constant(T::zero())
+    }
+    fn one() -> Self {
+        Self::constant(T::one())
+    }
+    fn sin(self) -> Self {
+        self.apply(self.value.sin(), self.value.cos())
+    }
+    fn cos(self) -> Self {
+        self.apply(self.value.cos(), -self.value.sin())
+    }
+    fn ln(self) -> Self {
+        self.apply(self.value.ln(), T::one() / self.value)
+    }
+    /// `self ^ exponent`; the exponent may carry a derivative too
+    fn powf(self, exponent: Self) -> Self {
+        let value = self.value.powf(exponent.value);
+        let mut deriv = exponent.value * self.value.powf(exponent.value - T::one()) * self.deriv;
+        // Skipped for constant exponents, where ln would be NaN for a negative base
+        if exponent.deriv != T::zero() {
+            deriv = deriv + value * self.value.ln() * exponent.deriv;
+        }
+        Self { value, deriv }
+    }
+}
+
+impl<T: Scalar> Add for Dual<T> {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self {
+            value: self.value + other.value,
+            deriv: self.deriv + other.deriv,
+        }
+    }
+}
+
+impl<T: Scalar> Sub for Dual<T> {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Self {
+            value: self.value - other.value,
+            deriv: self.deriv - other.deriv,
+        }
+    }
+}
+
+impl<T: Scalar> Mul for Dual<T> {
+    type Output = Self;
+    fn mul(self, other: Self) -> Self {
+        Self {
+            value: self.value * other.value,
+            deriv: self.deriv * other.value + self.value * other.deriv,
+        }
+    }
+}
+
+impl<T: Scalar> Div for Dual<T> {
+    type Output = Self;
+    fn div(self, other: Self) -> Self {
+        Self {
+            value: self.value / other.value,
+            deriv: (self.deriv * other.value - self.value * other.deriv)
+                / (other.value * other.value),
+        }
+    }
+}
+
+impl<T: Scalar> Neg for Dual<T> {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Self {
+            value: -self.value,
+            deriv: -self.deriv,
+        }
+    }
+}
+
+/// Exact derivatives of all outputs of `f` wrt. `x[wrt]`, from a single forward pass
+pub fn forward_derivative<T: Scalar>(
+    f: impl Fn(&[Dual<T>]) -> Vec<Dual<T>>,
+    x: &[T],
+    wrt: usize,
+) -> Vec<T> {
+    let point = x
+        .iter()
+        .enumerate()
+        .map(|(i, &value)| {
+            if i == wrt {
+                Dual::variable(value)
+            } else {
+                Dual::constant(value)
+            }
+        })
+        .collect::<Vec<Dual<T>>>();
+    f(&point).iter().map(|output| output.deriv).collect()
+}
+
+/// Gradient of a scalar `f` at `x`, seeding one input per forward pass
+pub fn grad<T: Scalar>(f: impl Fn(&[Dual<T>]) -> Dual<T>, x: &[T]) -> Vec<T> {
+    (0..x.len())
+        .map(|wrt| forward_derivative(|point: &[Dual<T>]| vec![f(point)], x, wrt)[0])
+        .collect()
+}
+
+/////////////////////
+//  Rewrite rules  //
+/////////////////////
+
+/// A condition on the subexpressions a pattern matched
+type Condition = fn(&Bindings) -> bool;
+
+/// A simplification `pattern → template`, e.g. `a * 1 → a`. Variables of the pattern match any
+/// subexpression, the same one wherever a variable repeats, except `k`, `k1`, `k2`, ... which
+/// only match constants. Sums and products also match with their arguments swapped.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pattern: Node,
+    template: Node,
+    condition: Option<Condition>,
+}
+
+/// The subexpressions bound to the variables of a pattern, passed to a rule's condition
+#[derive(Debug, Clone)]
+pub struct Bindings<'a>(Vec<(&'a str, &'a Node)>);
+impl<'a> Bindings<'a> {
+    pub fn get(&self, name: &str) -> Option<&'a Node> {
+        self.0
+            .iter()
+            .find(|(bound, _)| *bound == name)
+            .map(|(_, node)| *node)
+    }
+}
+impl std::ops::Index<&str> for Bindings<'_> {
+    type Output = Node;
+
+    fn index(&self, name: &str) -> &Node {
+        match self.get(name) {
+            Some(node) => node,
+            None => panic!("{} is not a variable of the pattern", name),
+        }
+    }
+}
+
+impl Rule {
+    /// Parse a rule from infix notation, e.g. `Rule::new("(a + k1) + k2", "a + (k1 + k2)")`.
+    /// Both sides are read as written, with only their constant parts evaluated. The pattern
+    /// must be an operation, and every variable of the template must occur in the pattern
+    pub fn new(pattern: &str, template: &str) -> Result<Rule, ParseError> {
+        let config = simplify_config();
+        set_simplify_config(SimplifyConfig {
+            disabled: true,
+            ..config
+        });
+        let parsed = parse(pattern).and_then(|pattern| Ok((pattern, parse(template)?)));
+        let parsed = parsed.map(|(pattern, template)| {
+            let fold = |node: &Node| {
+                node.fold(|node, args: Vec<Node>| {
+                    let node = Node {
+                        op: node.op.clone(),
+                        args: args.into_iter().map(Rc::new).collect(),
+                    };
+                    match node.constant_value() {
+                        Some(value) => c(value),
+                        None => node,
+                    }
+                })
+            };
+            (fold(&pattern), fold(&template))
+        });
+        set_simplify_config(config);
+        let (pattern, template) = parsed?;
+
+        if pattern.args.is_empty() {
+            return Err(ParseError {
+                position: 0,
+                message: "the pattern must be an operation".to_string(),
+            });
+        }
+        let bound = pattern.free_variables();
+        if let Some(name) = template
+            .free_variables()
+            .into_iter()
+            .find(|name| !bound.contains(name))
+        {
+            return Err(ParseError {
+                position: 0,
+                message: format!("'{}' in the template does not occur in the pattern", name),
+            });
+        }
+        Ok(Rule {
+            pattern,
+            template,
+            condition: None,
+        })
+    }
+
+    /// Only rewrite matches for which `condition` holds, e.g. `|b| b["a"] != b["b"]`
+    pub fn when(self, condition: fn(&Bindings) -> bool) -> Rule {
+        Rule {
+            condition: Some(condition),
+            ..self
+        }
+    }
+
+    /// `node` rewritten by the rule, or `None` if the pattern does not match it
+    fn apply(&self, node: &Node, tolerance: f64) -> Option<Node> {
+        match_pattern(&self.pattern, node, Bindings(vec![]), tolerance)
+            .into_iter()
+            .find(|bindings| self.condition.is_none_or(|condition| condition(bindings)))
+            .map(|bindings| instantiate(&self.template, &bindings))
+    }
+}
+
+/// `pattern → template`, with all the parentheses of `to_infix`
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} → {}",
+            self.pattern.to_infix(),
+            self.template.to_infix()
+        )
+    }
+}
+
+/// Whether a pattern variable only matches constants: `k`, `k1`, `k2`, ...
+fn is_constant_wildcard(name: &str) -> bool {
+    name.strip_prefix('k')
+        .is_some_and(|rest| rest.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Every way `pattern` matches `node`, each extending `bindings`. Constants of the pattern
+/// match constants within `tolerance` of them.
+fn match_pattern<'a>(
+    pattern: &'a Node,
+    node: &'a Node,
+    mut bindings: Bindings<'a>,
+    tolerance: f64,
+) -> Vec<Bindings<'a>> {
+    match &pattern.op {
+        Operation::Var(name) => {
+            if is_constant_wildcard(name) && !matches!(node.op, Operation::Const(_)) {
+                return vec![];
+            }
+            match bindings.get(name) {
+                Some(bound) if bound != node => vec![],
+                Some(_) => vec![bindings],
+                None => {
+                    bindings.0.push((name, node));
+                    vec![bindings]
+                }
+            }
+        }
+        Operation::Const(value) => match node.op {
+            Operation::Const(x) if (x - value).abs() < tolerance => vec![bindings],
+            _ => vec![],
+        },
+        op => {
+            if *op != node.op || pattern.args.len() != node.args.len() {
+                return vec![];
+            }
+            let mut orders = vec![node.args.iter().collect::<Vec<&Rc<Node>>>()];
+            if let Operation::Add | Operation::Mul = op {
+                orders.push(node.args.iter().rev().collect());
+            }
+            orders
+                .into_iter()
+                .flat_map(|args| {
+                    pattern.args.iter().zip(args).fold(
+                        vec![bindings.clone()],
+                        |found, (pattern, node)| {
+                            found
+                                .into_iter()
+                                .flat_map(|bindings| {
+                                    match_pattern(pattern, node, bindings, tolerance)
+                                })
+                                .collect()
+                        },
+                    )
+                })
+                .collect()
+        }
+    }
+}
+
+/// The template with its variables replaced by what they matched, simplified as it is built
+fn instantiate(template: &Node, bindings: &Bindings) -> Node {
+    match &template.op {
+        Operation::Var(name) => bindings[name.as_str()].clone(),
+        op => Node::new(
+            op.clone(),
+            template
+                .args
+                .iter()
+                .map(|arg| Rc::new(instantiate(arg, bindings)))
+                .collect(),
+        ),
+    }
+}
+
+/// The rules `simplify` applies unless they are replaced with `set_rewrite_rules`. Operations
+/// on constants are evaluated before any rule is tried, so no rule needs to handle them.
+pub fn default_rules() -> Vec<Rule> {
+    fn integer(bindings: &Bindings) -> bool {
+        matches!(bindings["k"].op, Operation::Const(k) if float::fract(k) == 0.0)
+    }
+    fn nonzero(bindings: &Bindings) -> bool {
+        matches!(bindings["k"].op, Operation::Const(k) if k != 0.0)
+    }
+    let rules: Vec<(&str, &str, Option<Condition>)> = vec![
+        // a + 0 = a
+        ("a + 0", "a", None),
+        // (a + k1) + k2 = a + (k1 + k2)
+        ("(a + k1) + k2", "a + (k1 + k2)", None),
+        // a * 1 = a
+        ("a * 1", "a", None),
+        // a * 0 = 0
+        ("a * 0", "0", None),
+        // (k1 * a) * k2 = (k1 * k2) * a
+        ("(k1 * a) * k2", "(k1 * k2) * a", None),
+        // a * a^-1 = 1, assuming a != 0
+        ("a * a^(-1)", "1", None),
+        // a ^ 1 = a
+        ("a^1", "a", None),
+        // a ^ 0 = 1
+        ("a^0", "1", None),
+        // (a ^ b) ^ k = a ^ (b * k), only for integer k (e.g. (x^2)^0.5 = |x|, not x)
+        ("(a^b)^k", "a^(b*k)", Some(integer)),
+        // b ^ log_b(a) = a, e.g. 2^log_2(a) = a, assuming a > 0
+        ("b^log(b, a)", "a", None),
+        // sqrt(a) ^ 2 = a, assuming a >= 0
+        ("sqrt(a)^2", "a", None),
+        // e ^ a = exp(a)
+        ("e^a", "exp(a)", None),
+        // sin(asin(a)) = a, for a in [-1, 1]
+        ("sin(asin(a))", "a", None),
+        // cos(acos(a)) = a, for a in [-1, 1]
+        ("cos(acos(a))", "a", None),
+        // tan(atan(a)) = a
+        ("tan(atan(a))", "a", None),
+        // log_b(b) = 1
+        ("log(b, b)", "1", None),
+        // log_b(1) = 0 (for a valid base, b != 1)
+        ("log(b, 1)", "0", None),
+        // log_b(b ^ a) = a, e.g. log_2(2^a) = a
+        ("log(b, b^a)", "a", None),
+        // ln(exp(a)) = a
+        ("ln(exp(a))", "a", None),
+        // exp(ln(a)) = a, assuming a > 0
+        ("exp(ln(a))", "a", None),
+        // sqrt(a ^ 2) = |a|
+        ("sqrt(a^2)", "abs(a)", None),
+        // abs(abs(a)) = abs(a)
+        ("abs(abs(a))", "abs(a)", None),
+        // relu(relu(a)) = relu(a)
+        ("relu(relu(a))", "relu(a)", None),
+        // min(a, a) = max(a, a) = a
+        ("min(a, a)", "a", None),
+        ("max(a, a)", "a", None),
+        // if(k, a, b) = a for k != 0, and b for k = 0
+        ("if(k, a, b)", "a", Some(nonzero)),
+        ("if(k, a, b)", "b", None),
+        // if(p, a, a) = a
+        ("if(p, a, a)", "a", None),
+    ];
+    rules
+        .into_iter()
+        .map(|(pattern, template, condition)| {
+            let rule = Rule::new(pattern, template).unwrap();
+            match condition {
+                Some(condition) => rule.when(condition),
+                None => rule,
+            }
+        })
+        .collect()
+}
+
+/// Two-way identities that `simplify_egraph` explores on top of the rewrite rules. They would
+/// never terminate as rewrite rules, but an e-graph keeps both sides of each.
+fn equalities() -> Vec<Rule> {
+    let equalities = [
+        ("a + b", "b + a"),
+        ("a * b", "b * a"),
+        ("(a + b) + p", "a + (b + p)"),
+        ("a + (b + p)", "(a + b) + p"),
+        ("(a * b) * p", "a * (b * p)"),
+        ("a * (b * p)", "(a * b) * p"),
+        ("a * (b + p)", "a * b + a * p"),
+        ("a * b + a * p", "a * (b + p)"),
+        ("a + a", "2 * a"),
+        ("a + k * a", "(1 + k) * a"),
+        ("a * a", "a^2"),
+        ("a^2", "a * a"),
+        ("a^b * a", "a^(b + 1)"),
+        ("a^b * a^p", "a^(b + p)"),
+    ];
+    equalities
+        .into_iter()
+        .map(|(pattern, template)| Rule::new(pattern, template).unwrap())
+        .collect()
+}
+
+/// `simplify_egraph` stops after the round that grows the e-graph past this many nodes
+const EGRAPH_NODE_LIMIT: usize = 10_000;
+
+/// An operation on e-classes
+type ENode = (Operation, Vec<usize>);
+
+/// Equivalence classes of expressions, each a set of operations whose arguments are classes
+/// themselves, so that one graph holds every rewriting of an expression found so far
+#[derive(Default)]
+struct EGraph {
+    /// Union-find over class ids: a class is canonical if it is its own parent
+    parent: Vec<usize>,
+    /// The nodes of each canonical class
+    classes: Vec<Vec<ENode>>,
+    /// The class of every node, by canonical arguments
+    memo: HashMap<ENode, usize>,
+    /// The value of each canonical class that is known to be constant
+    constants: Vec<Option<f64>>,
+}
+
+impl EGraph {
+    fn find(&self, mut id: usize) -> usize {
+        while self.parent[id] != id {
+            id = self.parent[id];
+        }
+        id
+    }
+
+    fn add(&mut self, op: Operation, args: Vec<usize>) -> usize {
+        let node = (op, args.into_iter().map(|arg| self.find(arg)).collect());
+        if let Some(&id) = self.memo.get(&node) {
+            return self.find(id);
+        }
+        let id = self.parent.len();
+        self.parent.push(id);
+        self.constants.push(match node.0 {
+            Operation::Const(value) => Some(value),
+            _ => None,
+        });
+        self.classes.push(vec![node.clone()]);
+        self.memo.insert(node, id);
+        id
+    }
+
+    /// Merge two classes, returning whether they were different
+    fn union(&mut self, a: usize, b: usize) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        let (keep, gone) = (a.min(b), a.max(b));
+        self.parent[gone] = keep;
+        let nodes = std::mem::take(&mut self.classes[gone]);
+        self.classes[keep].extend(nodes);
+        self.constants[keep] = self.constants[keep].or(self.constants[gone]);
+        true
+    }
+
+    /// Restore the invariants after unions: nodes refer to canonical classes, no node is stored
+    /// twice, and congruent nodes (the same operation on the same classes) share a class.
+    /// Operations on constant classes are evaluated and the constant added to their class.
+    fn rebuild(&mut self) {
+        loop {
+            let mut memo = HashMap::new();
+            let mut merges = vec![];
+            for id in 0..self.classes.len() {
+                if self.find(id) != id {
+                    continue;
+                }
+                let nodes = std::mem::take(&mut self.classes[id]);
+                let mut kept = vec![];
+                for (op, args) in nodes {
+                    let node = (op, args.iter().map(|arg| self.find(*arg)).collect());
+                    match memo.get(&node) {
+                        Some(&other) if other != id => merges.push((other, id)),
+                        Some(_) => (),
+                        None => {
+                            memo.insert(node.clone(), id);
+                            kept.push(node);
+                        }
+                    }
+                }
+                self.classes[id] = kept;
+            }
+            self.memo = memo;
+
+            let mut folded = vec![];
+            for id in 0..self.classes.len() {
+                if self.find(id) != id || self.constants[id].is_some() {
+                    continue;
+                }
+                let value = self.classes[id].iter().find_map(|(op, args)| {
+                    if args.is_empty() || matches!(op, Operation::UserFn(_)) {
+                        return None;
+                    }
+                    let values = args
+                        .iter()
+                        .map(|arg| self.constants[self.find(*arg)])
+                        .collect::<Option<Vec<f64>>>()?;
+                    Some(op.apply(&values))
+                });
+                if let Some(value) = value {
+                    folded.push((id, value));
+                }
+            }
+            if merges.is_empty() && folded.is_empty() {
+                return;
+            }
+            for (a, b) in merges {
+                self.union(a, b);
+            }
+            for (id, value) in folded {
+                let constant = self.add(Operation::Const(value), vec![]);
+                self.union(constant, id);
+            }
+        }
+    }
+
+    /// Every way `pattern` matches an expression of `class`, as in `match_pattern`
+    fn ematch<'a>(
+        &self,
+        pattern: &'a Node,
+        class: usize,
+        mut bindings: Vec<(&'a str, usize)>,
+        tolerance: f64,
+    ) -> Vec<Vec<(&'a str, usize)>> {
+        match &pattern.op {
+            Operation::Var(name) => {
+                if is_constant_wildcard(name) && self.constants[class].is_none() {
+                    return vec![];
+                }
+                match bindings.iter().find(|(bound, _)| bound == name) {
+                    Some((_, bound)) if self.find(*bound) != class => vec![],
+                    Some(_) => vec![bindings],
+                    None => {
+                        bindings.push((name, class));
+                        vec![bindings]
+                    }
+                }
+            }
+            Operation::Const(value) => match self.constants[class] {
+                Some(x) if (x - value).abs() < tolerance => vec![bindings],
+                _ => vec![],
+            },
+            op => {
+                let mut found = vec![];
+                for (node_op, args) in &self.classes[class] {
+                    if node_op != op || args.len() != pattern.args.len() {
+                        continue;
+                    }
+                    let mut orders = vec![args.clone()];
+                    if let Operation::Add | Operation::Mul = op {
+                        orders.push(args.iter().rev().copied().collect());
+                    }
+                    for args in orders {
+                        let matches = pattern.args.iter().zip(args).fold(
+                            vec![bindings.clone()],
+                            |found, (pattern, arg)| {
+                                found
+                                    .into_iter()
+                                    .flat_map(|bindings| {
+                                        self.ematch(pattern, self.find(arg), bindings, tolerance)
+                                    })
+                                    .collect()
+                            },
+                        );
+                        found.extend(matches);
+                    }
+                }
+                found
+            }
+        }
+    }
+
+    /// Add a rule's template with its variables bound to classes, returning the new class
+    fn instantiate(&mut self, template: &Node, bindings: &[(&str, usize)]) -> usize {
+        match &template.op {
+            Operation::Var(name) => bindings.iter().find(|(bound, _)| bound == name).unwrap().1,
+            op => {
+                let args = template
+                    .args
+                    .iter()
+                    .map(|arg| self.instantiate(arg, bindings))
+                    .collect();
+                self.add(op.clone(), args)
+            }
+        }
+    }
+
+    /// For every canonical class, the size of its smallest expression and the node that
+    /// starts it
+    fn extract(&self) -> Vec<Option<(usize, usize)>> {
+        let mut best: Vec<Option<(usize, usize)>> = vec![None; self.classes.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for id in 0..self.classes.len() {
+                for (i, (_, args)) in self.classes[id].iter().enumerate() {
+                    let size = args.iter().try_fold(1usize, |size, arg| {
+                        best[self.find(*arg)].map(|(cost, _)| size.saturating_add(cost))
+                    });
+                    if let Some(size) = size
+                        && best[id].is_none_or(|(cost, _)| size < cost)
+                    {
+                        best[id] = Some((size, i));
+                        changed = true;
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    /// The smallest expression of `class`, by the choices of `extract`
+    fn term(&self, best: &[Option<(usize, usize)>], class: usize) -> Node {
+        let class = self.find(class);
+        let (op, args) = &self.classes[class][best[class].unwrap().1];
+        Node::new(
+            op.clone(),
+            args.iter()
+                .map(|arg| Rc::new(self.term(best, *arg)))
+                .collect(),
+        )
+    }
+}
+
+thread_local! {
+    static REWRITE_RULES: RefCell<Rc<Vec<Rule>>> = RefCell::new(Rc::new(default_rules()));
+}
+
+/// Rewrite rules used by expressions built on this thread, in the order they are tried
+pub fn rewrite_rules() -> Vec<Rule> {
+    REWRITE_RULES.with_borrow(|rules| rules.to_vec())
+}
+pub fn set_rewrite_rules(rules: Vec<Rule>) {
+    REWRITE_RULES.set(Rc::new(rules));
+}
+
+/// Add a rule on this thread, tried after the rules already there
+pub fn register_rule(rule: Rule) {
+    REWRITE_RULES.with_borrow_mut(|rules| Rc::make_mut(rules).push(rule));
+}
+
+////////////////////
+//  Constructors  //
+////////////////////
+pub fn c(value: f64) -> Node {
+    Node::new(Operation::Const(value), vec![])
+}
+pub fn zero() -> Node {
+    c(0.0)
+}
+pub fn one() -> Node {
+    c(1.0)
+}
+pub fn pi() -> Node {
+    c(std::f64::consts::PI)
+}
+pub fn e() -> Node {
+    c(std::f64::consts::E)
+}
+
+/// Constants printed by name, and read back from it by the parsers
+const NAMED_CONSTANTS: [(&str, f64); 2] =
+    [("pi", std::f64::consts::PI), ("e", std::f64::consts::E)];
+
+fn named_constant(name: &str) -> Option<f64> {
+    NAMED_CONSTANTS
+        .iter()
+        .find(|(constant, _)| *constant == name)
+        .map(|(_, value)| *value)
+}
+
+const GREEK_LETTERS: [&str; 34] = [
+    "alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta", "iota", "kappa",
+    "lambda", "mu", "nu", "xi", "pi", "rho", "sigma", "tau", "upsilon", "phi", "chi", "psi",
+    "omega", "Gamma", "Delta", "Theta", "Lambda", "Xi", "Pi", "Sigma", "Upsilon", "Phi", "Psi",
+    "Omega",
+];
+
+/// The same letters as `GREEK_LETTERS`, in the same order
+const GREEK_SYMBOLS: &str = "αβγδεζηθικλμνξπρστυφχψωΓΔΘΛΞΠΣΥΦΨΩ";
+
+/// Split a variable name into its base and subscript, `x_1` and `x1` into `x` and `1`
+fn split_subscript(name: &str) -> (&str, Option<&str>) {
+    match name.split_once('_') {
+        Some((base, subscript)) if !base.is_empty() && !subscript.is_empty() => {
+            (base, Some(subscript))
+        }
+        _ => {
+            let split = name.trim_end_matches(|ch: char| ch.is_ascii_digit()).len();
+            if split > 0 && split < name.len() {
+                (&name[..split], Some(&name[split..]))
+            } else {
+                (name, None)
+            }
+        }
+    }
+}
+
+/// A variable name in LaTeX: `alpha` as `\alpha`, `x_1` and `x1` as `x_{1}`,
+/// and other multi-letter names upright, e.g. `\mathrm{rate}`
+fn latex_name(name: &str) -> String {
+    let (base, subscript) = split_subscript(name);
+    let base = if GREEK_LETTERS.contains(&base) {
+        format!("\\{}", base)
+    } else if base.chars().count() > 1 {
+        format!("\\mathrm{{{}}}", base)
+    } else {
+        base.to_string()
+    };
+    match subscript {
+        Some(subscript) => format!("{}_{{{}}}", base, latex_name(subscript)),
+        None => base,
+    }
+}
+
+/// A variable name in MathML: `alpha` as `α` and `x_1` or `x1` with a subscript
+fn mathml_name(name: &str) -> String {
+    let (base, subscript) = split_subscript(name);
+    let base = match GREEK_LETTERS.iter().position(|letter| *letter == base) {
+        Some(i) => format!("<mi>{}</mi>", GREEK_SYMBOLS.chars().nth(i).unwrap()),
+        None if base.chars().all(|ch| ch.is_ascii_digit()) => format!("<mn>{}</mn>", base),
+        None => format!("<mi>{}</mi>", base),
+    };
+    match subscript {
+        Some(subscript) => format!("<msub>{}{}</msub>", base, mathml_name(subscript)),
+        None => base,
+    }
+}
+
+/// A constant's name if it has one, its value otherwise
+fn format_constant(value: f64) -> String {
+    match NAMED_CONSTANTS
+        .iter()
+        .find(|(_, constant)| *constant == value)
+    {
+        Some((name, _)) => name.to_string(),
+        None => format!("{}", value),
+    }
+}
+impl Node {
+    pub fn pow(self, exponent: Node) -> Node {
+        Node::new(Operation::Pow, vec![Rc::new(self), Rc::new(exponent)])
+    }
+    /// Integer power, differentiated without the logarithmic power rule
+    pub fn powi(self, n: i64) -> Node {
+        self.pow(c(n as f64))
+    }
+    /// Logarithm of `self` in the given base
+    pub fn log(self, base: Node) -> Node {
+        Node::new(Operation::Log, vec![Rc::new(base), Rc::new(self)])
+    }
+    pub fn ln(self) -> Node {
+        self.log(e())
+    }
+    pub fn exp(self) -> Node {
+        Node::new(Operation::Exp, vec![Rc::new(self)])
+    }
+    pub fn sqrt(self) -> Node {
+        Node::new(Operation::Sqrt, vec![Rc::new(self)])
+    }
+    pub fn sin(self) -> Node {
+        Node::new(Operation::Sin, vec![Rc::new(self)])
+    }
+    pub fn cos(self) -> Node {
+        Node::new(Operation::Cos, vec![Rc::new(self)])
+    }
+    pub fn tan(self) -> Node {
+        Node::new(Operation::Tan, vec![Rc::new(self)])
+    }
+    pub fn asin(self) -> Node {
+        Node::new(Operation::Asin, vec![Rc::new(self)])
+    }
+    pub fn acos(self) -> Node {
+        Node::new(Operation::Acos, vec![Rc::new(self)])
+    }
+    pub fn sinh(self) -> Node {
+        Node::new(Operation::Sinh, vec![Rc::new(self)])
+    }
+    pub fn cosh(self) -> Node {
+        Node::new(Operation::Cosh, vec![Rc::new(self)])
+    }
+    pub fn tanh(self) -> Node {
+        Node::new(Operation::Tanh, vec![Rc::new(self)])
+    }
+    pub fn atan(self) -> Node {
+        Node::new(Operation::Atan, vec![Rc::new(self)])
+    }
+    /// Angle of the point `(x, self)`, in `(-π, π]`
+    pub fn atan2(self, x: Node) -> Node {
+        Node::new(Operation::Atan2, vec![Rc::new(self), Rc::new(x)])
+    }
+    pub fn abs(self) -> Node {
+        Node::new(Operation::Abs, vec![Rc::new(self)])
+    }
+    /// -1, 0 or 1; the derivative of `abs`
+    pub fn sign(self) -> Node {
+        Node::new(Operation::Sign, vec![Rc::new(self)])
+    }
+    /// max(self, 0), with derivative 0 at 0
+    pub fn relu(self) -> Node {
+        Node::new(Operation::Relu, vec![Rc::new(self)])
+    }
+    /// Logistic function 1 / (1 + e^-x)
+    pub fn sigmoid(self) -> Node {
+        Node::new(Operation::Sigmoid, vec![Rc::new(self)])
+    }
+    /// ln(1 + e^x), a smooth relu
+    pub fn softplus(self) -> Node {
+        Node::new(Operation::Softplus, vec![Rc::new(self)])
+    }
+    pub fn erf(self) -> Node {
+        Node::new(Operation::Erf, vec![Rc::new(self)])
+    }
+    pub fn gamma(self) -> Node {
+        Node::new(Operation::Gamma, vec![Rc::new(self)])
+    }
+    /// ln|Γ(self)|, defined where Γ overflows
+    pub fn ln_gamma(self) -> Node {
+        Node::new(Operation::LnGamma, vec![Rc::new(self)])
+    }
+    /// ψ(self) = Γ'(self) / Γ(self)
+    pub fn digamma(self) -> Node {
+        self.polygamma(zero())
+    }
+    /// The `order`-th derivative of the digamma function; `order` should be a whole number
+    pub fn polygamma(self, order: Node) -> Node {
+        Node::new(Operation::Polygamma, vec![Rc::new(order), Rc::new(self)])
+    }
+    /// Smaller of `self` and `other`; where they are equal, the derivative is the
+    /// average of theirs
+    pub fn min(self, other: Node) -> Node {
+        Node::new(Operation::Min, vec![Rc::new(self), Rc::new(other)])
+    }
+    /// Larger of `self` and `other`; where they are equal, the derivative is the
+    /// average of theirs
+    pub fn max(self, other: Node) -> Node {
+        Node::new(Operation::Max, vec![Rc::new(self), Rc::new(other)])
+    }
+    /// Clamp to `[lo, hi]`; NaN if `lo > hi` or a bound is NaN
+    pub fn clamp(self, lo: Node, hi: Node) -> Node {
+        Node::new(
+            Operation::Clamp,
+            vec![Rc::new(self), Rc::new(lo), Rc::new(hi)],
+        )
+    }
+    /// 1 if `lo < self < hi`, 0 otherwise (including on the boundaries)
+    pub fn in_range(self, lo: Node, hi: Node) -> Node {
+        Node::new(
+            Operation::InRange,
+            vec![Rc::new(self), Rc::new(lo), Rc::new(hi)],
+        )
+    }
+    /// 1 if `self < other`, 0 otherwise
+    pub fn lt(self, other: Node) -> Node {
+        Node::new(Operation::Lt, vec![Rc::new(self), Rc::new(other)])
+    }
+    /// 1 if `self > other`, 0 otherwise
+    pub fn gt(self, other: Node) -> Node {
+        Node::new(Operation::Gt, vec![Rc::new(self), Rc::new(other)])
+    }
+}
+pub fn pow(a: Node, b: Node) -> Node {
+    a.pow(b)
+}
+pub fn powi(base: Node, n: i64) -> Node {
+    base.powi(n)
+}
+pub fn log(base: Node, value: Node) -> Node {
+    value.log(base)
+}
+pub fn ln(value: Node) -> Node {
+    value.ln()
+}
+pub fn exp(value: Node) -> Node {
+    value.exp()
+}
+pub fn sqrt(value: Node) -> Node {
+    value.sqrt()
+}
+pub fn sin(value: Node) -> Node {
+    value.sin()
+}
+pub fn cos(value: Node) -> Node {
+    value.cos()
+}
+pub fn tan(value: Node) -> Node {
+    value.tan()
+}
+pub fn asin(value: Node) -> Node {
+    value.asin()
+}
+pub fn acos(value: Node) -> Node {
+    value.acos()
+}
+pub fn sinh(value: Node) -> Node {
+    value.sinh()
+}
+pub fn cosh(value: Node) -> Node {
+    value.cosh()
+}
+pub fn tanh(value: Node) -> Node {
+    value.tanh()
+}
+pub fn atan(value: Node) -> Node {
+    value.atan()
+}
+pub fn atan2(y: Node, x: Node) -> Node {
+    y.atan2(x)
+}
+pub fn abs(value: Node) -> Node {
+    value.abs()
+}
+pub fn sign(value: Node) -> Node {
+    value.sign()
+}
+pub fn relu(value: Node) -> Node {
+    value.relu()
+}
+pub fn sigmoid(value: Node) -> Node {
+    value.sigmoid()
+}
+pub fn softplus(value: Node) -> Node {
+    value.softplus()
+}
+pub fn erf(value: Node) -> Node {
+    value.erf()
+}
+pub fn gamma(value: Node) -> Node {
+    value.gamma()
+}
+pub fn ln_gamma(value: Node) -> Node {
+    value.ln_gamma()
+}
+pub fn digamma(value: Node) -> Node {
+    value.digamma()
+}
+pub fn polygamma(order: Node, value: Node) -> Node {
+    value.polygamma(order)
+}
+pub fn min(a: Node, b: Node) -> Node {
+    a.min(b)
+}
+pub fn max(a: Node, b: Node) -> Node {
+    a.max(b)
+}
+pub fn clamp(value: Node, lo: Node, hi: Node) -> Node {
+    value.clamp(lo, hi)
+}
+pub fn in_range(value: Node, lo: Node, hi: Node) -> Node {
+    value.in_range(lo, hi)
+}
+pub fn lt(a: Node, b: Node) -> Node {
+    a.lt(b)
+}
+pub fn gt(a: Node, b: Node) -> Node {
+    a.gt(b)
+}
+/// `then` where `condition` is nonzero, `otherwise` elsewhere. Only the selected branch is
+/// evaluated, so the other may be undefined there, e.g. `if_then_else(gt(x, 0), ln(x), 0)`.
+pub fn if_then_else(condition: Node, then: Node, otherwise: Node) -> Node {
+    Node::new(
+        Operation::If,
+        vec![Rc::new(condition), Rc::new(then), Rc::new(otherwise)],
+    )
+}
+/// Call a function added with `register_function`; panics if there is none by that name
+pub fn user_fn(name: &str, args: Vec<Node>) -> Node {
+    match user_function(name) {
+        Some(function) if function.arity == args.len() => Node::new(
+            Operation::UserFn(name.to_string()),
+            args.into_iter().map(Rc::new).collect(),
+        ),
+        Some(function) => panic!(
+            "{} takes {} argument(s), got {}",
+            name,
+            function.arity,
+            args.len()
+        ),
+        None => panic!("Function {} is not registered", name),
+    }
+}
+/// Apply `op` to `seed` `n` times, e.g. `iterate(sin, x, 3)` is `sin(sin(sin(x)))`
+pub fn iterate(op: impl Fn(Node) -> Node, seed: Node, n: usize) -> Node {
+    (0..n).fold(seed, |node, _| op(node))
+}
+/// A constant `value` that keeps its name, for sensitivities with `partial_derivative_param`
+pub fn param(name: &str, value: f64) -> Node {
+    Node::new(Operation::Param(name.to_string(), value), vec![])
+}
+pub fn var(name: &str) -> Node {
+    Node::new(Operation::Var(name.to_string()), vec![])
+}
+
+///////////////
+//  Parsing  //
+///////////////
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "parse error at {}: {}", self.position, self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum InfixToken {
+    Num(f64),
+    Ident(String),
+    Op(char),
+    LParen,
+    RParen,
+    Comma,
+}
+
+/// Split an infix expression into tokens, each paired with its byte offset
+fn tokenize(input: &str) -> Result<Vec<(usize, InfixToken)>, ParseError> {
+    let mut tokens = vec![];
+    let mut chars = input.char_indices().peekable();
+    while let Some(&(start, ch)) = chars.peek() {
+        if ch.is_whitespace() {
+            chars.next();
+        } else if ch.is_ascii_digit() || ch == '.' {
+            let mut end = start;
+            let mut previous = None;
+            while let Some(&(i, ch)) = chars.peek() {
+                let exponent_sign = (ch == '-' || ch == '+') && matches!(previous, Some('e' | 'E'));
+                // An `e` only starts an exponent if digits follow, so `2e` and `3exp(x)` are
+                // products with the constant e and with exp(x)
+                let mut rest = chars.clone().skip(1).map(|(_, ch)| ch);
+                let exponent = (ch == 'e' || ch == 'E')
+                    && match rest.next() {
+                        Some('+' | '-') => rest.next().is_some_and(|ch| ch.is_ascii_digit()),
+                        next => next.is_some_and(|ch| ch.is_ascii_digit()),
+                    };
+                if ch.is_ascii_digit() || ch == '.' || exponent || exponent_sign {
+                    end = i + ch.len_utf8();
+                    previous = Some(ch);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            let value = input[start..end].parse::<f64>().map_err(|_| ParseError {
+                position: start,
+                message: format!("invalid number '{}'", &input[start..end]),
+            })?;
+            tokens.push((start, InfixToken::Num(value)));
+        } else if ch.is_alphabetic() || ch == '_' {
+            let mut end = start;
+            while let Some(&(i, ch)) = chars.peek() {
+                if ch.is_alphanumeric() || ch == '_' {
+                    end = i + ch.len_utf8();
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push((start, InfixToken::Ident(input[start..end].to_string())));
+        } else {
+            let token = match ch {
+                '+' | '-' | '*' | '/' | '^' => InfixToken::Op(ch),
+                '(' => InfixToken::LParen,
+                ')' => InfixToken::RParen,
+                ',' => InfixToken::Comma,
+                _ => {
+                    return Err(ParseError {
+                        position: start,
+                        message: format!("unexpected character '{}'", ch),
+                    });
+                }
+            };
+            tokens.push((start, token));
+            chars.next();
+        }
+    }
+    Ok(tokens)
+}
+
+/// Precedence-climbing parser over infix tokens
+struct InfixParser {
+    tokens: Vec<(usize, InfixToken)>,
+    pos: usize,
+    end: usize,
+}
+
+impl InfixParser {
+    fn peek(&self) -> Option<&InfixToken> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |(i, _)| *i)
+    }
+
+    fn error(&self, message: String) -> ParseError {
+        ParseError {
+            position: self.position(),
+            message,
+        }
+    }
+
+    fn close_paren(&mut self) -> Result<(), ParseError> {
+        if self.peek() == Some(&InfixToken::RParen) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error("expected ')'".to_string()))
+        }
+    }
+
+    /// Parse operators binding at least as strongly as `min_precedence`. Juxtaposition is
+    /// multiplication, so `2x`, `2 sin(x) y` and `(x + 1)(x - 1)` are products
+    fn expression(&mut self, min_precedence: u8) -> Result<Node, ParseError> {
+        let mut lhs = self.unary()?;
+        let mut last_op = None;
+        loop {
+            let (op, implicit) = match self.peek() {
+                Some(InfixToken::Op(op)) => (*op, false),
+                Some(InfixToken::Num(_) | InfixToken::Ident(_) | InfixToken::LParen) => ('*', true),
+                _ => break,
+            };
+            let (precedence, right_associative) = match op {
+                '+' | '-' => (1, false),
+                '*' | '/' => (2, false),
+                _ => (4, true),
+            };
+            if precedence < min_precedence {
+                break;
+            }
+            if implicit {
+                self.check_implicit_product(last_op)?;
+            } else {
+                self.pos += 1;
+            }
+            last_op = Some(op);
+            let next_precedence = if right_associative {
+                precedence
+            } else {
+                precedence + 1
+            };
+            let rhs = self.expression(next_precedence)?;
+            lhs = match op {
+                '+' => lhs + rhs,
+                '-' => lhs - rhs,
+                '*' => lhs * rhs,
+                '/' => lhs / rhs,
+                _ => pow(lhs, rhs),
+            };
+        }
+        Ok(lhs)
+    }
+
+    /// Reject juxtapositions that read more than one way: `1/2x` could be `1/(2x)` or `x/2`,
+    /// and `2 3` is more likely a typo than `2*3`
+    fn check_implicit_product(&self, last_op: Option<char>) -> Result<(), ParseError> {
+        if last_op == Some('/') {
+            return Err(self.error(
+                "ambiguous implicit multiplication after '/', write a/(b*c) or a/b*c".to_string(),
+            ));
+        }
+        let previous = &self.tokens[self.pos - 1].1;
+        if matches!(previous, InfixToken::Num(_)) && matches!(self.peek(), Some(InfixToken::Num(_)))
+        {
+            return Err(self.error("missing operator between two numbers".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Unary minus binds tighter than `*` but looser than `^`, so `-x^2 = -(x^2)`
+    fn unary(&mut self) -> Result<Node, ParseError> {
+        if self.peek() == Some(&InfixToken::Op('-')) {
+            self.pos += 1;
+            return Ok(-self.expression(3)?);
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Node, ParseError> {
+        let start = self.position();
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(InfixToken::Num(value)) => Ok(c(value)),
+            Some(InfixToken::LParen) => {
+                let inner = self.expression(0)?;
+                self.close_paren()?;
+                Ok(inner)
+            }
+            Some(InfixToken::Ident(name)) => {
+                if self.peek() != Some(&InfixToken::LParen) {
+                    if function_arity(&name).is_some() {
+                        return Err(ParseError {
+                            position: start,
+                            message: format!(
+                                "function '{}' needs parentheses, e.g. {}(x)",
+                                name, name
+                            ),
+                        });
+                    }
+                    return Ok(named_constant(&name).map_or_else(|| var(&name), c));
+                }
+                self.pos += 1;
+                let mut args = vec![self.expression(0)?];
+                while self.peek() == Some(&InfixToken::Comma) {
+                    self.pos += 1;
+                    args.push(self.expression(0)?);
+                }
+                self.close_paren()?;
+                apply_function(&name, args).map_err(|message| ParseError {
+                    position: start,
+                    message,
+                })
+            }
+            _ => Err(ParseError {
+                position: start,
+                message: "expected a number, variable or '('".to_string(),
+            }),
+        }
+    }
+}
+
+/// Number of arguments taken by a named function
+fn function_arity(name: &str) -> Option<usize> {
+    match name {
+        "sin" | "cos" | "tan" | "asin" | "acos" | "atan" => Some(1),
+        "sinh" | "cosh" | "tanh" => Some(1),
+        "relu" | "sigmoid" | "softplus" => Some(1),
+        "erf" | "gamma" | "ln_gamma" | "digamma" => Some(1),
+        "ln" | "exp" | "sqrt" | "abs" | "sign" => Some(1),
+        "log" | "pow" | "atan2" | "min" | "max" | "lt" | "gt" | "polygamma" => Some(2),
+        "clamp" | "in_range" | "if" => Some(3),
+        _ => user_function(name).map(|function| function.arity),
+    }
+}
+
+/// Build a call to a named function, checking the argument count
+fn apply_function(name: &str, mut args: Vec<Node>) -> Result<Node, String> {
+    let Some(arity) = function_arity(name) else {
+        return Err(format!("unknown function '{}'", name));
+    };
+    if args.len() != arity {
+        return Err(format!(
+            "{} takes {} argument(s), got {}",
+            name,
+            arity,
+            args.len()
+        ));
+    }
+    let mut args = args.drain(..);
+    let mut next = || args.next().unwrap();
+    Ok(match name {
+        "sin" => sin(next()),
+        "cos" => cos(next()),
+        "tan" => tan(next()),
+        "asin" => asin(next()),
+        "acos" => acos(next()),
+        "sinh" => sinh(next()),
+        "cosh" => cosh(next()),
+        "tanh" => tanh(next()),
+        "relu" => relu(next()),
+        "sigmoid" => sigmoid(next()),
+        "softplus" => softplus(next()),
+        "erf" => erf(next()),
+        "gamma" => gamma(next()),
+        "ln_gamma" => ln_gamma(next()),
+        "digamma" => digamma(next()),
+        "polygamma" => polygamma(next(), next()),
+        "ln" => ln(next()),
+        "exp" => exp(next()),
+        "sqrt" => sqrt(next()),
+        "atan" => atan(next()),
+        "abs" => abs(next()),
+        "sign" => sign(next()),
+        "log" => log(next(), next()),
+        "pow" => pow(next(), next()),
+        "atan2" => atan2(next(), next()),
+        "min" => min(next(), next()),
+        "max" => max(next(), next()),
+        "clamp" => clamp(next(), next(), next()),
+        "in_range" => in_range(next(), next(), next()),
+        "lt" => lt(next(), next()),
+        "gt" => gt(next(), next()),
+        "if" => if_then_else(next(), next(), next()),
+        _ => user_fn(name, args.collect()),
+    })
+}
+
+/// Parse an infix expression such as `3*x + 4*sin(y) - x^2`
+pub fn parse(input: &str) -> Result<Node, ParseError> {
+    let mut parser = InfixParser {
+        tokens: tokenize(input)?,
+        pos: 0,
+        end: input.len(),
+    };
+    let node = parser.expression(0)?;
+    if parser.pos < parser.tokens.len() {
+        return Err(parser.error("unexpected trailing input".to_string()));
+    }
+    Ok(node)
+}
+
+impl Node {
+    /// Parse an infix expression, the same as the free function `parse`
+    pub fn parse(input: &str) -> Result<Node, ParseError> {
+        parse(input)
+    }
+
+    /// Parse LaTeX, the same as the free function `from_latex`
+    pub fn from_latex(input: &str) -> Result<Node, ParseError> {
+        from_latex(input)
+    }
+}
+
+impl std::str::FromStr for Node {
+    type Err = ParseError;
+
+    /// Parse an infix expression, so that `"3*x + 5".parse::<Node>()` works
+    fn from_str(input: &str) -> Result<Node, ParseError> {
+        parse(input)
+    }
+}
+
+/// Recursive-descent parser for the LaTeX written by `to_latex` and in homework write-ups:
+/// `\frac`, `\sqrt`, `\cdot`, juxtaposition, named functions, powers and subscripts
+struct LatexParser {
+    chars: Vec<(usize, char)>,
+    pos: usize,
+    end: usize,
+}
+
+impl LatexParser {
+    fn position(&self) -> usize {
+        self.chars.get(self.pos).map_or(self.end, |(i, _)| *i)
+    }
+
+    fn error(&self, message: String) -> ParseError {
+        ParseError {
+            position: self.position(),
+            message,
+        }
+    }
+
+    /// The next character, after skipping whitespace and spacing like `\,`
+    fn peek(&mut self) -> Option<char> {
+        loop {
+            match self.chars.get(self.pos).map(|(_, ch)| *ch) {
+                Some(ch) if ch.is_whitespace() => self.pos += 1,
+                Some('\\')
+                    if matches!(
+                        self.chars.get(self.pos + 1),
+                        Some((_, ',' | ';' | ':' | '!' | ' '))
+                    ) =>
+                {
+                    self.pos += 2
+                }
+                ch => return ch,
+            }
+        }
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        if self.eat(expected) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected '{}'", expected)))
+        }
+    }
+
+    /// The name of the command at the cursor, `frac` for `\frac`, without consuming it
+    fn peek_command(&mut self) -> Option<String> {
+        if self.peek() != Some('\\') {
+            return None;
+        }
+        let name = self.chars[self.pos + 1..]
+            .iter()
+            .map(|(_, ch)| *ch)
+            .take_while(|ch| ch.is_ascii_alphabetic())
+            .collect();
+        Some(name)
+    }
+
+    fn eat_command(&mut self, name: &str) -> bool {
+        if self.peek_command().as_deref() == Some(name) {
+            self.pos += 1 + name.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Sums and differences of terms
+    fn expression(&mut self) -> Result<Node, ParseError> {
+        let mut lhs = self.term()?;
+        loop {
+            if self.eat('+') {
+                lhs = lhs + self.term()?;
+            } else if self.eat('-') {
+                lhs = lhs - self.term()?;
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+
+    /// Products and quotients, written with `\cdot`, `\times`, `*`, `/` or by juxtaposition
+    fn term(&mut self) -> Result<Node, ParseError> {
+        let mut lhs = if self.eat('-') {
+            -self.power()?
+        } else {
+            self.power()?
+        };
+        loop {
+            if self.eat('*') || self.eat_command("cdot") || self.eat_command("times") {
+                lhs = lhs * self.power()?;
+            } else if self.eat('/') {
+                lhs = lhs / self.power()?;
+            } else if self.starts_factor() {
+                lhs = lhs * self.power()?;
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+
+    fn starts_factor(&mut self) -> bool {
+        match self.peek() {
+            Some('\\') => !matches!(
+                self.peek_command().as_deref(),
+                Some("" | "right" | "cdot" | "times")
+            ),
+            Some(ch) => ch.is_alphanumeric() || matches!(ch, '.' | '(' | '[' | '{'),
+            None => false,
+        }
+    }
+
+    /// A factor with an optional exponent, `x^{2}`, with `e^{x}` read as `exp(x)`
+    fn power(&mut self) -> Result<Node, ParseError> {
+        let base = self.primary()?;
+        if !self.eat('^') {
+            return Ok(base);
+        }
+        let exponent = self.group()?;
+        Ok(match base.op {
+            Operation::Const(value) if value == std::f64::consts::E => exp(exponent),
+            _ => pow(base, exponent),
+        })
+    }
+
+    /// A braced group, or a single digit or symbol as in `x^2` and `\frac12`
+    fn group(&mut self) -> Result<Node, ParseError> {
+        if self.eat('{') {
+            let inner = self.expression()?;
+            self.expect('}')?;
+            return Ok(inner);
+        }
+        match self.peek() {
+            Some(ch) if ch.is_ascii_digit() => {
+                self.pos += 1;
+                Ok(c(ch.to_digit(10).unwrap() as f64))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node, ParseError> {
+        let close = match self.peek() {
+            Some('(') => ')',
+            Some('[') => ']',
+            Some('{') => '}',
+            Some('\\') => return self.command(),
+            Some(ch) if ch.is_ascii_digit() || ch == '.' => return self.number(),
+            Some(ch) if ch.is_alphabetic() => {
+                self.pos += 1;
+                let name = self.subscript(ch.to_string())?;
+                return Ok(if name == "e" { e() } else { var(&name) });
+            }
+            _ => return Err(self.error("expected a number, variable or group".to_string())),
+        };
+        self.pos += 1;
+        let inner = self.expression()?;
+        self.expect(close)?;
+        Ok(inner)
+    }
+
+    fn number(&mut self) -> Result<Node, ParseError> {
+        let start = self.pos;
+        while matches!(self.chars.get(self.pos), Some((_, ch)) if ch.is_ascii_digit() || *ch == '.')
+        {
+            self.pos += 1;
+        }
+        let text = self.chars[start..self.pos]
+            .iter()
+            .map(|(_, ch)| *ch)
+            .collect::<String>();
+        text.parse::<f64>().map(c).map_err(|_| ParseError {
+            position: self.chars[start].0,
+            message: format!("invalid number '{}'", text),
+        })
+    }
+
+    /// Append a subscript to a variable name, so `x_{1}` is the variable `x_1`
+    fn subscript(&mut self, name: String) -> Result<String, ParseError> {
+        if !self.eat('_') {
+            return Ok(name);
+        }
+        let mut subscript = String::new();
+        let braced = self.eat('{');
+        loop {
+            match self.peek() {
+                Some('}') if braced => {
+                    self.pos += 1;
+                    break;
+                }
+                Some('\\') => subscript += &self.command_name()?,
+                Some(ch) if ch.is_alphanumeric() => {
+                    self.pos += 1;
+                    subscript.push(ch);
+                }
+                _ => return Err(self.error(format!("expected a subscript of {}", name))),
+            }
+            if !braced {
+                break;
+            }
+        }
+        Ok(format!("{}_{}", name, subscript))
+    }
+
+    /// Consume a command and return the name it spells, `alpha` for `\alpha` and
+    /// `rate` for `\mathrm{rate}`
+    fn command_name(&mut self) -> Result<String, ParseError> {
+        let command = self.peek_command().unwrap_or_default();
+        self.pos += 1 + command.len();
+        if !matches!(
+            command.as_str(),
+            "mathrm" | "mathit" | "operatorname" | "text"
+        ) {
+            return Ok(command);
+        }
+        self.expect('{')?;
+        let mut text = String::new();
+        while let Some(&(_, ch)) = self.chars.get(self.pos) {
+            if ch == '}' {
+                break;
+            }
+            text.push(ch);
+            self.pos += 1;
+        }
+        self.expect('}')?;
+        Ok(text.trim().to_string())
+    }
+
+    fn command(&mut self) -> Result<Node, ParseError> {
+        let start = self.position();
+        let command = self.peek_command().unwrap_or_default();
+        match command.as_str() {
+            "left" => {
+                self.pos += 1 + command.len();
+                let (open, close) = match self.peek() {
+                    Some('(') => ('(', ')'),
+                    Some('[') => ('[', ']'),
+                    Some('|') => ('|', '|'),
+                    _ => {
+                        return Err(self.error("expected '(', '[' or '|' after \\left".to_string()));
+                    }
+                };
+                self.pos += 1;
+                let inner = self.expression()?;
+                if !self.eat_command("right") {
+                    return Err(self.error("expected \\right".to_string()));
+                }
+                self.expect(close)?;
+                Ok(if open == '|' { abs(inner) } else { inner })
+            }
+            "frac" | "dfrac" | "tfrac" => {
+                self.pos += 1 + command.len();
+                let numerator = self.group()?;
+                Ok(numerator / self.group()?)
+            }
+            "sqrt" => {
+                self.pos += 1 + command.len();
+                if self.eat('[') {
+                    let degree = self.expression()?;
+                    self.expect(']')?;
+                    Ok(pow(self.group()?, 1.0 / degree))
+                } else {
+                    Ok(sqrt(self.group()?))
+                }
+            }
+            "pi" => {
+                self.pos += 1 + command.len();
+                Ok(pi())
+            }
+            "log" => {
+                self.pos += 1 + command.len();
+                if !self.eat('_') {
+                    return Err(ParseError {
+                        position: start,
+                        message: "\\log needs a base, write \\log_{10} or \\ln".to_string(),
+                    });
+                }
+                let base = self.group()?;
+                self.call("log", start, vec![base])
+            }
+            _ => {
+                let name = self.command_name()?;
+                let spelled = matches!(
+                    command.as_str(),
+                    "mathrm" | "mathit" | "operatorname" | "text"
+                );
+                let greek = GREEK_LETTERS.contains(&name.as_str()) && name != "Gamma";
+                match latex_function(&name) {
+                    Some(function) if !greek => self.call(function, start, vec![]),
+                    _ if greek || spelled => Ok(var(&self.subscript(name)?)),
+                    _ => Err(ParseError {
+                        position: start,
+                        message: format!("unsupported command \\{}", command),
+                    }),
+                }
+            }
+        }
+    }
+
+    /// The arguments of a function, `\sin\left(x\right)`, `\max(a, b)`, `\sin^{2} x` or
+    /// `\sqrt{x}`, after any already given like the base of `\log_b`
+    fn call(&mut self, name: &str, start: usize, mut args: Vec<Node>) -> Result<Node, ParseError> {
+        let exponent = if self.eat('^') {
+            Some(self.group()?)
+        } else {
+            None
+        };
+        let left = self.eat_command("left");
+        if left || self.peek() == Some('(') {
+            self.expect('(')?;
+            args.push(self.expression()?);
+            while self.eat(',') {
+                args.push(self.expression()?);
+            }
+            if left && !self.eat_command("right") {
+                return Err(self.error("expected \\right".to_string()));
+            }
+            self.expect(')')?;
+        } else {
+            args.push(self.power()?);
+        }
+        let value = apply_function(name, args).map_err(|message| ParseError {
+            position: start,
+            message,
+        })?;
+        Ok(match exponent {
+            Some(exponent) => pow(value, exponent),
+            None => value,
+        })
+    }
+}
+
+/// The function a LaTeX command or `\operatorname` names, as spelled by `parse`
+fn latex_function(name: &str) -> Option<&str> {
+    Some(match name {
+        "arcsin" => "asin",
+        "arccos" => "acos",
+        "arctan" => "atan",
+        "sgn" => "sign",
+        "ReLU" => "relu",
+        "Gamma" => "gamma",
+        name if function_arity(name).is_some() => name,
+        _ => return None,
+    })
+}
+
+/// Parse LaTeX such as `\frac{x^{2}}{2} + \sin\left(\alpha_{1} x\right)`, the inverse of
+/// `Node::to_latex`. Subscripts become part of the name, so `x_{1}` is the variable `x_1`.
+pub fn from_latex(input: &str) -> Result<Node, ParseError> {
+    let mut parser = LatexParser {
+        chars: input.char_indices().collect(),
+        pos: 0,
+        end: input.len(),
+    };
+    let node = parser.expression()?;
+    if parser.peek().is_some() {
+        return Err(parser.error("unexpected trailing input".to_string()));
+    }
+    Ok(node)
+}
+
+/// Token of a postfix (reverse Polish) expression
+#[derive(Debug, Clone)]
+pub enum Token {
+    Num(f64),
+    Var(String),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    /// Named function such as `sin` or `log`, taking its arguments from the stack
+    Func(String),
+}
+
+/// Build an expression from postfix tokens, e.g. `3 x * 5 +` for `3*x + 5`.
+/// Error positions are token indices.
+pub fn from_rpn(tokens: &[Token]) -> Result<Node, ParseError> {
+    let mut stack: Vec<Node> = vec![];
+    for (position, token) in tokens.iter().enumerate() {
+        let error = |message: String| ParseError { position, message };
+        let arity = match token {
+            Token::Num(_) | Token::Var(_) => 0,
+            Token::Func(name) => {
+                function_arity(name).ok_or(error(format!("unknown function '{}'", name)))?
+            }
+            _ => 2,
+        };
+        if stack.len() < arity {
+            return Err(error(format!(
+                "{:?} needs {} operand(s), stack has {}",
+                token,
+                arity,
+                stack.len()
+            )));
+        }
+        let mut args = stack.split_off(stack.len() - arity);
+        let node = match token {
+            Token::Num(value) => c(*value),
+            Token::Var(name) => var(name),
+            Token::Func(name) => apply_function(name, args).map_err(error)?,
+            _ => {
+                let b = args.pop().unwrap();
+                let a = args.pop().unwrap();
+                match token {
+                    Token::Add => a + b,
+                    Token::Sub => a - b,
+                    Token::Mul => a * b,
+                    Token::Div => a / b,
+                    _ => pow(a, b),
+                }
+            }
+        };
+        stack.push(node);
+    }
+    match stack.len() {
+        1 => Ok(stack.pop().unwrap()),
+        n => Err(ParseError {
+            position: tokens.len(),
+            message: format!("expected a single result, stack has {}", n),
+        }),
+    }
+}
+
+/// Parse space-separated postfix tokens such as `3 x * 5 +`
+pub fn parse_postfix(input: &str) -> Result<Node, ParseError> {
+    let tokens = input
+        .split_whitespace()
+        .map(|word| match word {
+            "+" => Token::Add,
+            "-" => Token::Sub,
+            "*" => Token::Mul,
+            "/" => Token::Div,
+            "^" => Token::Pow,
+            _ if function_arity(word).is_some() => Token::Func(word.to_string()),
+            _ if let Some(value) = named_constant(word) => Token::Num(value),
+            _ => match word.parse::<f64>() {
+                Ok(value) => Token::Num(value),
+                Err(_) => Token::Var(word.to_string()),
+            },
+        })
+        .collect::<Vec<Token>>();
+    from_rpn(&tokens)
+}
+
+/////////////////////
+//  Serialization  //
+/////////////////////
+
+/// Leading bytes of `Node::to_bytes`, followed by `BINARY_VERSION`
+const BINARY_MAGIC: &[u8; 4] = b"FADX";
+const BINARY_VERSION: u8 = 1;
+
+/// Tags of the operations without data in the binary format, by position. Only ever append
+/// to this list, or files written by older builds decode to the wrong operations.
+const BINARY_OPCODES: [Operation; 32] = [
+    Operation::Add,
+    Operation::Mul,
+    Operation::Pow,
+    Operation::Sin,
+    Operation::Cos,
+    Operation::Tan,
+    Operation::Asin,
+    Operation::Acos,
+    Operation::Sinh,
+    Operation::Cosh,
+    Operation::Tanh,
+    Operation::Log,
+    Operation::Exp,
+    Operation::Sqrt,
+    Operation::Atan,
+    Operation::Atan2,
+    Operation::Abs,
+    Operation::Sign,
+    Operation::Min,
+    Operation::Max,
+    Operation::Relu,
+    Operation::Sigmoid,
+    Operation::Softplus,
+    Operation::Erf,
+    Operation::Gamma,
+    Operation::LnGamma,
+    Operation::Polygamma,
+    Operation::Clamp,
+    Operation::InRange,
+    Operation::Lt,
+    Operation::Gt,
+    Operation::If,
+];
+const BINARY_VAR: u8 = 128;
+const BINARY_CONST: u8 = 129;
+const BINARY_PARAM: u8 = 130;
+const BINARY_USER_FN: u8 = 131;
+/// A constant that is a small integer, stored as a zigzag varint rather than eight bytes
+const BINARY_INTEGER: u8 = 132;
+
+/// Why `Node::from_bytes` or `Node::from_json` rejected its input
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The input does not start with the magic bytes of the format
+    NotAnExpression,
+    /// Written by a newer build in a version this one cannot read
+    UnsupportedVersion(u64),
+    /// The input is not well-formed JSON
+    Json(String),
+    /// The input ends in the middle of the expression
+    Truncated,
+    Invalid(&'static str),
+}
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::NotAnExpression => write!(f, "not an encoded expression"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+            DecodeError::Json(message) => write!(f, "malformed JSON: {}", message),
+            DecodeError::Truncated => write!(f, "the encoded expression is truncated"),
+            DecodeError::Invalid(reason) => write!(f, "invalid encoded expression: {}", reason),
+        }
+    }
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Cursor over the input of `Node::from_bytes`
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl ByteReader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], DecodeError> {
+        let end = self.pos.checked_add(n).ok_or(DecodeError::Truncated)?;
+        let bytes = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or(DecodeError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<usize, DecodeError> {
+        let mut value = 0usize;
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as usize)
+                .checked_shl(shift)
+                .ok_or(DecodeError::Invalid("varint overflows"))?;
+            if byte < 0x80 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::Invalid("varint overflows"))
+    }
+
+    fn f64(&mut self) -> Result<f64, DecodeError> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// A string from the table by its varint index
+    fn string(&mut self, strings: &[String]) -> Result<String, DecodeError> {
+        let index = self.varint()?;
+        strings
+            .get(index)
+            .cloned()
+            .ok_or(DecodeError::Invalid("string index out of range"))
+    }
+}
+
+impl Node {
+    /// Encode compactly for caching, e.g. large derivatives on disk. The format is the magic
+    /// `FADX` and a version byte, a string table of the names used (a varint count, then each
+    /// name as a varint length and UTF-8), and a varint node count followed by the nodes in
+    /// postfix order: an operation tag, then a string index, an `f64` (a zigzag varint for
+    /// small integers) or a user function's arity where the operation carries one.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut postfix = vec![];
+        let mut pending = vec![(self, false)];
+        while let Some((node, expanded)) = pending.pop() {
+            if expanded {
+                postfix.push(node);
+            } else {
+                pending.push((node, true));
+                pending.extend(node.args.iter().rev().map(|arg| (&**arg, false)));
+            }
+        }
+        let mut strings: Vec<String> = vec![];
+        let mut string_ids = HashMap::new();
+        let mut nodes = vec![];
+        for node in &postfix {
+            let mut string_id = |name: &str| {
+                *string_ids.entry(name.to_string()).or_insert_with(|| {
+                    strings.push(name.to_string());
+                    strings.len() - 1
+                })
+            };
+            match &node.op {
+                Operation::Var(name) => {
+                    nodes.push(BINARY_VAR);
+                    write_varint(&mut nodes, string_id(name));
+                }
+                Operation::Const(value)
+                    if float::fract(*value) == 0.0
+                        && float::abs(*value) <= i32::MAX as f64
+                        && !(*value == 0.0 && value.is_sign_negative()) =>
+                {
+                    let value = *value as i64;
+                    nodes.push(BINARY_INTEGER);
+                    write_varint(&mut nodes, ((value << 1) ^ (value >> 63)) as usize);
+                }
+                Operation::Const(value) => {
+                    nodes.push(BINARY_CONST);
+                    nodes.extend(value.to_le_bytes());
+                }
+                Operation::Param(name, value) => {
+                    nodes.push(BINARY_PARAM);
+                    write_varint(&mut nodes, string_id(name));
+                    nodes.extend(value.to_le_bytes());
+                }
+                Operation::UserFn(name) => {
+                    nodes.push(BINARY_USER_FN);
+                    write_varint(&mut nodes, string_id(name));
+                    write_varint(&mut nodes, node.args.len());
+                }
+                op => nodes.push(BINARY_OPCODES.iter().position(|code| code == op).unwrap() as u8),
+            }
+        }
+        let mut bytes = BINARY_MAGIC.to_vec();
+        bytes.push(BINARY_VERSION);
+        write_varint(&mut bytes, strings.len());
+        for string in strings {
+            write_varint(&mut bytes, string.len());
+            bytes.extend(string.as_bytes());
+        }
+        write_varint(&mut bytes, postfix.len());
+        bytes.extend(nodes);
+        bytes
+    }
+
+    /// Decode the output of `to_bytes`. The tree comes back as it was saved, without being
+    /// simplified again.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Node, DecodeError> {
+        let mut reader = ByteReader { bytes, pos: 0 };
+        if reader.take(4).ok() != Some(BINARY_MAGIC) {
+            return Err(DecodeError::NotAnExpression);
+        }
+        let version = reader.byte()?;
+        if version != BINARY_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version.into()));
+        }
+        let mut strings = vec![];
+        for _ in 0..reader.varint()? {
+            let length = reader.varint()?;
+            let string = std::str::from_utf8(reader.take(length)?)
+                .map_err(|_| DecodeError::Invalid("a name is not UTF-8"))?;
+            strings.push(string.to_string());
+        }
+        let mut stack: Vec<Rc<Node>> = vec![];
+        for _ in 0..reader.varint()? {
+            let tag = reader.byte()?;
+            let (op, arity) = match tag {
+                BINARY_VAR => (Operation::Var(reader.string(&strings)?), 0),
+                BINARY_CONST => (Operation::Const(reader.f64()?), 0),
+                BINARY_INTEGER => {
+                    let zigzag = reader.varint()? as i64;
+                    (Operation::Const(((zigzag >> 1) ^ -(zigzag & 1)) as f64), 0)
+                }
+                BINARY_PARAM => {
+                    let name = reader.string(&strings)?;
+                    (Operation::Param(name, reader.f64()?), 0)
+                }
+                BINARY_USER_FN => (
+                    Operation::UserFn(reader.string(&strings)?),
+                    reader.varint()?,
+                ),
+                _ => {
+                    let op = BINARY_OPCODES
+                        .get(tag as usize)
+                        .cloned()
+                        .ok_or(DecodeError::Invalid("unknown operation tag"))?;
+                    let arity = op.arity().unwrap();
+                    (op, arity)
+                }
+            };
+            if arity > stack.len() {
+                return Err(DecodeError::Invalid("an operation is missing arguments"));
+            }
+            let args = stack.split_off(stack.len() - arity);
+            stack.push(Rc::new(Node { op, args }));
+        }
+        if reader.pos != bytes.len() {
+            return Err(DecodeError::Invalid("trailing bytes after the expression"));
+        }
+        match (stack.pop(), stack.is_empty()) {
+            (Some(node), true) => Ok(Rc::unwrap_or_clone(node)),
+            _ => Err(DecodeError::Invalid(
+                "the nodes do not form a single expression",
+            )),
+        }
+    }
+}
+
+/// Version of the JSON written by `Node::to_json`
+#[cfg(feature = "serde")]
+const JSON_VERSION: u64 = 1;
+
+#[cfg(feature = "serde")]
+impl Node {
+    /// Save as JSON in a stable, versioned structure:
+    ///
+    /// ```json
+    /// {
+    ///   "version": 1,
+    ///   "nodes": [
+    ///     {"op": "var", "name": "x"},
+    ///     {"op": "const", "value": 2},
+    ///     {"op": "mul", "args": [1, 0]},
+    ///     {"op": "sin", "args": [2]}
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// The nodes are listed arguments first, each referring to its arguments by index, and the
+    /// last node is the expression. Equal subexpressions are listed once. Operations are named
+    /// `add`, `mul`, `pow`, `var`, `const` and `param` (with a `name` and a `value`), and
+    /// functions as `parse` spells them, with `log` taking the base first. Values that JSON
+    /// cannot hold are written as the strings `"NaN"`, `"inf"` and `"-inf"`.
+    ///
+    /// New operations are added without changing the version, and `from_json` loads one it
+    /// does not know as a user function of that name, to be provided with `register_function`.
+    /// Unknown fields are ignored. The version only changes when existing fields change their
+    /// meaning, and builds reject versions newer than their own.
+    pub fn to_json(&self) -> String {
+        let nodes = self
+            .shared_graph()
+            .into_iter()
+            .map(|(node, args)| {
+                let number = |value: f64| {
+                    if value.is_finite() {
+                        serde_json::json!(value)
+                    } else {
+                        serde_json::json!(format!("{}", value))
+                    }
+                };
+                let op = match &node.op {
+                    Operation::Var(_) => "var",
+                    Operation::Const(_) => "const",
+                    Operation::Param(..) => "param",
+                    Operation::Add => "add",
+                    Operation::Mul => "mul",
+                    Operation::Pow => "pow",
+                    op => op.function_name().unwrap(),
+                };
+                let mut entry = serde_json::json!({ "op": op });
+                match &node.op {
+                    Operation::Var(name) => entry["name"] = serde_json::json!(name),
+                    Operation::Const(value) => entry["value"] = number(*value),
+                    Operation::Param(name, value) => {
+                        entry["name"] = serde_json::json!(name);
+                        entry["value"] = number(*value);
+                    }
+                    _ => entry["args"] = serde_json::json!(args),
+                }
+                entry
+            })
+            .collect::<Vec<serde_json::Value>>();
+        serde_json::json!({ "version": JSON_VERSION, "nodes": nodes }).to_string()
+    }
+
+    /// Load the JSON written by `to_json` in this or an older build. The tree comes back as it
+    /// was saved, without being simplified again.
+    pub fn from_json(json: &str) -> Result<Node, DecodeError> {
+        use serde_json::Value;
+        let document = serde_json::from_str::<Value>(json)
+            .map_err(|error| DecodeError::Json(error.to_string()))?;
+        let version = document["version"]
+            .as_u64()
+            .ok_or(DecodeError::Invalid("missing version"))?;
+        if version > JSON_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let entries = document["nodes"]
+            .as_array()
+            .ok_or(DecodeError::Invalid("missing nodes"))?;
+        let mut nodes: Vec<Node> = vec![];
+        for entry in entries {
+            let name = || match &entry["name"] {
+                Value::String(name) => Ok(name.clone()),
+                _ => Err(DecodeError::Invalid("a variable or parameter has no name")),
+            };
+            let value = || match &entry["value"] {
+                Value::Number(value) => value.as_f64(),
+                Value::String(value) => value.parse::<f64>().ok(),
+                _ => None,
+            };
+            let value = || value().ok_or(DecodeError::Invalid("a constant has no value"));
+            let args = match &entry["args"] {
+                Value::Null => vec![],
+                Value::Array(args) => args
+                    .iter()
+                    .map(|arg| {
+                        let arg = arg.as_u64().and_then(|i| nodes.get(i as usize));
+                        arg.map(|arg| Rc::new(arg.clone()))
+                            .ok_or(DecodeError::Invalid(
+                                "an argument does not refer to an earlier node",
+                            ))
+                    })
+                    .collect::<Result<Vec<Rc<Node>>, DecodeError>>()?,
+                _ => return Err(DecodeError::Invalid("the arguments are not a list")),
+            };
+            let op = match entry["op"].as_str() {
+                Some("var") => Operation::Var(name()?),
+                Some("const") => Operation::Const(value()?),
+                Some("param") => Operation::Param(name()?, value()?),
+                Some("add") => Operation::Add,
+                Some("mul") => Operation::Mul,
+                Some("pow") => Operation::Pow,
+                Some(function) => BINARY_OPCODES
+                    .iter()
+                    .find(|op| op.function_name() == Some(function))
+                    .cloned()
+                    .unwrap_or_else(|| Operation::UserFn(function.to_string())),
+                None => return Err(DecodeError::Invalid("a node has no op")),
+            };
+            if op.arity().is_some_and(|arity| arity != args.len()) {
+                return Err(DecodeError::Invalid(
+                    "an operation has the wrong number of arguments",
+                ));
+            }
+            nodes.push(Node { op, args });
+        }
+        nodes.pop().ok_or(DecodeError::Invalid("no nodes"))
+    }
+}
+
+///////////////////////
+//  Code generation  //
+///////////////////////
+
+/// Source code for expressions in other languages. Subexpressions that occur more than once are
+/// computed once into temporaries
+pub mod codegen {
+    use super::{Node, Operation, float};
+    use std::collections::HashMap;
+    use std::fmt;
+
+    /// Expressions numbered so that equal subtrees share one entry, arguments before operations
+    struct Dag<'a> {
+        entries: Vec<Entry<'a>>,
+        ids: HashMap<(Operation, Vec<usize>), usize>,
+    }
+
+    struct Entry<'a> {
+        node: &'a Node,
+        args: Vec<usize>,
+        /// How often the value is written out by the operations using it
+        uses: usize,
+    }
+
+    impl<'a> Dag<'a> {
+        fn new() -> Self {
+            Dag {
+                entries: vec![],
+                ids: HashMap::new(),
+            }
+        }
+
+        fn add(&mut self, node: &'a Node, syntax: &dyn Syntax) -> usize {
+            let args = node
+                .args
+                .iter()
+                .map(|arg| self.add(arg, syntax))
+                .collect::<Vec<usize>>();
+            let key = (node.op.clone(), args.clone());
+            if let Some(&id) = self.ids.get(&key) {
+                return id;
+            }
+            for &arg in &args {
+                self.entries[arg].uses += syntax.arg_repeats(node);
+            }
+            self.entries.push(Entry {
+                node,
+                args,
+                uses: 0,
+            });
+            self.ids.insert(key, self.entries.len() - 1);
+            self.entries.len() - 1
+        }
+
+        /// Whether the entry gets a temporary: it is an operation whose value is used repeatedly
+        fn is_temporary(&self, id: usize) -> bool {
+            !self.entries[id].args.is_empty() && self.entries[id].uses > 1
+        }
+
+        /// Declarations of all temporaries, one per line with the given indentation
+        fn temporaries(&self, syntax: &dyn Syntax, indent: &str) -> String {
+            (0..self.entries.len())
+                .filter(|&id| self.is_temporary(id))
+                .map(|id| {
+                    let value = self.render_value(id, syntax).0;
+                    format!(
+                        "{}{}\n",
+                        indent,
+                        syntax.declare(&format!("t{}", id), &value)
+                    )
+                })
+                .collect()
+        }
+
+        /// The expression for an entry and the binding strength of its outermost operator
+        fn render(&self, id: usize, syntax: &dyn Syntax) -> (String, u8) {
+            if self.is_temporary(id) {
+                (format!("t{}", id), 5)
+            } else {
+                self.render_value(id, syntax)
+            }
+        }
+
+        fn render_value(&self, id: usize, syntax: &dyn Syntax) -> (String, u8) {
+            let entry = &self.entries[id];
+            let node = entry.node;
+            let wrap = |id: usize, min_precedence: u8| {
+                let (code, precedence) = self.render(id, syntax);
+                if precedence < min_precedence {
+                    format!("({})", code)
+                } else {
+                    code
+                }
+            };
+            // The argument of a negation `-1 * a` that is written out in place
+            let negated = |id: usize| {
+                let entry = &self.entries[id];
+                let is_negation = !self.is_temporary(id) && entry.node.is_negation();
+                is_negation.then(|| entry.args[1])
+            };
+            match &node.op {
+                Operation::Var(name) => (name.clone(), 5),
+                Operation::Const(value) | Operation::Param(_, value) => {
+                    let precedence = if *value < 0.0 { 3 } else { 5 };
+                    (syntax.constant(*value), precedence)
+                }
+                Operation::Add => match negated(entry.args[1]) {
+                    Some(term) => (format!("{} - {}", wrap(entry.args[0], 1), wrap(term, 2)), 1),
+                    None => (
+                        format!("{} + {}", wrap(entry.args[0], 1), wrap(entry.args[1], 2)),
+                        1,
+                    ),
+                },
+                Operation::Mul if node.is_negation() => (format!("-{}", wrap(entry.args[1], 4)), 3),
+                Operation::Mul
+                    if node.args[1].is_reciprocal() && !self.is_temporary(entry.args[1]) =>
+                {
+                    let denominator = self.entries[entry.args[1]].args[0];
+                    (
+                        format!("{} / {}", wrap(entry.args[0], 2), wrap(denominator, 3)),
+                        2,
+                    )
+                }
+                Operation::Mul => (
+                    format!("{} * {}", wrap(entry.args[0], 2), wrap(entry.args[1], 3)),
+                    2,
+                ),
+                _ => {
+                    let args = entry
+                        .args
+                        .iter()
+                        .map(|&arg| self.render(arg, syntax).0)
+                        .collect::<Vec<String>>();
+                    (syntax.function(node, &args), 5)
+                }
+            }
+        }
+    }
+
+    /// How a target language writes constants, functions and temporaries. Sums, products,
+    /// quotients and negations use the same C-like operators in all targets
+    trait Syntax {
+        fn constant(&self, value: f64) -> String;
+        /// An operation other than a sum or product, with its arguments already written out.
+        /// The result is used as an operand as is, so anything but a call is parenthesized
+        fn function(&self, node: &Node, args: &[String]) -> String;
+        /// How many times `function` writes out each argument of `node`
+        fn arg_repeats(&self, _node: &Node) -> usize {
+            1
+        }
+        fn declare(&self, name: &str, value: &str) -> String;
+    }
+
+    /// C99 functions using `math.h`
+    pub mod c {
+        use super::{Dag, Syntax};
+        use crate::{Node, Operation};
+
+        struct C;
+
+        impl Syntax for C {
+            fn constant(&self, value: f64) -> String {
+                match value {
+                    value if value.is_nan() => "NAN".to_string(),
+                    f64::INFINITY => "INFINITY".to_string(),
+                    f64::NEG_INFINITY => "-INFINITY".to_string(),
+                    value => format!("{:?}", value),
+                }
+            }
+
+            fn function(&self, node: &Node, args: &[String]) -> String {
+                let call = |name: &str| format!("{}({})", name, args.join(", "));
+                match &node.op {
+                    Operation::Pow => call("pow"),
+                    Operation::Log if node.is_ln() => format!("log({})", args[1]),
+                    Operation::Log => format!("(log({}) / log({}))", args[1], args[0]),
+                    Operation::Abs => call("fabs"),
+                    Operation::Min => call("fmin"),
+                    Operation::Max => call("fmax"),
+                    Operation::Gamma => call("tgamma"),
+                    Operation::LnGamma => call("lgamma"),
+                    Operation::Relu => format!("fmax({}, 0.0)", args[0]),
+                    Operation::Sigmoid => format!("(1.0 / (1.0 + exp(-({}))))", args[0]),
+                    Operation::Softplus => {
+                        format!("(fmax({0}, 0.0) + log1p(exp(-fabs({0}))))", args[0])
+                    }
+                    Operation::Sign => format!("(double)(({0} > 0.0) - ({0} < 0.0))", args[0]),
+                    Operation::Clamp => {
+                        format!("fmin(fmax({}, {}), {})", args[0], args[1], args[2])
+                    }
+                    Operation::InRange => format!(
+                        "({1} < {0} && {0} < {2} ? 1.0 : 0.0)",
+                        args[0], args[1], args[2]
+                    ),
+                    Operation::Lt => format!("({} < {} ? 1.0 : 0.0)", args[0], args[1]),
+                    Operation::Gt => format!("({} > {} ? 1.0 : 0.0)", args[0], args[1]),
+                    Operation::If => format!("({} != 0.0 ? {} : {})", args[0], args[1], args[2]),
+                    Operation::UserFn(name) => call(name),
+                    op => call(op.function_name().unwrap()),
+                }
+            }
+
+            fn arg_repeats(&self, node: &Node) -> usize {
+                match node.op {
+                    Operation::Log if node.is_ln() => 1,
+                    Operation::Log | Operation::Softplus | Operation::Sign | Operation::InRange => {
+                        2
+                    }
+                    _ => 1,
+                }
+            }
+
+            fn declare(&self, name: &str, value: &str) -> String {
+                format!("const double {} = {};", name, value)
+            }
+        }
+
+        fn params(variables: &[String]) -> String {
+            variables
+                .iter()
+                .map(|v| format!("double {}", v))
+                .collect::<Vec<String>>()
+                .join(", ")
+        }
+
+        /// `double name(double x, double y, ...)` with the variables in sorted order.
+        /// The polygamma function and registered functions are called by name and have to be
+        /// provided by the surrounding code
+        pub fn function(f: &Node, name: &str) -> String {
+            let mut dag = Dag::new();
+            let root = dag.add(f, &C);
+            format!(
+                "double {}({}) {{\n{}    return {};\n}}\n",
+                name,
+                params(&f.variables()),
+                dag.temporaries(&C, "    "),
+                dag.render(root, &C).0
+            )
+        }
+
+        /// `void name_gradient(double x, double y, ..., double *gradient)`, writing the partial
+        /// derivatives in the order of the parameters. The partials share their temporaries
+        pub fn gradient(f: &Node, name: &str) -> String {
+            let variables = f.variables();
+            let gradient = f.gradient();
+            let partials = variables
+                .iter()
+                .map(|v| gradient[v].simplified())
+                .collect::<Vec<Node>>();
+            let mut dag = Dag::new();
+            let roots = partials
+                .iter()
+                .map(|partial| dag.add(partial, &C))
+                .collect::<Vec<usize>>();
+            let assignments = roots
+                .iter()
+                .enumerate()
+                .map(|(i, &root)| format!("    gradient[{}] = {};\n", i, dag.render(root, &C).0))
+                .collect::<String>();
+            let mut parameters = params(&variables);
+            if !parameters.is_empty() {
+                parameters += ", ";
+            }
+            format!(
+                "void {}_gradient({}double *gradient) {{\n{}{}}}\n",
+                name,
+                parameters,
+                dag.temporaries(&C, "    "),
+                assignments
+            )
+        }
+    }
+
+    /// Why an expression has no shader function
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum CodegenError {
+        /// The function with this name has no counterpart in the shading language
+        Unsupported(String),
+        /// The expression uses a variable that is not in the declared parameter list
+        MissingParameter(String),
+    }
+    impl fmt::Display for CodegenError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                CodegenError::Unsupported(name) => {
+                    write!(f, "{} is not supported in shaders", name)
+                }
+                CodegenError::MissingParameter(name) => {
+                    write!(f, "variable {} is not a declared parameter", name)
+                }
+            }
+        }
+    }
+
+    /// GLSL or WGSL, which differ in types, declarations and how they select between values
+    struct Shader {
+        wgsl: bool,
+    }
+
+    impl Shader {
+        fn select(&self, condition: &str, then: &str, otherwise: &str) -> String {
+            if self.wgsl {
+                format!("select({}, {}, {})", otherwise, then, condition)
+            } else {
+                format!("({} ? {} : {})", condition, then, otherwise)
+            }
+        }
+
+        /// The shader type holding `n` values
+        fn vector(&self, n: usize) -> String {
+            match (n, self.wgsl) {
+                (1, true) => "f32".to_string(),
+                (1, false) => "float".to_string(),
+                (n, true) => format!("vec{}<f32>", n),
+                (n, false) => format!("vec{}", n),
+            }
+        }
+
+        /// A function returning `outputs` from float parameters `params`
+        fn function(
+            &self,
+            name: &str,
+            params: &[&str],
+            outputs: &[Node],
+        ) -> Result<String, CodegenError> {
+            for output in outputs {
+                for variable in output.variables() {
+                    if !params.contains(&variable.as_str()) {
+                        return Err(CodegenError::MissingParameter(variable));
+                    }
+                }
+                unsupported(output)?;
+            }
+            let mut dag = Dag::new();
+            let roots = outputs
+                .iter()
+                .map(|output| dag.add(output, self))
+                .collect::<Vec<usize>>();
+            let values = roots
+                .iter()
+                .map(|&root| dag.render(root, self).0)
+                .collect::<Vec<String>>();
+            let value = match values.as_slice() {
+                [value] => value.clone(),
+                values => format!("{}({})", self.vector(values.len()), values.join(", ")),
+            };
+            let temporaries = dag.temporaries(self, "    ");
+            let body = format!("{}    return {};\n}}\n", temporaries, value);
+            Ok(if self.wgsl {
+                let params = params
+                    .iter()
+                    .map(|p| format!("{}: f32", p))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                format!(
+                    "fn {}({}) -> {} {{\n{}",
+                    name,
+                    params,
+                    self.vector(outputs.len()),
+                    body
+                )
+            } else {
+                let params = params
+                    .iter()
+                    .map(|p| format!("float {}", p))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                format!(
+                    "{} {}({}) {{\n{}",
+                    self.vector(outputs.len()),
+                    name,
+                    params,
+                    body
+                )
+            })
+        }
+
+        /// A function returning the partial derivatives with respect to all of `params`
+        fn gradient(&self, f: &Node, name: &str, params: &[&str]) -> Result<String, CodegenError> {
+            if params.is_empty() || params.len() > 4 {
+                return Err(CodegenError::Unsupported(format!(
+                    "a gradient of {} values",
+                    params.len()
+                )));
+            }
+            let gradient = f.gradient();
+            let partials = params
+                .iter()
+                .map(|p| match gradient.get(*p) {
+                    Some(partial) => partial.simplified(),
+                    None => super::zero(),
+                })
+                .collect::<Vec<Node>>();
+            self.function(&format!("{}_gradient", name), params, &partials)
+        }
+    }
+
+    /// The first function in `f` that shaders do not have
+    fn unsupported(f: &Node) -> Result<(), CodegenError> {
+        match &f.op {
+            Operation::Erf
+            | Operation::Gamma
+            | Operation::LnGamma
+            | Operation::Polygamma
+            | Operation::UserFn(_) => {
+                let name = match &f.op {
+                    Operation::UserFn(name) => name.as_str(),
+                    op => op.function_name().unwrap(),
+                };
+                Err(CodegenError::Unsupported(name.to_string()))
+            }
+            _ => f.args.iter().try_for_each(|arg| unsupported(arg)),
+        }
+    }
+
+    impl Syntax for Shader {
+        fn constant(&self, value: f64) -> String {
+            // Shading languages have no literals for infinity and NaN
+            let bits = match value {
+                value if value.is_nan() => "0x7fc00000u",
+                f64::INFINITY => "0x7f800000u",
+                f64::NEG_INFINITY => "0xff800000u",
+                value => return format!("{:?}", value),
+            };
+            if self.wgsl {
+                format!("bitcast<f32>({})", bits)
+            } else {
+                format!("uintBitsToFloat({})", bits)
+            }
+        }
+
+        fn function(&self, node: &Node, args: &[String]) -> String {
+            let call = |name: &str| format!("{}({})", name, args.join(", "));
+            let indicator = |condition: String| self.select(&condition, "1.0", "0.0");
+            match &node.op {
+                // pow is undefined for negative bases, so small integer powers are multiplied out
+                Operation::Pow => match node.args[1].op {
+                    Operation::Const(-1.0) => format!("(1.0 / {})", args[0]),
+                    Operation::Const(n)
+                        if float::fract(n) == 0.0 && (2.0..=4.0).contains(&float::abs(n)) =>
+                    {
+                        let product = vec![args[0].as_str(); float::abs(n) as usize].join(" * ");
+                        if n < 0.0 {
+                            format!("(1.0 / ({}))", product)
+                        } else {
+                            format!("({})", product)
+                        }
+                    }
+                    _ => call("pow"),
+                },
+                Operation::Log if node.is_ln() => format!("log({})", args[1]),
+                Operation::Log => format!("(log({}) / log({}))", args[1], args[0]),
+                Operation::Atan2 if self.wgsl => call("atan2"),
+                Operation::Atan2 => call("atan"),
+                Operation::Relu => format!("max({}, 0.0)", args[0]),
+                Operation::Sigmoid => format!("(1.0 / (1.0 + exp(-({}))))", args[0]),
+                Operation::Softplus => {
+                    format!("(max({0}, 0.0) + log(1.0 + exp(-abs({0}))))", args[0])
+                }
+                Operation::InRange => {
+                    indicator(format!("{1} < {0} && {0} < {2}", args[0], args[1], args[2]))
+                }
+                Operation::Lt => indicator(format!("{} < {}", args[0], args[1])),
+                Operation::Gt => indicator(format!("{} > {}", args[0], args[1])),
+                Operation::If => self.select(&format!("{} != 0.0", args[0]), &args[1], &args[2]),
+                op => call(op.function_name().unwrap()),
+            }
+        }
+
+        fn arg_repeats(&self, node: &Node) -> usize {
+            match (&node.op, node.args.get(1).map(|arg| &arg.op)) {
+                (Operation::Log, _) if node.is_ln() => 1,
+                (Operation::Pow, Some(Operation::Const(n)))
+                    if float::fract(*n) == 0.0 && (2.0..=4.0).contains(&float::abs(*n)) =>
+                {
+                    float::abs(*n) as usize
+                }
+                (Operation::Log | Operation::Softplus | Operation::InRange, _) => 2,
+                _ => 1,
+            }
+        }
+
+        fn declare(&self, name: &str, value: &str) -> String {
+            if self.wgsl {
+                format!("let {} = {};", name, value)
+            } else {
+                format!("float {} = {};", name, value)
+            }
+        }
+    }
+
+    /// GLSL functions on `float`s, for fragment shaders drawing scalar fields
+    pub mod glsl {
+        use super::{CodegenError, Shader};
+        use crate::Node;
+
+        /// `float name(float x, float y, ...)` with the parameters in the declared order
+        pub fn function(f: &Node, name: &str, params: &[&str]) -> Result<String, CodegenError> {
+            Shader { wgsl: false }.function(name, params, std::slice::from_ref(f))
+        }
+
+        /// `vecN name_gradient(...)` with the partial derivatives with respect to the 1 to 4
+        /// parameters, or a `float` for a single parameter
+        pub fn gradient(f: &Node, name: &str, params: &[&str]) -> Result<String, CodegenError> {
+            Shader { wgsl: false }.gradient(f, name, params)
+        }
+    }
+
+    /// WGSL functions on `f32`s, for WebGPU shaders drawing scalar fields
+    pub mod wgsl {
+        use super::{CodegenError, Shader};
+        use crate::Node;
+
+        /// `fn name(x: f32, y: f32, ...) -> f32` with the parameters in the declared order
+        pub fn function(f: &Node, name: &str, params: &[&str]) -> Result<String, CodegenError> {
+            Shader { wgsl: true }.function(name, params, std::slice::from_ref(f))
+        }
+
+        /// `fn name_gradient(...) -> vecN<f32>` with the partial derivatives with respect to the
+        /// 1 to 4 parameters, or an `f32` for a single parameter
+        pub fn gradient(f: &Node, name: &str, params: &[&str]) -> Result<String, CodegenError> {
+            Shader { wgsl: true }.gradient(f, name, params)
+        }
+    }
+}
+
+/////////////
+//  C API  //
+/////////////
+
+/// An `extern "C"` API on opaque expression handles, behind the `ffi` feature. The declarations
+/// are in `include/forward_auto_diff.h`
+#[cfg(feature = "ffi")]
+pub mod ffi {
+    use super::Node;
+    use std::collections::HashMap;
+    use std::ffi::{CStr, c_char, c_int};
+
+    /// An expression owned by the caller until it is passed to `expr_free`
+    pub struct Expr(Node);
+
+    /// The `n` name/value pairs at `names` and `values` as a point to evaluate at
+    unsafe fn point(
+        names: *const *const c_char,
+        values: *const f64,
+        n: usize,
+    ) -> Option<HashMap<String, f64>> {
+        (0..n)
+            .map(|i| {
+                // SAFETY: the caller passes `n` valid strings and values
+                let name = unsafe { CStr::from_ptr(*names.add(i)) }.to_str().ok()?;
+                Some((name.to_string(), unsafe { *values.add(i) }))
+            })
+            .collect()
+    }
+
+    /// Parse infix notation such as `3*x + sin(y)`, or return null if it is not valid.
+    ///
+    /// # Safety
+    /// `input` must be a NUL-terminated string
+    #[unsafe(no_mangle)]
+    pub unsafe extern "C" fn expr_parse(input: *const c_char) -> *mut Expr {
+        // SAFETY: guaranteed by the caller
+        let input = unsafe { CStr::from_ptr(input) };
+        match input
+            .to_str()
+            .ok()
+            .and_then(|input| super::parse(input).ok())
+        {
+            Some(node) => Box::into_raw(Box::new(Expr(node))),
+            None => std::ptr::null_mut(),
+        }
+    }
+
+    /// The fully simplified partial derivative wrt. `variable`, as a new expression.
+    ///
+    /// # Safety
+    /// `expr` must come from this API and not be freed, `variable` must be a NUL-terminated string
+    #[unsafe(no_mangle)]
+    pub unsafe extern "C" fn expr_diff(expr: *const Expr, variable: *const c_char) -> *mut Expr {
+        // SAFETY: guaranteed by the caller
+        let (expr, variable) = unsafe { (&*expr, CStr::from_ptr(variable)) };
+        match variable.to_str() {
+            Ok(variable) => Box::into_raw(Box::new(Expr(expr.0.clone().diff(variable)))),
+            Err(_) => std::ptr::null_mut(),
+        }
+    }
+
+    /// Evaluate with `names[i]` set to `values[i]` for `i < n` and store the value in `result`.
+    /// Returns 0 on success and -1 if a variable is missing or the result is not finite.
+    ///
+    /// # Safety
+    /// `expr` must come from this API and not be freed, `names` and `values` must point to `n`
+    /// NUL-terminated strings and values, and `result` to writable memory
+    #[unsafe(no_mangle)]
+    pub unsafe extern "C" fn expr_eval(
+        expr: *const Expr,
+        names: *const *const c_char,
+        values: *const f64,
+        n: usize,
+        result: *mut f64,
+    ) -> c_int {
+        // SAFETY: guaranteed by the caller
+        let (expr, at) = unsafe { (&*expr, point(names, values, n)) };
+        match at.map(|at| expr.0.try_evaluate(&at)) {
+            Some(Ok(value)) => {
+                // SAFETY: guaranteed by the caller
+                unsafe { *result = value };
+                0
+            }
+            _ => -1,
+        }
+    }
+
+    /// Evaluate the partial derivatives wrt. `names[0..n]` at the point given by `names` and
+    /// `values`, and store them in `gradient`. Returns 0 on success and -1 otherwise.
+    ///
+    /// # Safety
+    /// As for `expr_eval`, with `gradient` pointing to `n` writable values
+    #[unsafe(no_mangle)]
+    pub unsafe extern "C" fn expr_gradient(
+        expr: *const Expr,
+        names: *const *const c_char,
+        values: *const f64,
+        n: usize,
+        gradient: *mut f64,
+    ) -> c_int {
+        // SAFETY: guaranteed by the caller
+        let (expr, at) = unsafe { (&*expr, point(names, values, n)) };
+        let Some(at) = at else {
+            return -1;
+        };
+        let partials = expr.0.gradient();
+        for i in 0..n {
+            // SAFETY: guaranteed by the caller
+            let name = unsafe { CStr::from_ptr(*names.add(i)) }.to_string_lossy();
+            let value = match partials.get(name.as_ref()) {
+                Some(partial) => match partial.try_evaluate(&at) {
+                    Ok(value) => value,
+                    Err(_) => return -1,
+                },
+                None => 0.0,
+            };
+            // SAFETY: guaranteed by the caller
+            unsafe { *gradient.add(i) = value };
+        }
+        0
+    }
+
+    /// Free an expression from `expr_parse` or `expr_diff`; null is ignored.
+    ///
+    /// # Safety
+    /// `expr` must come from this API and not be freed already
+    #[unsafe(no_mangle)]
+    pub unsafe extern "C" fn expr_free(expr: *mut Expr) {
+        if !expr.is_null() {
+            // SAFETY: guaranteed by the caller
+            drop(unsafe { Box::from_raw(expr) });
+        }
+    }
+}
+
+///////////////////
+//  WebAssembly  //
+///////////////////
+
+/// JavaScript bindings with `wasm-bindgen`, behind the `wasm` feature
+#[cfg(feature = "wasm")]
+mod wasm {
+    use super::Node;
+    use std::collections::HashMap;
+    use wasm_bindgen::prelude::*;
+
+    /// A parsed expression
+    #[wasm_bindgen]
+    pub struct Expression(Node);
+
+    /// Parse infix notation such as `3*x + sin(y)`
+    #[wasm_bindgen]
+    pub fn parse(input: &str) -> Result<Expression, JsError> {
+        super::parse(input)
+            .map(Expression)
+            .map_err(|error| JsError::new(&error.to_string()))
+    }
+
+    #[wasm_bindgen]
+    impl Expression {
+        /// The fully simplified partial derivative wrt. `variable`
+        pub fn differentiate(&self, variable: &str) -> Expression {
+            Expression(self.0.clone().diff(variable))
+        }
+
+        /// Value with `names[i]` set to `values[i]`, or an error for a missing variable or a
+        /// subexpression outside its domain
+        pub fn evaluate(&self, names: Vec<String>, values: Vec<f64>) -> Result<f64, JsError> {
+            let at = names
+                .into_iter()
+                .zip(values)
+                .collect::<HashMap<String, f64>>();
+            self.0
+                .try_evaluate(&at)
+                .map_err(|error| JsError::new(&error.to_string()))
+        }
+
+        /// Names of the variables, sorted
+        pub fn variables(&self) -> Vec<String> {
+            self.0.variables()
+        }
+
+        /// Infix notation that `parse` accepts
+        #[wasm_bindgen(js_name = toString)]
+        pub fn to_infix(&self) -> String {
+            self.0.to_infix()
+        }
+
+        #[wasm_bindgen(js_name = toLatex)]
+        pub fn to_latex(&self) -> String {
+            self.0.to_latex()
+        }
+    }
+}
+
+///////////
+//  JIT  //
+///////////
+
+#[cfg(feature = "jit")]
+pub use jit::JitError;
+
+/// Native code generation with Cranelift, behind the `jit` feature
+// Operations are boxed so that the addresses baked into the code stay valid as more are added
+#[cfg(feature = "jit")]
+#[allow(clippy::vec_box)]
+mod jit {
+    use super::{Node, Operation, float};
+    use cranelift_codegen::ir::{AbiParam, InstBuilder, MemFlags, StackSlotData, StackSlotKind};
+    use cranelift_codegen::ir::{Value, types};
+    use cranelift_codegen::settings::{self, Configurable};
+    use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+    use cranelift_jit::{JITBuilder, JITModule};
+    use cranelift_module::{FuncId, Linkage, Module, default_libcall_names};
+    use std::fmt;
+
+    /// Why an expression could not be compiled to native code
+    #[derive(Debug)]
+    pub struct JitError(pub String);
+    impl fmt::Display for JitError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "JIT compilation failed: {}", self.0)
+        }
+    }
+
+    // Functions called from the generated code
+    extern "C" fn sin(x: f64) -> f64 {
+        float::sin(x)
+    }
+    extern "C" fn cos(x: f64) -> f64 {
+        float::cos(x)
+    }
+    extern "C" fn exp(x: f64) -> f64 {
+        float::exp(x)
+    }
+    extern "C" fn ln(x: f64) -> f64 {
+        float::ln(x)
+    }
+    extern "C" fn powi(x: f64, n: i32) -> f64 {
+        float::powi(x, n)
+    }
+    /// Any other operation, applied to `arity` arguments stored at `args`
+    extern "C" fn apply(op: *const Operation, args: *const f64, arity: usize) -> f64 {
+        // SAFETY: the generated code passes an operation owned by its `Compiled` and a stack
+        // slot holding `arity` arguments
+        unsafe { (*op).apply(std::slice::from_raw_parts(args, arity)) }
+    }
+
+    /// Generated code and the module and operations it refers to
+    struct Compiled {
+        _module: JITModule,
+        _operations: Vec<Box<Operation>>,
+        function: extern "C" fn(*const f64) -> f64,
+        inputs: usize,
+    }
+    impl Compiled {
+        fn call(&self, x: &[f64]) -> f64 {
+            assert!(
+                x.len() >= self.inputs,
+                "expected {} values, got {}",
+                self.inputs,
+                x.len()
+            );
+            (self.function)(x.as_ptr())
+        }
+    }
+
+    /// The imported helper functions, declared once per module
+    struct Helpers {
+        sin: FuncId,
+        cos: FuncId,
+        exp: FuncId,
+        ln: FuncId,
+        powi: FuncId,
+        apply: FuncId,
+    }
+
+    impl Node {
+        /// Compile to native code with Cranelift. The closure takes the values of `variables()`
+        /// in that order, like the one from `compile`, and panics if given fewer values
+        pub fn compile_jit(&self) -> Result<impl Fn(&[f64]) -> f64 + use<>, JitError> {
+            let error = |e: &dyn fmt::Display| JitError(e.to_string());
+            let mut flags = settings::builder();
+            flags.set("opt_level", "speed").map_err(|e| error(&e))?;
+            let isa = cranelift_native::builder()
+                .map_err(|e| error(&e))?
+                .finish(settings::Flags::new(flags))
+                .map_err(|e| error(&e))?;
+            let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+            builder.symbol("fad_sin", sin as *const u8);
+            builder.symbol("fad_cos", cos as *const u8);
+            builder.symbol("fad_exp", exp as *const u8);
+            builder.symbol("fad_ln", ln as *const u8);
+            builder.symbol("fad_powi", powi as *const u8);
+            builder.symbol("fad_apply", apply as *const u8);
+            let mut module = JITModule::new(builder);
+            let pointer = module.target_config().pointer_type();
+
+            let mut declare = |name: &str, params: &[types::Type]| {
+                let mut signature = module.make_signature();
+                for &param in params {
+                    signature.params.push(AbiParam::new(param));
+                }
+                signature.returns.push(AbiParam::new(types::F64));
+                module
+                    .declare_function(name, Linkage::Import, &signature)
+                    .map_err(|e| error(&e))
+            };
+            let helpers = Helpers {
+                sin: declare("fad_sin", &[types::F64])?,
+                cos: declare("fad_cos", &[types::F64])?,
+                exp: declare("fad_exp", &[types::F64])?,
+                ln: declare("fad_ln", &[types::F64])?,
+                powi: declare("fad_powi", &[types::F64, types::I32])?,
+                apply: declare("fad_apply", &[pointer, pointer, pointer])?,
+            };
+
+            let names = self.variables();
+            let mut operations = vec![];
+            let mut context = module.make_context();
+            context.func.signature.params.push(AbiParam::new(pointer));
+            context
+                .func
+                .signature
+                .returns
+                .push(AbiParam::new(types::F64));
+            let mut function_context = FunctionBuilderContext::new();
+            let mut b = FunctionBuilder::new(&mut context.func, &mut function_context);
+            let entry = b.create_block();
+            b.append_block_params_for_function_params(entry);
+            b.switch_to_block(entry);
+            b.seal_block(entry);
+            let x = b.block_params(entry)[0];
+
+            let mut call = |b: &mut FunctionBuilder, id: FuncId, args: &[Value]| {
+                let callee = module.declare_func_in_func(id, b.func);
+                let call = b.ins().call(callee, args);
+                b.inst_results(call)[0]
+            };
+            fn emit(
+                node: &Node,
+                b: &mut FunctionBuilder,
+                x: Value,
+                names: &[String],
+                helpers: &Helpers,
+                operations: &mut Vec<Box<Operation>>,
+                call: &mut dyn FnMut(&mut FunctionBuilder, FuncId, &[Value]) -> Value,
+            ) -> Value {
+                let mut arg = |i: usize, b: &mut FunctionBuilder| {
+                    emit(&node.args[i], b, x, names, helpers, operations, call)
+                };
+                match &node.op {
+                    Operation::Var(name) => {
+                        let index = names.iter().position(|v| v == name).unwrap();
+                        b.ins()
+                            .load(types::F64, MemFlags::trusted(), x, (8 * index) as i32)
+                    }
+                    Operation::Const(value) | Operation::Param(_, value) => {
+                        b.ins().f64const(*value)
+                    }
+                    Operation::Add => {
+                        let (l, r) = (arg(0, b), arg(1, b));
+                        b.ins().fadd(l, r)
+                    }
+                    Operation::Mul => {
+                        let (l, r) = (arg(0, b), arg(1, b));
+                        b.ins().fmul(l, r)
+                    }
+                    Operation::Sqrt => {
+                        let a = arg(0, b);
+                        b.ins().sqrt(a)
+                    }
+                    Operation::Abs => {
+                        let a = arg(0, b);
+                        b.ins().fabs(a)
+                    }
+                    Operation::Pow => match node.args[1].op {
+                        Operation::Const(n)
+                            if float::fract(n) == 0.0 && float::abs(n) <= i32::MAX as f64 =>
+                        {
+                            let base = arg(0, b);
+                            let n = b.ins().iconst(types::I32, n as i64);
+                            call(b, helpers.powi, &[base, n])
+                        }
+                        _ => generic(node, b, x, names, helpers, operations, call),
+                    },
+                    Operation::Log if node.is_ln() => {
+                        let a = arg(1, b);
+                        call(b, helpers.ln, &[a])
+                    }
+                    Operation::Sin => {
+                        let a = arg(0, b);
+                        call(b, helpers.sin, &[a])
+                    }
+                    Operation::Cos => {
+                        let a = arg(0, b);
+                        call(b, helpers.cos, &[a])
+                    }
+                    Operation::Exp => {
+                        let a = arg(0, b);
+                        call(b, helpers.exp, &[a])
+                    }
+                    _ => generic(node, b, x, names, helpers, operations, call),
+                }
+            }
+            // Store the arguments in a stack slot and let `Operation::apply` do the work
+            fn generic(
+                node: &Node,
+                b: &mut FunctionBuilder,
+                x: Value,
+                names: &[String],
+                helpers: &Helpers,
+                operations: &mut Vec<Box<Operation>>,
+                call: &mut dyn FnMut(&mut FunctionBuilder, FuncId, &[Value]) -> Value,
+            ) -> Value {
+                let args = node
+                    .args
+                    .iter()
+                    .map(|arg| emit(arg, b, x, names, helpers, operations, call))
+                    .collect::<Vec<Value>>();
+                let size = (8 * args.len().max(1)) as u32;
+                let slot = b.create_sized_stack_slot(StackSlotData::new(
+                    StackSlotKind::ExplicitSlot,
+                    size,
+                    3,
+                ));
+                for (i, &value) in args.iter().enumerate() {
+                    b.ins().stack_store(value, slot, (8 * i) as i32);
+                }
+                let pointer = b.func.dfg.value_type(x);
+                let address = b.ins().stack_addr(pointer, slot, 0);
+                let op = Box::new(node.op.clone());
+                let op_address = b.ins().iconst(pointer, &*op as *const Operation as i64);
+                operations.push(op);
+                let arity = b.ins().iconst(pointer, args.len() as i64);
+                call(b, helpers.apply, &[op_address, address, arity])
+            }
+            let result = emit(
+                self,
+                &mut b,
+                x,
+                &names,
+                &helpers,
+                &mut operations,
+                &mut call,
+            );
+            b.ins().return_(&[result]);
+            b.finalize();
+
+            let id = module
+                .declare_function("expression", Linkage::Export, &context.func.signature)
+                .map_err(|e| error(&e))?;
+            module
+                .define_function(id, &mut context)
+                .map_err(|e| error(&e))?;
+            module.clear_context(&mut context);
+            module.finalize_definitions().map_err(|e| error(&e))?;
+            let code = module.get_finalized_function(id);
+            let compiled = Compiled {
+                // SAFETY: the function was defined above with exactly this signature
+                function: unsafe {
+                    std::mem::transmute::<*const u8, extern "C" fn(*const f64) -> f64>(code)
+                },
+                _module: module,
+                _operations: operations,
+                inputs: names.len(),
+            };
+            // Calling a method moves all of `compiled` into the closure, not just the fields used
+            Ok(move |x: &[f64]| compiled.call(x))
+        }
+    }
+}
+
+//////////////////
+//  Float math  //
+//////////////////
+
+/// Floating-point functions used to evaluate and fold expressions. With the `libm` feature they
+/// come from `libm` instead of `std`, which gives the same results on every platform. The crate
+/// itself still needs `std`: there is no `no_std` build.
+mod float {
+    #[cfg(feature = "libm")]
+    pub use libm::{acos, asin, atan, atan2, cos, exp, fabs as abs, log as ln, pow as powf};
+    #[cfg(feature = "libm")]
+    pub use libm::{cosh, log1p as ln_1p, sin, sinh, sqrt, tan, tanh};
+
+    #[cfg(feature = "libm")]
+    pub fn powi(x: f64, n: i32) -> f64 {
+        libm::pow(x, n as f64)
+    }
+    #[cfg(feature = "libm")]
+    pub fn fract(x: f64) -> f64 {
+        x - libm::trunc(x)
+    }
+
+    #[cfg(not(feature = "libm"))]
+    pub fn sin(x: f64) -> f64 {
+        x.sin()
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn cos(x: f64) -> f64 {
+        x.cos()
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn tan(x: f64) -> f64 {
+        x.tan()
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn asin(x: f64) -> f64 {
+        x.asin()
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn acos(x: f64) -> f64 {
+        x.acos()
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn sinh(x: f64) -> f64 {
+        x.sinh()
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn cosh(x: f64) -> f64 {
+        x.cosh()
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn tanh(x: f64) -> f64 {
+        x.tanh()
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn exp(x: f64) -> f64 {
+        x.exp()
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn sqrt(x: f64) -> f64 {
+        x.sqrt()
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn atan(x: f64) -> f64 {
+        x.atan()
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn atan2(y: f64, x: f64) -> f64 {
+        y.atan2(x)
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn abs(x: f64) -> f64 {
+        x.abs()
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn ln(x: f64) -> f64 {
+        x.ln()
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn ln_1p(x: f64) -> f64 {
+        x.ln_1p()
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn powf(x: f64, y: f64) -> f64 {
+        x.powf(y)
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn powi(x: f64, n: i32) -> f64 {
+        x.powi(n)
+    }
+    #[cfg(not(feature = "libm"))]
+    pub fn fract(x: f64) -> f64 {
+        x.fract()
+    }
+
+    /// Logarithm of `x` in the given base
+    pub fn log(x: f64, base: f64) -> f64 {
+        ln(x) / ln(base)
+    }
+}
+
+////////////////////////////
+//  Operator overloading  //
+////////////////////////////
+
+macro_rules! impl_op {
+    ($trait:ident, $method:ident, $op:expr) => {
+        impl $trait for Node {
+            type Output = Node;
+
+            fn $method(self, other: Self) -> Self {
+                Node::new($op, vec![Rc::new(self), Rc::new(other)])
+            }
+        }
+
+        impl $trait<f64> for Node {
+            type Output = Node;
+
+            fn $method(self, other: f64) -> Self {
+                Node::new($op, vec![Rc::new(self), Rc::new(c(other))])
+            }
+        }
+
+        impl $trait<Node> for f64 {
+            type Output = Node;
+
+            fn $method(self, other: Node) -> Node {
+                Node::new($op, vec![Rc::new(c(self)), Rc::new(other)])
+            }
+        }
+    };
+}
+
+impl_op!(Add, add, Operation::Add);
+impl_op!(Mul, mul, Operation::Mul);
+
+/// Operators without an `Operation` of their own, written in terms of `Add`, `Mul` and `Pow`
+/// so that derivatives and simplification need no extra rules
+macro_rules! impl_lowered_op {
+    ($trait:ident, $method:ident, |$a:ident, $b:ident| $lowered:expr) => {
+        impl $trait for Node {
+            type Output = Node;
+
+            fn $method(self, other: Self) -> Self {
+                let ($a, $b) = (self, other);
+                $lowered
+            }
+        }
+
+        impl $trait<f64> for Node {
+            type Output = Node;
+
+            fn $method(self, other: f64) -> Self {
+                let ($a, $b) = (self, c(other));
+                $lowered
+            }
+        }
+
+        impl $trait<Node> for f64 {
+            type Output = Node;
+
+            fn $method(self, other: Node) -> Node {
+                let ($a, $b) = (c(self), other);
+                $lowered
+            }
+        }
+    };
+}
+
+// a - b = a + -1 * b
+impl_lowered_op!(Sub, sub, |a, b| a + -1.0 * b);
+// a / b = a * b^-1
+impl_lowered_op!(Div, div, |a, b| a * pow(b, c(-1.0)));
+
+impl Neg for Node {
+    type Output = Node;
+
+    /// -a = -1 * a
+    fn neg(self) -> Node {
+        -1.0 * self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(point: &[(&str, f64)]) -> HashMap<String, f64> {
+        point
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value))
+            .collect()
+    }
+
+    /// Central difference of `f` along `variable` at `point`
+    fn central(f: &Node, variable: &str, point: &HashMap<String, f64>) -> f64 {
+        let h = 1e-6;
+        let step = |delta: f64| {
+            let mut shifted = point.clone();
+            *shifted.get_mut(variable).unwrap() += delta;
+            f.evaluate(&shifted)
+        };
+        (step(h) - step(-h)) / (2.0 * h)
+    }
+
+    #[test]
+    fn polynomial_derivatives() {
+        let mut f = 5.0 * var("x") + 3.0 * var("y") + 4.0 * var("x") * var("y") * var("z");
+        let point = at(&[("x", 1.0), ("y", 2.0), ("z", 3.0)]);
+        assert_eq!(f.evaluate(&point), 35.0);
+        assert_eq!(f.diff("x").evaluate(&point), 29.0);
+        assert_eq!(f.diff("y").evaluate(&point), 15.0);
+        assert_eq!(f.diff("z").evaluate(&point), 8.0);
+    }
+
+    /// `y = sin(y) + cos(y)` applied `n` times to `x`: 3n + 1 nodes, each used by both `sin`
+    /// and `cos` of the next level, so the tree doubles in size with every level
+    fn doubling_graph(n: usize) -> Node {
+        (0..n).fold(var("x"), |y, _| {
+            let y = Rc::new(y);
+            let apply = |op| Rc::new(Node::new(op, vec![Rc::clone(&y)]));
+            Node::new(
+                Operation::Add,
+                vec![apply(Operation::Sin), apply(Operation::Cos)],
+            )
+        })
+    }
+
+    #[test]
+    fn shared_subtrees_evaluate_once() {
+        let f = doubling_graph(22);
+        let point = at(&[("x", 0.4)]);
+        let y = (0..22).fold(0.4f64, |y, _| y.sin() + y.cos());
+        assert!((f.evaluate(&point) - y).abs() < 1e-12);
+        assert!((f.evaluate_compensated(&point) - y).abs() < 1e-12);
+        assert!((f.try_evaluate(&point).unwrap() - y).abs() < 1e-12);
+    }
+
+    #[test]
+    fn interning_hashes_shared_subtrees_once() {
+        let mut interner = Interner::new();
+        let id = interner.intern(&doubling_graph(22));
+        assert_eq!(interner.len(), 67);
+        let rebuilt = interner.get(id);
+        assert_eq!(interner.intern(&rebuilt), id);
+        assert_eq!(interner.len(), 67);
+    }
+
+    #[test]
+    fn diff_simplifies_fully() {
+        let mut f = 3.0 * var("x") + 5.0;
+        assert_eq!(f.diff("x"), c(3.0));
+        assert_eq!(f.diff("y"), c(0.0));
+    }
+
+    #[test]
+    fn clamp_passes_the_derivative_through_only_inside() {
+        let mut f = clamp(2.0 * var("x"), c(-1.0), c(1.0));
+        let df_dx = f.diff("x");
+        for (x, value, slope) in [(-1.0, -1.0, 0.0), (0.25, 0.5, 2.0), (1.0, 1.0, 0.0)] {
+            assert_eq!(f.evaluate(&at(&[("x", x)])), value);
+            assert_eq!(df_dx.evaluate(&at(&[("x", x)])), slope);
+        }
+    }
+
+    #[test]
+    fn clamp_to_an_empty_range_is_nan() {
+        assert!(
+            clamp(c(0.5), c(2.0), c(1.0))
+                .evaluate(&HashMap::new())
+                .is_nan()
+        );
+        let f = clamp(var("x"), c(2.0), var("hi"));
+        for hi in [1.0, f64::NAN] {
+            assert!(matches!(
+                f.try_evaluate(&at(&[("x", 0.5), ("hi", hi)])),
+                Err(EvalError::Domain { .. })
+            ));
+        }
+        assert_eq!(f.try_evaluate(&at(&[("x", 0.5), ("hi", 3.0)])), Ok(2.0));
+    }
+
+    #[test]
+    fn polygamma_of_negative_and_non_finite_arguments() {
+        let evaluate = |f: Node, x: f64| f.evaluate(&at(&[("x", x)]));
+        // ψ(-1/2) = ψ(3/2), and ψ'(-1/2) = ψ'(1/2) + 4 = π²/2 + 4
+        let digamma_at = |x| evaluate(digamma(var("x")), x);
+        assert!((digamma_at(-0.5) - 0.03648997397857652).abs() < 1e-12);
+        let trigamma = evaluate(polygamma(one(), var("x")), -0.5);
+        assert!((trigamma - (std::f64::consts::PI.powi(2) / 2.0 + 4.0)).abs() < 1e-10);
+        // One reflection instead of a million unit steps
+        assert!((digamma_at(-1e6 - 0.5) - digamma_at(1e6 + 1.5)).abs() < 1e-12);
+        for x in [f64::NEG_INFINITY, f64::INFINITY, f64::NAN, -1e300] {
+            assert!(digamma_at(x).is_nan());
+            assert!(evaluate(ln_gamma(var("x")).diff("x"), x).is_nan());
+        }
+        assert!(evaluate(polygamma(c(1e9), var("x")), 2.0).is_nan());
+    }
+
+    #[test]
+    fn non_ascii_after_a_number() {
+        for input in ["2×x", "2€", "1e5·y", "3e-2−1"] {
+            let error = parse(input).unwrap_err();
+            assert!(
+                error.message.starts_with("unexpected character"),
+                "{}",
+                input
+            );
+        }
+        // Letters are identifiers, whatever the script
+        assert_eq!(parse("2π").unwrap().variables(), ["π"]);
+        assert_eq!(parse("2é").unwrap().variables(), ["é"]);
+    }
+
+    #[test]
+    fn latex_and_mathml_parenthesize_alike() {
+        let f = pow(var("x") + 1.0, c(3.0)) * (var("y") + 2.0);
+        let latex = f.to_latex();
+        let mathml = f.to_mathml();
+        assert_eq!(latex.matches("\\left(").count(), 2, "{}", latex);
+        assert_eq!(mathml.matches("<mo>(</mo>").count(), 2, "{}", mathml);
+    }
+
+    #[test]
+    fn stats_count_operations_by_name() {
+        let f = sin(var("x")) * var("x") + 2.0 * cos(var("x"));
+        let stats = f.stats();
+        let count = |name: &str| stats.operations.get(name).copied().unwrap_or(0);
+        assert_eq!(stats.nodes, 9);
+        assert_eq!((count("add"), count("mul"), count("var")), (1, 2, 3));
+        assert_eq!((count("sin"), count("cos"), count("const")), (1, 1, 1));
+    }
+
+    #[test]
+    fn atan_derivatives_match_central_differences() {
+        let mut f = atan(var("x"));
+        let df_dx = f.diff("x");
+        for x in [-1e3, -1.0, 0.0, 0.5, 1e3] {
+            let point = at(&[("x", x)]);
+            assert!((df_dx.evaluate(&point) - central(&f, "x", &point)).abs() < 1e-8);
+        }
+        let mut f = atan2(var("y"), var("x"));
+        let point = at(&[("x", 1.0), ("y", 2.0)]);
+        assert!((f.diff("x").evaluate(&point) + 0.4).abs() < 1e-15);
+        assert!((f.diff("y").evaluate(&point) - 0.2).abs() < 1e-15);
+    }
+
+    #[test]
+    fn nested_powers_collapse() {
+        let f = pow(pow(var("x"), c(2.0)), c(3.0));
+        assert_eq!(f, pow(var("x"), c(6.0)));
+        assert_eq!(f.evaluate(&at(&[("x", -1.5)])), (-1.5f64).powi(2).powi(3));
+    }
+
+    #[test]
+    fn gradient_norm_of_a_paraboloid() {
+        let mut f = pow(var("x"), c(2.0)) + pow(var("y"), c(2.0));
+        let point = at(&[("x", 3.0), ("y", 4.0)]);
+        assert_eq!(f.gradient_norm(&point), 10.0);
+        let unit = f.normalized_gradient(&point);
+        assert_eq!((unit["x"], unit["y"]), (0.6, 0.8));
+    }
+
+    #[test]
+    fn tolerance_decides_what_counts_as_one() {
+        let default = simplify_config();
+        assert_eq!(1.0000001 * var("x"), var("x"));
+        set_simplify_config(SimplifyConfig {
+            tolerance: 1e-9,
+            ..default
+        });
+        let f = 1.0000001 * var("x");
+        set_simplify_config(default);
+        assert_eq!(format!("{:?}", f), "Mul(1.0000001, x)");
+    }
+
+    #[test]
+    fn powi_differentiates_exactly() {
+        let mut f = powi(var("x"), 3);
+        assert_eq!(f.diff("x"), 3.0 * powi(var("x"), 2));
+        assert_eq!(f.evaluate(&at(&[("x", -2.0)])), -8.0);
+    }
+
+    #[test]
+    fn flops_of_shared_subtrees_count_once() {
+        // One Mul, one Add and one Sin
+        assert_eq!((3.0 * var("x") + sin(var("y"))).flop_count(), 1 + 1 + 15);
+        // A Sin, a Cos and an Add per level
+        assert_eq!(doubling_graph(22).flop_count(), 22 * (15 + 15 + 1));
+    }
+
+    #[test]
+    fn missing_variables() {
+        let f = 3.0 * var("x") + 4.0 * var("y");
+        let point = at(&[("x", 2.0)]);
+        assert_eq!(f.evaluate_with_default(&point, 0.0), 6.0);
+        assert_eq!(f.partial_evaluate(&point), 4.0 * var("y") + 6.0);
+        assert!(matches!(
+            f.try_evaluate(&point),
+            Err(EvalError::MissingVariable(name)) if name == "y"
+        ));
+    }
+
+    #[test]
+    fn constants_fold_across_chains() {
+        assert_eq!((2.0 + var("x")) + 3.0, var("x") + 5.0);
+        assert_eq!(2.0 * (var("x") * 3.0), 6.0 * var("x"));
+    }
+
+    #[test]
+    fn non_finite_constants_are_kept() {
+        let point = at(&[("x", 1.3)]);
+        for constant in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let expected = [constant + 1.3, constant * 1.3];
+            for (f, expected) in [
+                (c(constant) + var("x"), expected[0]),
+                (var("x") + c(constant), expected[0]),
+                (c(constant) * var("x"), expected[1]),
+                (var("x") * c(constant), expected[1]),
+            ] {
+                let value = f.evaluate(&point);
+                assert!(value.total_cmp(&expected).is_eq() || value.is_nan() && expected.is_nan());
+            }
+        }
+        assert!(
+            (c(f64::NAN) * var("x") + var("x"))
+                .evaluate(&point)
+                .is_nan()
+        );
+        assert!(
+            (c(f64::INFINITY) + c(f64::NEG_INFINITY) + var("x"))
+                .evaluate(&point)
+                .is_nan()
+        );
+    }
+
+    #[test]
+    fn compensated_sums_reduce_rounding_error() {
+        // 1e16 + 1 + ... + 1 - 1e16 with ten ones
+        let values = std::iter::once(1e16)
+            .chain([1.0; 10])
+            .chain(std::iter::once(-1e16))
+            .collect::<Vec<f64>>();
+        let names = (0..values.len())
+            .map(|i| format!("x_{}", i))
+            .collect::<Vec<String>>();
+        let f = names.iter().fold(c(0.0), |sum, name| sum + var(name));
+        let point = names.iter().cloned().zip(values).collect();
+        let naive = f.evaluate(&point);
+        assert_eq!(f.evaluate_compensated(&point), 10.0);
+        assert!(
+            (naive - 10.0).abs() >= 1.0,
+            "naive summation gave {}",
+            naive
+        );
+    }
+
+    #[test]
+    fn polynomial_coefficients() {
+        let f = (var("x") + 1.0) * (var("x") + 2.0);
+        assert_eq!(f.poly_coeffs("x").unwrap(), vec![2.0, 3.0, 1.0]);
+        assert!(sin(var("x")).poly_coeffs("x").is_err());
+    }
+
+    #[test]
+    fn abs_derivative_away_from_and_at_the_kink() {
+        let mut f = abs(pow(var("x"), c(2.0)) - 1.0);
+        let df_dx = f.diff("x");
+        for x in [-2.0, 0.5, 3.0] {
+            let point = at(&[("x", x)]);
+            assert!((df_dx.evaluate(&point) - central(&f, "x", &point)).abs() < 1e-6);
+        }
+        assert_eq!(df_dx.evaluate(&at(&[("x", 1.0)])), 0.0);
+    }
+
+    #[test]
+    fn postfix_tokens() {
+        let tokens = [
+            Token::Num(3.0),
+            Token::Var("x".to_string()),
+            Token::Mul,
+            Token::Num(5.0),
+            Token::Add,
+        ];
+        assert_eq!(from_rpn(&tokens).unwrap(), 3.0 * var("x") + 5.0);
+        assert!(from_rpn(&tokens[1..]).is_err());
+    }
+
+    #[test]
+    fn logarithms_of_their_base_and_of_one() {
+        assert_eq!(log(c(3.0), c(3.0)), c(1.0));
+        assert_eq!(ln(e()), c(1.0));
+        let (x, y) = (var("x"), var("y"));
+        assert_eq!(
+            log(x.clone() + y.clone(), x.clone() + y) + log(x, c(1.0)),
+            c(1.0)
+        );
+    }
+
+    #[test]
+    fn vector_jacobian_matches_central_differences() {
+        let (x, y) = (var("x"), var("y"));
+        let mut f = VectorExpr::new(vec![sin(x.clone()) + cos(y.clone()), cos(x) - sin(y)]);
+        let names = f.variables();
+        assert_eq!(names, ["x", "y"]);
+        let jacobian = f.jacobian();
+        let point = at(&[("x", 0.7), ("y", -1.3)]);
+        for (i, row) in jacobian.iter().enumerate() {
+            for (j, entry) in row.iter().enumerate() {
+                let component = &f.components[i];
+                let numeric = central(component, &names[j], &point);
+                assert!((entry.evaluate(&point) - numeric).abs() < 1e-9);
+            }
+        }
+        assert_eq!(f.evaluate(&point)[1], 0.7f64.cos() - (-1.3f64).sin());
+    }
+
+    #[test]
+    fn simultaneous_substitution() {
+        let f = 3.0 * var("x") + var("y");
+        let both = HashMap::from([("x".to_string(), var("y")), ("y".to_string(), c(2.0))]);
+        assert_eq!(f.substitute_all(&both), 3.0 * var("y") + 2.0);
+        assert_eq!(
+            f.substitute("x", &var("y")).substitute("y", &c(2.0)),
+            c(8.0)
+        );
+    }
+
+    #[test]
+    fn string_round_trips_of_random_expressions() {
+        let mut seed: u64 = 1;
+        let mut random = move |n: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+        fn random_node(depth: usize, random: &mut dyn FnMut(u64) -> u64) -> Node {
+            let choice = if depth == 0 { random(3) } else { random(9) };
+            let mut next = || Rc::new(random_node(depth - 1, random));
+            match choice {
+                0 => var("x"),
+                1 => var("y"),
+                2 => c(random(9) as f64 - 4.0),
+                3 | 4 => Node::new(Operation::Add, vec![next(), next()]),
+                5 | 6 => Node::new(Operation::Mul, vec![next(), next()]),
+                7 => Node::new(Operation::Sin, vec![next()]),
+                _ => Node::new(Operation::Atan, vec![next()]),
+            }
+        }
+        let point = at(&[("x", 0.7), ("y", -1.3)]);
+        for _ in 0..200 {
+            let f = random_node(4, &mut random);
+            let value = f.evaluate(&point);
+            for g in [
+                parse(&f.to_infix()).unwrap(),
+                parse_postfix(&f.to_postfix()).unwrap(),
+                Node::from_bytes(&f.to_bytes()).unwrap(),
+            ] {
+                assert!(g.same_as(&f), "{} came back as {}", f, g);
+                assert_eq!(g.evaluate(&point), value);
+            }
+        }
+    }
+
+    #[test]
+    fn quotients_cancel() {
+        let x = var("x");
+        assert_eq!(x.clone() * pow(x.clone(), c(-1.0)), c(1.0));
+        assert_eq!(x.clone() / x, c(1.0));
+    }
+
+    #[test]
+    fn common_factors() {
+        let f = 2.0 * var("x") + 2.0 * var("y");
+        assert_eq!(f.factor_common(), 2.0 * (var("x") + var("y")));
+    }
+
+    #[test]
+    fn critical_points_of_sine() {
+        let mut f = sin(var("x"));
+        let bounds = HashMap::from([("x".to_string(), (0.0, 2.0 * std::f64::consts::PI))]);
+        let mut found = critical_points(&mut f, &bounds, 20)
+            .iter()
+            .map(|point| point["x"])
+            .collect::<Vec<f64>>();
+        found.sort_by(f64::total_cmp);
+        assert_eq!(found.len(), 2);
+        assert!((found[0] - std::f64::consts::FRAC_PI_2).abs() < 1e-9);
+        assert!((found[1] - 3.0 * std::f64::consts::FRAC_PI_2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn named_constants_print_symbolically() {
+        assert_eq!(pi().to_string(), "pi");
+        let f = pi() * powi(var("r"), 2);
+        assert_eq!(parse(&f.to_infix()).unwrap(), f);
+    }
+
+    #[test]
+    fn streamed_rows_match_the_batch() {
+        let f = powi(var("x"), 2) * sin(var("y")) + 3.0;
+        let xs = (0..5).map(|i| i as f64).collect::<Vec<f64>>();
+        let ys = xs.iter().map(|x| 0.5 * x).collect::<Vec<f64>>();
+        let columns = HashMap::from([("x".to_string(), &xs[..]), ("y".to_string(), &ys[..])]);
+        let rows = xs.iter().zip(&ys).map(|(&x, &y)| vec![x, y]);
+        let order = ["x".to_string(), "y".to_string()];
+        let streamed = f.evaluate_stream(&order, rows).collect::<Vec<f64>>();
+        assert_eq!(streamed, f.evaluate_batch(&columns));
+    }
+
+    #[test]
+    fn derivative_with_respect_to_a_parameter() {
+        let mut f = param("k", 2.5) * var("x");
+        assert_eq!(f.partial_derivative_param("k"), var("x"));
+        assert_eq!(f.evaluate(&at(&[("x", 4.0)])), 10.0);
+    }
+
+    #[test]
+    fn inverse_functions_cancel() {
+        let x = var("x");
+        assert_eq!(exp(ln(x.clone())), x);
+        assert_eq!(ln(exp(x.clone())), x);
+        assert_eq!(log(c(2.0), pow(c(2.0), x.clone())), x);
+    }
+
+    #[test]
+    fn tree_diff_points_at_the_constant() {
+        let f = var("x") + 1.0;
+        assert_eq!(
+            f.tree_diff(&(var("x") + 2.0)).as_deref(),
+            Some("at root.1: 1 vs 2")
+        );
+        assert_eq!(f.tree_diff(&(var("x") + 1.0)), None);
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn ndarray_matches_scalar_evaluation() {
+        let f = 2.0 * sin(var("x")) + 3.0 * cos(var("y"));
+        let xs = Array1::linspace(-5.0, 5.0, 1000);
+        let ys = xs.mapv(|x: f64| x * x / 5.0);
+        let columns = HashMap::from([("x".to_string(), xs.view()), ("y".to_string(), ys.view())]);
+        let vectorized = f.evaluate_ndarray(&columns);
+        for ((&x, &y), &value) in xs.iter().zip(&ys).zip(&vectorized) {
+            assert!((f.evaluate(&at(&[("x", x), ("y", y)])) - value).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn deep_chains_evaluate_without_recursion() {
+        let f = (1..=100_000).fold(c(0.0), |sum, i| Node {
+            op: Operation::Add,
+            args: vec![Rc::new(sum), Rc::new(var(&format!("x_{}", i)))],
+        });
+        assert_eq!(f.evaluate_with_default(&HashMap::new(), 0.5), 50_000.0);
+    }
+
+    #[test]
+    fn iterated_sine_matches_central_differences() {
+        let mut f = iterate(sin, var("x"), 5);
+        assert_eq!(f.to_infix(), "sin(sin(sin(sin(sin(x)))))");
+        let df_dx = f.diff("x");
+        for x in [0.0, 0.7, 2.0] {
+            let point = at(&[("x", x)]);
+            assert!((df_dx.evaluate(&point) - central(&f, "x", &point)).abs() < 1e-8);
+        }
+    }
+
+    #[test]
+    fn negative_terms_print_as_subtractions() {
+        let mut f = 5.0 * var("x") + -3.0 * var("y");
+        assert_eq!(f.to_infix(), "5 * x - 3 * y");
+        assert_eq!(f.diff("y").to_infix(), "-3");
+    }
+
+    #[test]
+    fn user_functions_differentiate() {
+        register_function(
+            "smoothstep",
+            1,
+            |args| 3.0 * args[0].powi(2) - 2.0 * args[0].powi(3),
+            |args, derivatives| {
+                let t = args[0].clone();
+                6.0 * t.clone() * (1.0 - t) * derivatives[0].clone()
+            },
+        );
+        let mut f = user_fn("smoothstep", vec![0.25 * var("x")]) * sin(var("x"));
+        let df_dx = f.diff("x");
+        for x in [0.5, 1.0, 3.0] {
+            let point = at(&[("x", x)]);
+            assert!((df_dx.evaluate(&point) - central(&f, "x", &point)).abs() < 1e-8);
+        }
+        assert_eq!(
+            parse("smoothstep(x)").unwrap(),
+            user_fn("smoothstep", vec![var("x")])
+        );
+    }
+
+    #[test]
+    fn composition_follows_the_chain_rule() {
+        let f = var("u") * var("v") + sin(var("u"));
+        let inner = HashMap::from([
+            ("u".to_string(), powi(var("x"), 2)),
+            ("v".to_string(), exp(var("x"))),
+        ]);
+        let chain = inner
+            .iter()
+            .map(|(name, g)| f.clone().diff(name).compose(&inner) * g.clone().diff("x"))
+            .fold(zero(), |sum, term| sum + term);
+        let point = at(&[("x", 0.7)]);
+        let composed = f.compose(&inner).diff("x").evaluate(&point);
+        assert!((composed - chain.evaluate(&point)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn compiled_evaluators_agree_with_the_tree() {
+        let f = ln(powi(var("x"), 2) + 1.0) * sin(var("y")) + exp(-var("y")) * powi(var("x"), 3);
+        let program = f.compile_bytecode(&["x", "y"]);
+        let bound = f.bind(&["x", "y"]);
+        let compiled = f.compile();
+        for point in [[0.0, 0.0], [1.3, 0.4], [-2.0, 5.5]] {
+            let expected = f.evaluate(&at(&[("x", point[0]), ("y", point[1])]));
+            assert_eq!(program.run(&point), expected);
+            assert_eq!(bound.evaluate(&point), expected);
+            assert_eq!(compiled(&point), expected);
+        }
+    }
+
+    #[test]
+    fn equal_derivatives_hash_alike() {
+        let mut f = powi(var("x"), 2) * powi(var("y"), 2) + sin(var("x"));
+        let (mut dx, mut dy) = (f.diff("x"), f.diff("y"));
+        let second = [dx.diff("x"), dx.diff("y"), dy.diff("x"), dy.diff("y")];
+        assert_eq!(second[1], second[2]);
+        assert_eq!(second.iter().collect::<HashSet<_>>().len(), 3);
+    }
+
+    #[test]
+    fn gradient_matches_partial_derivatives() {
+        let (x, y) = (var("x"), var("y"));
+        let mut f = powi(x.clone(), 2) * y.clone() * sin(x.clone() * y.clone())
+            / (1.0 + powi(x, 2) + powi(y, 2));
+        let gradient = f.gradient();
+        assert_eq!(gradient.len(), 2);
+        for name in ["x", "y"] {
+            assert_eq!(gradient[name], f.diff(name));
+        }
+        // Shared subtrees are differentiated once; simplifying would walk the whole tree
+        let f = doubling_graph(22);
+        let point = at(&[("x", 0.4)]);
+        let config = simplify_config();
+        set_simplify_config(SimplifyConfig {
+            disabled: true,
+            ..config
+        });
+        let gradient = f.gradient();
+        set_simplify_config(config);
+        let derivative = gradient["x"].evaluate(&point);
+        let tape = Tape::record(&f, &point);
+        assert!((derivative - tape.grad()["x"]).abs() < 1e-12 * derivative.abs());
+    }
+
+    #[test]
+    fn tape_gradient_matches_symbolic() {
+        let (x, y, z) = (var("x"), var("y"), var("z"));
+        let mut f = (1..=5).fold(c(0.0), |f, k| {
+            let k = k as f64;
+            f + sin(k * x.clone() * y.clone())
+                + log(c(2.0), k + powi(z.clone(), 2))
+                + atan2(y.clone(), k + x.clone())
+        });
+        let point = at(&[("x", 0.3), ("y", -1.2), ("z", 2.0)]);
+        let tape = Tape::record(&f, &point);
+        assert!((tape.value() - f.evaluate(&point)).abs() < 1e-12);
+        for (name, derivative) in tape.grad() {
+            assert!((derivative - f.diff(&name).evaluate(&point)).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn tape_records_shared_and_deep_graphs_once() {
+        let f = doubling_graph(22);
+        let tape = Tape::record(&f, &at(&[("x", 0.4)]));
+        assert_eq!(tape.entries.len(), 67);
+        // d/dx (sin(y) + cos(y)) = (cos(y) - sin(y)) dy/dx, level by level
+        let (mut y, mut dy_dx) = (0.4f64, 1.0);
+        for _ in 0..22 {
+            dy_dx *= y.cos() - y.sin();
+            y = y.sin() + y.cos();
+        }
+        assert!((tape.value() - y).abs() < 1e-12);
+        assert!((tape.grad()["x"] - dy_dx).abs() < 1e-12 * dy_dx.abs());
+        let f = (0..100_000).fold(var("x"), |node, _| Node {
+            op: Operation::Sin,
+            args: vec![Rc::new(node)],
+        });
+        let tape = Tape::record(&f, &at(&[("x", 1.0)]));
+        assert_eq!(tape.value(), f.evaluate(&at(&[("x", 1.0)])));
+        assert!(tape.grad()["x"] > 0.0);
+    }
+
+    #[test]
+    fn tape_is_reused_across_gradients() {
+        let evaluations = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&evaluations);
+        register_function(
+            "counted",
+            1,
+            move |args| {
+                counter.set(counter.get() + 1);
+                args[0].powi(2)
+            },
+            |args, derivatives| 2.0 * args[0].clone() * derivatives[0].clone(),
+        );
+        let mut f = sin(user_fn("counted", vec![var("x")])) * var("y");
+        let point = at(&[("x", 0.8), ("y", -2.0)]);
+        let expected = [f.diff("x").evaluate(&point), f.diff("y").evaluate(&point)];
+        evaluations.set(0);
+        let tape = Tape::record(&f, &point);
+        for _ in 0..10 {
+            let gradient = tape.grad();
+            assert!((gradient["x"] - expected[0]).abs() < 1e-12);
+            assert!((gradient["y"] - expected[1]).abs() < 1e-12);
+        }
+        // Only recording evaluated the function; the sweeps use the recorded values
+        assert_eq!(evaluations.get(), 1);
+    }
+
+    #[test]
+    fn hessian_vector_product_matches_the_hessian() {
+        let names = (0..6).map(|i| format!("x_{}", i)).collect::<Vec<String>>();
+        let mut f = (0..5).fold(sin(var(&names[0]) * var(&names[1])), |sum, i| {
+            sum + powi(var(&names[i]), 2) * var(&names[i + 1])
+        });
+        let x = (0..6).map(|i| 0.3 * i as f64 - 0.5).collect::<Vec<f64>>();
+        let v = (0..6).map(|i| 1.0 / (i + 1) as f64).collect::<Vec<f64>>();
+        let point = names.iter().cloned().zip(x.iter().copied()).collect();
+        let product = hvp(&f, &names, &x, &v);
+        for (row, expected) in names.iter().zip(product) {
+            let mut d_row = f.diff(row);
+            let full = names
+                .iter()
+                .zip(&v)
+                .map(|(column, v)| d_row.diff(column).evaluate(&point) * v)
+                .sum::<f64>();
+            assert!((full - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn jacobian_products_match_the_jacobian() {
+        let (x, y) = (var("x"), var("y"));
+        let mut f = [
+            x.clone() * y.clone(),
+            sin(x.clone()) + powi(y.clone(), 2),
+            exp(x - y),
+        ];
+        let names = ["x".to_string(), "y".to_string()];
+        let point = [0.5, -1.5];
+        let values = names.iter().cloned().zip(point).collect();
+        let jacobian = f
+            .iter_mut()
+            .map(|f| {
+                names
+                    .iter()
+                    .map(|name| f.diff(name).evaluate(&values))
+                    .collect()
+            })
+            .collect::<Vec<Vec<f64>>>();
+        let (t, u) = ([2.0, -1.0], [1.0, 0.5, -3.0]);
+        for (row, product) in jacobian.iter().zip(jvp(&f, &names, &point, &t)) {
+            let expected = row.iter().zip(t).map(|(j, t)| j * t).sum::<f64>();
+            assert!((product - expected).abs() < 1e-12);
+        }
+        let symbolic = vjp_symbolic(&f, &names, &u);
+        for (i, product) in vjp(&f, &names, &point, &u).into_iter().enumerate() {
+            let expected = jacobian
+                .iter()
+                .zip(u)
+                .map(|(row, u)| u * row[i])
+                .sum::<f64>();
+            assert!((product - expected).abs() < 1e-12);
+            assert!((symbolic[i].evaluate(&values) - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn dual_numbers_match_the_symbolic_gradient() {
+        let mut f = pow(var("x"), var("y")) + var("y") * ln(var("x")) / sin(var("x"));
+        let point = at(&[("x", 2.0), ("y", 3.0)]);
+        let g = |x: &[Dual<f64>]| x[0].powf(x[1]) + x[1] * x[0].ln() / x[0].sin();
+        let gradient = grad(g, &[2.0, 3.0]);
+        for (name, derivative) in ["x", "y"].into_iter().zip(gradient) {
+            assert!((derivative - f.diff(name).evaluate(&point)).abs() < 1e-12);
+        }
+        // Single precision, and duals of duals for the second derivative of x^3
+        assert_eq!(grad(|x: &[Dual<f32>]| x[0] * x[0], &[3.0f32]), [6.0]);
+        let cube = |x: &[Dual<Dual<f64>>]| vec![x[0] * x[0] * x[0]];
+        let x = Dual::variable(2.0);
+        assert_eq!(
+            forward_derivative(cube, &[x], 0),
+            [Dual {
+                value: 12.0,
+                deriv: 12.0
+            }]
+        );
+    }
+}
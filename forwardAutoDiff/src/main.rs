@@ -1,242 +1,102 @@
-use std::collections::HashMap;
-use std::fmt;
-use std::ops::{Add, Mul};
-
-// Debugging expression simplification logic
-const DISABLE_SIMPLIFICATION: bool = false;
-const DEBUG_SIMPLIFICATION: bool = false;
-
-#[derive(Debug, Clone)]
-enum Operation {
-    Add,
-    Mul,
-    Pow,
-    Sin,
-    Cos,
-    Log,
-    Var(String),
-    Const(f64),
-}
+use clap::Parser;
+use forward_auto_diff::*;
+#[cfg(feature = "ndarray")]
+use ndarray::Array1;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
-#[derive(Clone)]
-struct Node {
-    op: Operation,
-    args: Vec<Box<Node>>,
-}
-impl fmt::Debug for Node {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self.op {
-            Operation::Var(name) => write!(f, "{}", name),
-            Operation::Const(value) => write!(f, "{}", value),
-            _ => {
-                let arguments = self
-                    .args
-                    .iter()
-                    .map(|arg| format!("{:?}", arg))
-                    .collect::<Vec<String>>()
-                    .join(", ");
-                write!(f, "{:?}({})", self.op, arguments)
-            }
-        }
-    }
+/// Differentiate and evaluate expressions
+#[derive(Parser)]
+struct Cli {
+    /// Expression such as "3*x + 4*y"; runs the built-in examples when omitted
+    expression: Option<String>,
+    /// Differentiate with respect to this variable
+    #[arg(long, value_name = "VARIABLE")]
+    diff: Option<String>,
+    /// Evaluate at this point, e.g. x=1,y=2
+    #[arg(long, value_name = "POINT", value_delimiter = ',', value_parser = parse_assignment)]
+    at: Vec<(String, f64)>,
+    /// Print expressions as LaTeX
+    #[arg(long)]
+    latex: bool,
+    /// Additionally simplify the whole result tree to a fixpoint
+    #[arg(long, overrides_with = "no_simplify")]
+    simplify: bool,
+    /// Do not simplify expressions while building them
+    #[arg(long, overrides_with = "simplify")]
+    no_simplify: bool,
 }
 
-impl Node {
-    fn new(op: Operation, args: Vec<Box<Node>>) -> Self {
-        if DISABLE_SIMPLIFICATION {
-            return Self { op, args };
-        } else {
-            let mut node = Self { op, args };
-            node.simplify()
-        }
-    }
-
-    #[allow(dead_code)]
-    fn simplify_tree(&mut self) -> Node {
-        let orig = format!("{:?}", self);
-        for arg in &mut self.args {
-            *arg = Box::new(arg.simplify_tree());
-        }
-        let b = self.simplify();
-        if DEBUG_SIMPLIFICATION {
-            println!("Simplified {} to {:?}", orig, b);
-        }
-        b
-    }
-
-    fn simplify(&mut self) -> Node {
-        let op = &self.op;
-        let mut args = self.args.clone();
-
-        fn eq(a: &Operation, b: f64) -> bool {
-            matches!(a, Operation::Const(value) if (value - b).abs() < 1e-5)
-        }
+fn parse_assignment(assignment: &str) -> Result<(String, f64), String> {
+    let (name, value) = assignment
+        .split_once('=')
+        .ok_or(format!("expected NAME=VALUE, got '{}'", assignment))?;
+    let value = value
+        .trim()
+        .parse::<f64>()
+        .map_err(|e| format!("invalid value for {}: {}", name, e))?;
+    Ok((name.trim().to_string(), value))
+}
 
-        match op {
-            // a + 0 = a
-            // evaluate const + const
-            Operation::Add => {
-                if eq(&args[0].op, 0.0) {
-                    return *args.remove(1);
-                }
-                if eq(&args[1].op, 0.0) {
-                    return *args.remove(0);
-                }
-                if let Operation::Const(a) = args[0].op {
-                    if let Operation::Const(b) = args[1].op {
-                        return c(a + b);
-                    }
-                }
-            }
-            // a * 1 = a
-            // a * 0 = 0
-            // evaluate const * const
-            Operation::Mul => {
-                if eq(&args[0].op, 1.0) {
-                    return *args.remove(1);
-                }
-                if eq(&args[1].op, 1.0) {
-                    return *args.remove(0);
-                }
-                if eq(&args[0].op, 0.0) || eq(&args[1].op, 0.0) {
-                    return c(0.0);
-                }
-                if let Operation::Const(a) = args[0].op {
-                    if let Operation::Const(b) = args[1].op {
-                        return c(a * b);
-                    }
-                }
-            }
-            // a ^ 1 = a
-            // a ^ 0 = 1
-            // evaluate const ^ const
-            Operation::Pow => {
-                if eq(&args[1].op, 1.0) {
-                    return *args.remove(0);
-                }
-                if eq(&args[1].op, 0.0) {
-                    return c(1.0);
-                }
-                if let Operation::Const(a) = args[0].op {
-                    if let Operation::Const(b) = args[1].op {
-                        return c(a.powf(b));
-                    }
-                }
-            }
-            // evaluate sin(const)
-            Operation::Sin => {
-                if let Operation::Const(value) = args[0].op {
-                    return c(value.sin());
-                }
-            }
-            // evaluate cos(const)
-            Operation::Cos => {
-                if let Operation::Const(value) = args[0].op {
-                    return c(value.cos());
-                }
-            }
-            // evaluate log_const(const)
-            Operation::Log => {
-                if let Operation::Const(base) = args[0].op {
-                    if let Operation::Const(value) = args[1].op {
-                        return c(value.log(base));
-                    }
-                }
-            }
-            Operation::Var(_) => (),
-            Operation::Const(_) => (),
-        };
-        Self {
-            op: op.clone(),
-            args,
+fn main() {
+    let cli = Cli::parse();
+    let Some(expression) = cli.expression else {
+        examples();
+        return;
+    };
+    set_simplify_config(SimplifyConfig {
+        disabled: cli.no_simplify,
+        ..simplify_config()
+    });
+    let mut f = match parse(&expression) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
+    };
+    let (label, mut result) = match &cli.diff {
+        Some(variable) => (format!("df/d{}", variable), f.partial_derivative(variable)),
+        None => ("f".to_string(), f),
+    };
+    if cli.simplify {
+        result = result.simplified();
     }
-
-    /// Compute partial derivative wrt. variable
-    fn partial_derivative(&mut self, variable: &String) -> Node {
-        match &self.op {
-            Operation::Var(name) => {
-                if name == variable {
-                    c(1.0)
-                } else {
-                    c(0.0)
-                }
-            }
-            Operation::Const(_) => c(0.0),
-            Operation::Add => {
-                // (a + b)' = a' + b'
-                let da = self.args[0].partial_derivative(variable);
-                let db = self.args[1].partial_derivative(variable);
-                da + db
-            }
-            Operation::Mul => {
-                // (a * b)' = a' * b + a * b'
-                let da = self.args[0].partial_derivative(variable);
-                let db = self.args[1].partial_derivative(variable);
-                let a = *self.args[0].clone();
-                let b = *self.args[1].clone();
-                da * b + a * db
-            }
-            Operation::Pow => {
-                // (a ^ b)' = a ^ b * (b' * ln(a) + b * a' * a^-1)
-                let da = self.args[0].partial_derivative(variable);
-                let db = self.args[1].partial_derivative(variable);
-                let a = *self.args[0].clone();
-                let b = *self.args[1].clone();
-                pow(a.clone(), b.clone()) * (db * ln(a.clone()) + b * da * pow(a.clone(), c(-1.0)))
-            }
-            Operation::Sin => {
-                // (sin(a))' = cos(a) * a'
-                let da = self.args[0].partial_derivative(variable);
-                let a = *self.args[0].clone();
-                cos(a) * da
-            }
-            Operation::Cos => {
-                // (cos(a))' = -sin(a) * a'
-                let da = self.args[0].partial_derivative(variable);
-                let a = *self.args[0].clone();
-                -1.0 * sin(a) * da
-            }
-            Operation::Log => {
-                // (log_a(b))' = (b'/b * ln(a) - a'/a * ln(b)) / ln(a)^2
-                let da = self.args[0].partial_derivative(variable);
-                let db = self.args[1].partial_derivative(variable);
-                let a = *self.args[0].clone();
-                let b = *self.args[1].clone();
-                (db * pow(b.clone(), c(-1.0)) * ln(a.clone())
-                    + -1.0 * da * pow(a.clone(), c(-1.0)) * ln(b.clone()))
-                    * pow(ln(a.clone()), c(-2.0))
-            }
-        }
+    if cli.latex {
+        println!("{}", result.to_latex());
+    } else {
+        println!("{} = {:?}", label, result);
     }
-
-    fn evaluate(&self, variables: &HashMap<String, f64>) -> f64 {
-        let args = self
-            .args
+    if !cli.at.is_empty() {
+        let coordinates = cli
+            .at
             .iter()
-            .map(|arg| arg.evaluate(variables))
-            .collect::<Vec<f64>>();
-        match &self.op {
-            Operation::Var(name) => {
-                if let Some(value) = variables.get(name) {
-                    *value
-                } else {
-                    panic!("Variable {} not found", name);
-                }
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let point = cli.at.into_iter().collect::<HashMap<String, f64>>();
+        match result.try_evaluate(&point) {
+            Ok(value) => println!("{}({}) = {}", label, coordinates, value),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
             }
-            Operation::Const(value) => *value,
-            Operation::Add => args[0] + args[1],
-            Operation::Mul => args[0] * args[1],
-            Operation::Pow => args[0].powf(args[1]),
-            Operation::Sin => args[0].sin(),
-            Operation::Cos => args[0].cos(),
-            Operation::Log => args[1].log(args[0]),
         }
     }
 }
 
-#[allow(unreachable_code)]
-fn main() {
+/// Central difference of `f` at `x` along coordinate `i`
+fn numerical_partial(f: &dyn Fn(&[f64]) -> f64, x: &[f64], i: usize) -> f64 {
+    let h = f64::EPSILON.cbrt() * x[i].abs().max(1.0);
+    let step = |delta: f64| {
+        let mut x_h = x.to_vec();
+        x_h[i] += delta;
+        f(&x_h)
+    };
+    (step(h) - step(-h)) / (2.0 * h)
+}
+
+fn examples() {
     // f(x, y) = 3x + 4y + 5
     let x = var("x");
     let y = var("y");
@@ -330,8 +190,7 @@ fn main() {
     // f(x, y) = tan(ln(x/y))
     let x = var("x");
     let y = var("y");
-    let mut f =
-        sin(ln(x.clone() * pow(y.clone(), c(-1.0)))) * pow(cos(ln(x * pow(y, c(-1.0)))), c(-1.0));
+    let mut f = tan(ln(x / y));
     let df_dx = f.partial_derivative(&"x".to_string());
     let df_dy = f.partial_derivative(&"y".to_string());
     println!("f = tan(ln(x/y)) = {:?}", f);
@@ -344,64 +203,1546 @@ fn main() {
     println!("df/dx(1, 2) = {}", df_dx.evaluate(&x_0));
     println!("df/dy(1, 2) = {}", df_dy.evaluate(&x_0));
     println!();
-}
 
-////////////////////
-/// Constructors ///
-////////////////////
-fn c(value: f64) -> Node {
-    Node::new(Operation::Const(value), vec![])
-}
-fn pow(a: Node, b: Node) -> Node {
-    Node::new(Operation::Pow, vec![Box::new(a), Box::new(b)])
-}
-fn log(base: Node, value: Node) -> Node {
-    Node::new(Operation::Log, vec![Box::new(base), Box::new(value)])
-}
-fn ln(value: Node) -> Node {
-    log(c(std::f64::consts::E), value)
-}
-fn sin(value: Node) -> Node {
-    Node::new(Operation::Sin, vec![Box::new(value)])
-}
-fn cos(value: Node) -> Node {
-    Node::new(Operation::Cos, vec![Box::new(value)])
-}
-fn var(name: &str) -> Node {
-    Node::new(Operation::Var(name.to_string()), vec![])
-}
+    // f(x) = clamp(2x, -1, 1)
+    let x = var("x");
+    let mut f = clamp(2.0 * x, c(-1.0), c(1.0));
+    let df_dx = f.partial_derivative(&"x".to_string());
+    println!("f = clamp(2x, -1, 1) = {:?}", f);
+    println!("df/dx = {:?}", df_dx);
+    for x in [-1.0, 0.25, 1.0] {
+        let mut x_0 = HashMap::new();
+        x_0.insert("x".to_string(), x);
+        println!("f({}) = {}", x, f.evaluate(&x_0));
+        println!("df/dx({}) = {}", x, df_dx.evaluate(&x_0));
+    }
+    println!();
 
-////////////////////////////
-/// Operator overloading ///
-////////////////////////////
+    // f(x) = 3x + 5
+    let mut f = 3.0 * var("x") + 5.0;
+    println!("f = 3x + 5 = {:?}", f);
+    println!("df/dx = {:?}", f.diff("x"));
+    println!("df/dy = {:?}", f.diff("y"));
+    println!();
 
-macro_rules! impl_op {
-    ($trait:ident, $method:ident, $op:expr) => {
-        impl $trait for Node {
-            type Output = Node;
+    // f(x, y) = x^2 + y^2
+    let x = var("x");
+    let y = var("y");
+    let mut f = pow(x, c(2.0)) + pow(y, c(2.0));
+    let mut x_0 = HashMap::new();
+    x_0.insert("x".to_string(), 3.0);
+    x_0.insert("y".to_string(), 4.0);
+    println!("f = x^2 + y^2 = {:?}", f);
+    println!("|grad f(3, 4)| = {}", f.gradient_norm(&x_0));
+    let unit = f.normalized_gradient(&x_0);
+    println!(
+        "grad f(3, 4) / |grad f(3, 4)| = ({}, {})",
+        unit["x"], unit["y"]
+    );
+    println!();
 
-            fn $method(self, other: Self) -> Self {
-                Node::new($op, vec![Box::new(self), Box::new(other)])
-            }
+    // f(x, y) = [sin(x) + cos(y), cos(x) - sin(y)]: the symbolic Jacobian, checked against
+    // central differences as in the numDiff examples
+    let x = var("x");
+    let y = var("y");
+    let mut f = VectorExpr::new(vec![
+        sin(x.clone()) + cos(y.clone()),
+        cos(x) + -1.0 * sin(y),
+    ]);
+    let names = f.variables();
+    let jacobian = f.jacobian();
+    println!("f = {:?}, variables {:?}", f.components, names);
+    for row in &jacobian {
+        println!("  {:?}", row);
+    }
+    let point = [0.7, -1.3];
+    let at = |point: [f64; 2]| names.iter().cloned().zip(point).collect();
+    let h = 1e-6;
+    let mut worst: f64 = 0.0;
+    for j in 0..point.len() {
+        let (mut forward, mut backward) = (point, point);
+        forward[j] += h;
+        backward[j] -= h;
+        let (forward, backward) = (f.evaluate(&at(forward)), f.evaluate(&at(backward)));
+        for (i, row) in jacobian.iter().enumerate() {
+            let numeric = (forward[i] - backward[i]) / (2.0 * h);
+            worst = worst.max((row[j].evaluate(&at(point)) - numeric).abs());
         }
+    }
+    println!(
+        "f(0.7, -1.3) = {:?}, largest difference to central differences: {:e}",
+        f.evaluate(&at(point)),
+        worst
+    );
+    println!();
 
-        impl $trait<f64> for Node {
-            type Output = Node;
+    // f(x, y) = 3x + y with x -> y and y -> 2
+    let f = 3.0 * var("x") + var("y");
+    let both = HashMap::from([("x".to_string(), var("y")), ("y".to_string(), c(2.0))]);
+    println!("f = 3x + y = {:?}", f);
+    println!("simultaneously = {:?}", f.substitute_all(&both));
+    println!(
+        "one after another = {:?}",
+        f.substitute("x", &var("y")).substitute("y", &c(2.0))
+    );
+    // Plug the minimizer x = 2 of (x - 2)^2 + 3 back in, and change variables to x = t^2
+    let f = powi(var("x") - 2.0, 2) + 3.0;
+    println!(
+        "f = {}, at the minimum f = {:?}",
+        f.to_infix(),
+        f.substitute("x", &c(2.0))
+    );
+    let mut g = f.substitute("x", &powi(var("t"), 2));
+    println!(
+        "f(t^2) = {}, d/dt = {}",
+        g.to_infix(),
+        g.diff("t").to_infix()
+    );
+    println!();
+
+    // f(u, v) = u v + sin(u) composed with u = x^2 and v = exp(x)
+    let f = var("u") * var("v") + sin(var("u"));
+    let inner = HashMap::from([
+        ("u".to_string(), powi(var("x"), 2)),
+        ("v".to_string(), exp(var("x"))),
+    ]);
+    let mut composed = f.compose(&inner);
+    println!("f(g(x), h(x)) = {}", composed.to_infix());
+    // Chain rule: d/dx f(g, h) = f_u(g, h) g' + f_v(g, h) h'
+    let chain = inner
+        .iter()
+        .map(|(name, g)| f.clone().diff(name).compose(&inner) * g.clone().diff("x"))
+        .fold(zero(), |sum, term| sum + term);
+    let x_0 = HashMap::from([("x".to_string(), 0.7)]);
+    println!(
+        "d/dx at 0.7 = {} (chain rule {})",
+        composed.diff("x").evaluate(&x_0),
+        chain.evaluate(&x_0)
+    );
+    println!();
 
-            fn $method(self, other: f64) -> Self {
-                Node::new($op, vec![Box::new(self), Box::new(c(other))])
+    // f(x, y) = log_(x + y)(x + y) + log_x(1)
+    let x = var("x");
+    let y = var("y");
+    let f = log(x.clone() + y.clone(), x.clone() + y) + log(x, c(1.0));
+    println!("f = log_(x + y)(x + y) + log_x(1) = {:?}", f);
+    println!();
+
+    // f(x) = 3x + 5 from postfix tokens `3 x * 5 +`
+    let tokens = [
+        Token::Num(3.0),
+        Token::Var("x".to_string()),
+        Token::Mul,
+        Token::Num(5.0),
+        Token::Add,
+    ];
+    println!("3 x * 5 + = {:?}", from_rpn(&tokens).unwrap());
+    println!("3*x + 5 = {:?}", 3.0 * var("x") + 5.0);
+    if let Err(e) = from_rpn(&tokens[1..]) {
+        println!("x * 5 + = {}", e);
+    }
+    let tokens = [
+        Token::Var("x".to_string()),
+        Token::Num(1.0),
+        Token::Sub,
+        Token::Var("y".to_string()),
+        Token::Div,
+        Token::Func("sin".to_string()),
+        Token::Num(2.0),
+        Token::Pow,
+    ];
+    println!("x 1 - y / sin 2 ^ = {:?}", from_rpn(&tokens).unwrap());
+    println!();
+
+    // Round trips through infix and postfix strings of random expressions
+    let mut seed: u64 = 1;
+    let mut random = move |n: u64| {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 33) % n
+    };
+    fn random_node(depth: usize, random: &mut dyn FnMut(u64) -> u64) -> Node {
+        let choice = if depth == 0 { random(3) } else { random(10) };
+        let mut next = || Rc::new(random_node(depth - 1, random));
+        match choice {
+            0 => var("x"),
+            1 => var("y"),
+            2 => c(random(9) as f64 - 4.0),
+            3 | 4 => Node::new(Operation::Add, vec![next(), next()]),
+            5 | 6 => Node::new(Operation::Mul, vec![next(), next()]),
+            7 => {
+                let base = next();
+                let exponent = Rc::new(c(random(4) as f64));
+                Node::new(Operation::Pow, vec![base, exponent])
             }
+            8 => Node::new(Operation::Sin, vec![next()]),
+            _ => Node::new(Operation::Atan, vec![next()]),
         }
+    }
+    let mut x_0 = HashMap::new();
+    x_0.insert("x".to_string(), 0.7);
+    x_0.insert("y".to_string(), -1.3);
+    let trials = 200;
+    let mut agree = 0;
+    let mut display_agree = 0;
+    for _ in 0..trials {
+        let f = random_node(4, &mut random);
+        let from_infix = parse(&f.to_infix()).unwrap();
+        let from_postfix = parse_postfix(&f.to_postfix()).unwrap();
+        let value = f.evaluate(&x_0);
+        let same_value = |g: &Node| {
+            let other = g.evaluate(&x_0);
+            other == value || (other.is_nan() && value.is_nan())
+        };
+        let from_bytes = Node::from_bytes(&f.to_bytes()).unwrap();
+        if from_infix.same_as(&f)
+            && from_postfix.same_as(&f)
+            && from_bytes.same_as(&f)
+            && same_value(&from_infix)
+            && same_value(&from_postfix)
+        {
+            agree += 1;
+        } else {
+            println!("round trip changed {}", f.to_infix());
+        }
+        // Display drops the grouping of sums and products, which may only change rounding
+        let other = parse(&f.to_string()).unwrap().evaluate(&x_0);
+        if (other - value).abs() <= 1e-9 * value.abs().max(1.0)
+            || (other.is_nan() && value.is_nan())
+        {
+            display_agree += 1;
+        }
+    }
+    let f = 3.0 * var("x") + -2.0 * sin(var("y"));
+    println!("f = {}", f.to_infix());
+    println!("f = {}", f.to_postfix());
+    println!("{}/{} random round trips agree", agree, trials);
+    println!("{}/{} agree through Display", display_agree, trials);
+    let f = var("x") * (var("y") * (var("z") + (1.0 + var("x")))) / (var("y") - 2.0);
+    println!("{:?} = {}", f, f);
+    println!();
 
-        impl $trait<Node> for f64 {
-            type Output = Node;
+    // f(x, y) = sin(xy) xy + exp(xy) as a DOT graph, where xy is drawn once
+    let xy = var("x") * var("y");
+    let mut f = sin(xy.clone()) * xy.clone() + exp(xy);
+    print!("{}", f.to_dot());
+    let derivative = f.diff("x");
+    println!(
+        "df/dx has {} nodes as a tree and {} in the DOT graph",
+        derivative.to_postfix().split(' ').count(),
+        derivative.to_dot().matches("shape=").count()
+    );
+    println!();
 
-            fn $method(self, other: Node) -> Node {
-                Node::new($op, vec![Box::new(c(self)), Box::new(other)])
-            }
+    // f(x) = ln(x) / x as a Mermaid flowchart for a markdown report
+    let f = ln(var("x")) / var("x");
+    println!("```mermaid\n{}```", f.to_mermaid());
+    println!();
+
+    // f(x, y_1) = log_2(x) / sqrt(y_1) as MathML for a browser or an LMS
+    let f = log(c(2.0), var("x")) / sqrt(var("y_1"));
+    println!("{}", f.to_mathml());
+    println!();
+
+    // f(x, y) = x^2 sin(y) with df/dx saved to JSON and loaded back
+    #[cfg(feature = "serde")]
+    {
+        let mut f = powi(var("x"), 2) * sin(var("y"));
+        let derivative = f.diff("x");
+        let json = serde_json::to_string(&derivative).unwrap();
+        let loaded = serde_json::from_str::<Node>(&json).unwrap();
+        println!("df/dx = {}", json);
+        println!(
+            "loaded back: {}, same: {}",
+            loaded,
+            loaded.same_as(&derivative)
+        );
+        let json = derivative.to_json();
+        let loaded = Node::from_json(&json).unwrap();
+        println!("df/dx = {}", json);
+        println!("loaded back: {}", loaded.same_as(&derivative));
+        // A file from a newer build, using an operation this one does not know
+        let newer = r#"{"version": 1, "nodes": [{"op": "var", "name": "x", "unit": "m"},
+            {"op": "lambert_w", "args": [0]}, {"op": "mul", "args": [0, 1]}]}"#;
+        println!("from a newer build: {}", Node::from_json(newer).unwrap());
+        match Node::from_json(r#"{"version": 2, "nodes": []}"#) {
+            Ok(f) => println!("version 2: {}", f),
+            Err(error) => println!("version 2: {}", error),
+        }
+        println!();
+    }
+
+    // f(x, y) = sin(xy)^3 / (1 + x^2 y), with its second derivative cached in binary
+    let mut f = powi(sin(var("x") * var("y")), 3) / (1.0 + powi(var("x"), 2) * var("y"));
+    let mut derivative = f.diff("x");
+    let second = derivative.diff("y");
+    let bytes = second.to_bytes();
+    let loaded = Node::from_bytes(&bytes).unwrap();
+    println!(
+        "d2f/dxdy: {} bytes in binary, {} as infix, loaded back: {}",
+        bytes.len(),
+        second.to_infix().len(),
+        loaded.same_as(&second)
+    );
+    match Node::from_bytes(&bytes[..bytes.len() - 3]) {
+        Ok(_) => println!("truncated input decoded"),
+        Err(error) => println!("truncated input: {}", error),
+    }
+    println!();
+
+    // f(x, y) = x^2 y sin(xy) / (1 + x^2 + y^2), whose gradient repeats the same subexpressions
+    let x = var("x");
+    let y = var("y");
+    let mut f = powi(x.clone(), 2) * y.clone() * sin(x.clone() * y.clone())
+        / (1.0 + powi(x, 2) + powi(y, 2));
+    let gradient = [f.diff("x"), f.diff("y")];
+    let mut interner = Interner::new();
+    let ids = gradient.each_ref().map(|partial| interner.intern(partial));
+    let tree_size = gradient
+        .iter()
+        .map(|partial| partial.to_postfix().split(' ').count())
+        .sum::<usize>();
+    println!(
+        "grad f: {} nodes as trees, {} interned",
+        tree_size,
+        interner.len()
+    );
+    let at = HashMap::from([("x".to_string(), 0.5), ("y".to_string(), -1.5)]);
+    println!(
+        "grad f = {:?} = {:?}",
+        gradient.each_ref().map(|partial| partial.evaluate(&at)),
+        interner.evaluate(&ids, &at)
+    );
+    println!(
+        "same tree back: {}",
+        interner.get(ids[0]).same_as(&gradient[0])
+    );
+    println!();
+
+    // f(x) = sin(sin(...sin(x)...)) nested 20000 times; clones share the subtrees
+    let mut f = var("x");
+    for _ in 0..20_000 {
+        f = sin(f);
+    }
+    let start = std::time::Instant::now();
+    let copies = (0..1000).map(|_| f.clone()).collect::<Vec<Node>>();
+    println!(
+        "1000 clones of a 20001-node tree took {:?}, shared: {}",
+        start.elapsed(),
+        Rc::ptr_eq(&copies[0].args[0], &f.args[0])
+    );
+    drop(copies);
+    println!();
+
+    // The same chain in an arena, evaluated by a loop over its nodes, and the partial
+    // derivatives of sin(xy) e^(xy) + (xy)^2, whose shared subtrees are stored once
+    let mut arena = ExprArena::new();
+    let chain = arena.add(&f);
+    let at = HashMap::from([("x".to_string(), 1.0), ("y".to_string(), 2.0)]);
+    println!(
+        "{} nodes, f(1) = {:?} = {}",
+        arena.len(),
+        arena.evaluate(&[chain], &at),
+        f.evaluate(&at)
+    );
+    let xy = var("x") * var("y");
+    let mut f = sin(xy.clone()) * exp(xy.clone()) + powi(xy, 2);
+    let partials = [
+        f.partial_derivative(&"x".to_string()),
+        f.partial_derivative(&"y".to_string()),
+    ];
+    let mut arena = ExprArena::new();
+    let ids = partials.each_ref().map(|partial| arena.add(partial));
+    let operations = arena.iter().filter(|(_, _, args)| !args.is_empty()).count();
+    println!(
+        "grad f: {} nodes as trees, {} in the arena of which {} are operations",
+        partials
+            .iter()
+            .map(|partial| partial.to_postfix().split(' ').count())
+            .sum::<usize>(),
+        arena.len(),
+        operations
+    );
+    println!(
+        "grad f = {:?} = {:?}",
+        partials.each_ref().map(|partial| partial.evaluate(&at)),
+        arena.evaluate(&ids, &at)
+    );
+    println!();
+
+    // Second derivatives of f(x, y) = x^2 y^2 + sin(x) deduplicated in a HashSet, and the
+    // derivatives used as map keys
+    let mut f = powi(var("x"), 2) * powi(var("y"), 2) + sin(var("x"));
+    let mut second = vec![];
+    for first in ["x", "y"] {
+        let mut partial = f.diff(first);
+        for other in ["x", "y"] {
+            second.push(partial.diff(other));
+        }
+    }
+    let distinct = second.iter().cloned().collect::<HashSet<Node>>();
+    println!(
+        "{} second derivatives, {} distinct, d2f/dxdy == d2f/dydx: {}",
+        second.len(),
+        distinct.len(),
+        second[1] == second[2]
+    );
+    let names = HashMap::from([
+        (second[0].clone(), "d2f/dx2"),
+        (second[3].clone(), "d2f/dy2"),
+    ]);
+    println!("{} is {}", second[3], names[&second[3]]);
+    println!("NaN == NaN as expressions: {}", c(f64::NAN) == c(f64::NAN));
+    println!();
+
+    // f(x) = (x + 1)(x + 2) = x^2 + 3x + 2
+    let f = (var("x") + 1.0) * (var("x") + 2.0);
+    println!("f = (x + 1)(x + 2) = {:?}", f);
+    println!("coefficients of f = {:?}", f.poly_coeffs("x"));
+    if let Err(e) = sin(var("x")).poly_coeffs("x") {
+        println!("coefficients of sin(x): {}", e);
+    }
+    println!();
+
+    // f(x) = (2 + x) + 3 and g(x) = 2(x * 3)
+    println!("f = (2 + x) + 3 = {:?}", (2.0 + var("x")) + 3.0);
+    println!("g = 2(x * 3) = {:?}", 2.0 * (var("x") * 3.0));
+    println!();
+
+    // f(x, y) = 3x + 4y, with y left out
+    let f = 3.0 * var("x") + 4.0 * var("y");
+    let mut x_0 = HashMap::new();
+    x_0.insert("x".to_string(), 2.0);
+    println!("f = 3x + 4y = {:?}", f);
+    println!("f(2, default 0) = {}", f.evaluate_with_default(&x_0, 0.0));
+    println!("f(2, y) = {:?}", f.partial_evaluate(&x_0));
+    println!("f(2, y) = {:?}", f.try_evaluate(&x_0));
+    let x_0 = HashMap::from([("x".to_string(), -1.0)]);
+    let mut g = sqrt(var("x") + 1.0);
+    let reciprocal = 1.0 / (var("x") + 1.0);
+    for f in [
+        ln(var("x")) + 1.0,
+        exp(-1000.0 * var("x")),
+        sin(var("x")),
+        g.diff("x"),
+        reciprocal,
+        pow(var("x"), c(0.5)),
+    ] {
+        match f.try_evaluate(&x_0) {
+            Ok(value) => println!("{} at x = -1: {}", f.to_infix(), value),
+            Err(error) => println!("{} at x = -1: {}", f.to_infix(), error),
         }
+    }
+    let missing = f
+        .free_variables()
+        .into_iter()
+        .filter(|name| !x_0.contains_key(name))
+        .collect::<Vec<String>>();
+    println!(
+        "f depends on {:?}, missing {:?}",
+        f.free_variables(),
+        missing
+    );
+    // Jacobian sparsity of [xy, sin(z), x + z]: which inputs each output depends on
+    let (x, y, z) = (var("x"), var("y"), var("z"));
+    let outputs = [x.clone() * y, sin(z.clone()), x + z];
+    for (i, output) in outputs.iter().enumerate() {
+        let inputs = output.free_variables();
+        let row = ["x", "y", "z"]
+            .map(|name| if inputs.contains(name) { '*' } else { '.' })
+            .iter()
+            .collect::<String>();
+        println!("row {}: {}", i, row);
+    }
+    // f(x, y) = exp(sin(a) * x) + y with the parameter a = 0.5 specialized away
+    let f = exp(sin(var("a")) * var("x")) + var("y");
+    let a = HashMap::from([("a".to_string(), 0.5)]);
+    println!(
+        "f = {}, at a = 0.5: {}",
+        f.to_infix(),
+        f.partial_evaluate(&a).to_infix()
+    );
+    println!();
+
+    // f(x, y) = 3x + sin(y): one Mul, one Add and one Sin
+    let mut f = 3.0 * var("x") + sin(var("y"));
+    println!("f = 3x + sin(y) = {:?}", f);
+    println!("flops(f) = {}", f.flop_count());
+    println!(
+        "flops(df/dy) = {}",
+        f.partial_derivative(&"y".to_string()).flop_count()
+    );
+    println!();
+
+    // f(x) = x^3
+    let mut f = powi(var("x"), 3);
+    let df_dx = f.partial_derivative(&"x".to_string());
+    println!("f = x^3 = {:?}", f);
+    println!("df/dx = {:?}", df_dx);
+    let mut x_0 = HashMap::new();
+    x_0.insert("x".to_string(), -2.0);
+    println!("f(-2) = {}", f.evaluate(&x_0));
+    println!("df/dx(-2) = {}", df_dx.evaluate(&x_0));
+    println!();
+
+    // f(x) = 1.0000001x, with the default and a tighter simplification tolerance
+    let f = 1.0000001 * var("x");
+    println!("f = 1.0000001x = {:?} (tolerance 1e-5)", f);
+    let default = simplify_config();
+    set_simplify_config(SimplifyConfig {
+        tolerance: 1e-9,
+        ..default
+    });
+    let f = 1.0000001 * var("x");
+    println!("f = 1.0000001x = {:?} (tolerance 1e-9)", f);
+    set_simplify_config(default);
+    println!();
+
+    // f(x) = (x^2)^3
+    let x = var("x");
+    let f = pow(pow(x, c(2.0)), c(3.0));
+    println!("f = (x^2)^3 = {:?}", f);
+    let mut x_0 = HashMap::new();
+    x_0.insert("x".to_string(), -1.5);
+    println!(
+        "f(-1.5) = {} (expected {})",
+        f.evaluate(&x_0),
+        (-1.5f64).powi(2).powi(3)
+    );
+    println!();
+
+    // f(x) = |x^2 - 1|, checked against central differences away from the kinks
+    let x = var("x");
+    let mut f = abs(pow(x, c(2.0)) - 1.0);
+    let df_dx = f.partial_derivative(&"x".to_string());
+    println!("f = |x^2 - 1| = {:?}", f);
+    println!("df/dx = {:?}", df_dx);
+    let h = 1e-6;
+    for x in [-2.0, 0.5, 1.0, 3.0] {
+        let at = |x: f64| HashMap::from([("x".to_string(), x)]);
+        let numeric = (f.evaluate(&at(x + h)) - f.evaluate(&at(x - h))) / (2.0 * h);
+        println!(
+            "df/dx({}) = {} (numeric {})",
+            x,
+            df_dx.evaluate(&at(x)),
+            numeric
+        );
+    }
+    println!();
+
+    // f(x) = max(x, 0) + min(x, 1 - x), checked against central differences; at the kinks
+    // x = 0 and x = 0.5 each piece contributes the average of its one-sided derivatives
+    let x = var("x");
+    let mut f = max(x.clone(), c(0.0)) + min(x.clone(), 1.0 - x);
+    let df_dx = f.diff("x");
+    println!("f = {}", f.to_infix());
+    for x in [-1.0, 0.0, 0.25, 0.5, 2.0] {
+        let at = |x: f64| HashMap::from([("x".to_string(), x)]);
+        let numeric = (f.evaluate(&at(x + h)) - f.evaluate(&at(x - h))) / (2.0 * h);
+        println!(
+            "df/dx({}) = {} (numeric {})",
+            x,
+            df_dx.evaluate(&at(x)),
+            numeric
+        );
+    }
+    println!();
+
+    // f(x) = x ln(x) for x > 0 and 0 elsewhere; the unused branch is never evaluated, so
+    // ln(x) at x <= 0 does not turn the result into NaN
+    let x = var("x");
+    let mut f = if_then_else(gt(x.clone(), zero()), x.clone() * ln(x), zero());
+    let df_dx = f.diff("x");
+    println!("f = {}", f.to_infix());
+    println!("df/dx = {}", df_dx.to_infix());
+    for x in [-1.0, 0.0, 2.0] {
+        let at = HashMap::from([("x".to_string(), x)]);
+        println!(
+            "f({}) = {}, df/dx({}) = {} (tape {})",
+            x,
+            f.evaluate(&at),
+            x,
+            df_dx.evaluate(&at),
+            Tape::record(&f, &at).grad()["x"]
+        );
+    }
+    println!();
+
+    // Special functions against known values: erf(0.5), erf(3.5), Γ(5) = 24, Γ(1/2) = √π,
+    // ln Γ(100), ψ(1) = -γ and ψ'(1) = π²/6
+    let known = [
+        ("erf(0.5)", erf(c(0.5)), 0.5204998778130465),
+        ("erf(3.5)", erf(c(3.5)), 0.9999992569016276),
+        ("gamma(5)", gamma(c(5.0)), 24.0),
+        ("gamma(0.5)", gamma(c(0.5)), std::f64::consts::PI.sqrt()),
+        ("ln_gamma(100)", ln_gamma(c(100.0)), 359.1342053695754),
+        ("digamma(1)", digamma(c(1.0)), -0.5772156649015329),
+        (
+            "polygamma(1, 1)",
+            polygamma(one(), c(1.0)),
+            std::f64::consts::PI.powi(2) / 6.0,
+        ),
+    ];
+    for (name, node, expected) in known {
+        println!(
+            "{} = {} (expected {})",
+            name,
+            node.evaluate(&HashMap::new()),
+            expected
+        );
+    }
+    // Poisson log-likelihood of k events at rate λ = 3, ln(λ^k e^-λ / k!), for a
+    // continuous k; d/dk = ln(λ) - ψ(k + 1)
+    let k = var("k");
+    let mut f = k.clone() * ln(c(3.0)) - 3.0 - ln_gamma(k + 1.0);
+    let df_dk = f.diff("k");
+    println!("f = {}", f.to_infix());
+    println!("df/dk = {}", df_dk.to_infix());
+    let h = 1e-6;
+    for k in [0.5, 2.0, 10.0] {
+        let at = |k: f64| HashMap::from([("k".to_string(), k)]);
+        let numeric = (f.evaluate(&at(k + h)) - f.evaluate(&at(k - h))) / (2.0 * h);
+        println!(
+            "df/dk({}) = {} (numeric {})",
+            k,
+            df_dk.evaluate(&at(k)),
+            numeric
+        );
+    }
+    println!();
+
+    // Taylor coefficients f^(k)(0) / k! of f(x) = exp(sin(x)) = 1 + x + x^2/2 - x^4/8 - x^5/15 + ...
+    let x = var("x");
+    let f = exp(sin(x.clone()));
+    let at = HashMap::from([("x".to_string(), 0.0)]);
+    let mut factorial = 1.0;
+    let coefficients = (0..6)
+        .map(|k| {
+            factorial *= k.max(1) as f64;
+            f.nth_derivative("x", k).evaluate(&at) / factorial
+        })
+        .collect::<Vec<f64>>();
+    println!("f = {}", f.to_infix());
+    println!("Taylor coefficients at 0: {:?}", coefficients);
+    let sizes = (0..6)
+        .map(|k| f.nth_derivative("x", k).flop_count())
+        .collect::<Vec<usize>>();
+    println!("flops of the derivatives: {:?}", sizes);
+    println!();
+
+    // f(x) = atan(x), checked against central differences
+    let x = var("x");
+    let mut f = atan(x);
+    let df_dx = f.partial_derivative(&"x".to_string());
+    println!("f = atan(x) = {:?}", f);
+    println!("df/dx = {:?}", df_dx);
+    let h = 1e-6;
+    for x in [-1e3, -1.0, 0.0, 0.5, 1e3] {
+        let at = |x: f64| HashMap::from([("x".to_string(), x)]);
+        let numeric = (f.evaluate(&at(x + h)) - f.evaluate(&at(x - h))) / (2.0 * h);
+        println!(
+            "df/dx({}) = {} (numeric {})",
+            x,
+            df_dx.evaluate(&at(x)),
+            numeric
+        );
+    }
+    println!();
+
+    // f(x, y) = atan2(y, x)
+    let x = var("x");
+    let y = var("y");
+    let mut f = atan2(y, x);
+    let df_dx = f.partial_derivative(&"x".to_string());
+    let df_dy = f.partial_derivative(&"y".to_string());
+    println!("f = atan2(y, x) = {:?}", f);
+    println!("df/dx = {:?}", df_dx);
+    println!("df/dy = {:?}", df_dy);
+    let mut x_0 = HashMap::new();
+    x_0.insert("x".to_string(), 1.0);
+    x_0.insert("y".to_string(), 2.0);
+    println!("f(1, 2) = {}", f.evaluate(&x_0));
+    println!("df/dx(1, 2) = {}", df_dx.evaluate(&x_0));
+    println!("df/dy(1, 2) = {}", df_dy.evaluate(&x_0));
+    println!();
+
+    // f(x, y) = tan(ln(x / y)), built with methods and with free functions
+    let x = var("x");
+    let y = var("y");
+    let fluent = (x.clone() * y.clone().powi(-1)).ln().tan();
+    let free = tan(ln(x.clone() * powi(y.clone(), -1)));
+    println!("f = tan(ln(x / y)) = {}", fluent.to_infix());
+    println!(
+        "same as the free-function version: {}",
+        fluent.same_as(&free)
+    );
+    println!();
+
+    // f(x, y) = 2 sin(x) + 3 cos(y) over 1000 points at once
+    #[cfg(feature = "ndarray")]
+    {
+        let f = 2.0 * sin(var("x")) + 3.0 * cos(var("y"));
+        let xs = Array1::linspace(-5.0, 5.0, 1000);
+        let ys = xs.mapv(|x: f64| x * x / 5.0);
+        let columns = HashMap::from([("x".to_string(), xs.view()), ("y".to_string(), ys.view())]);
+        let vectorized = f.evaluate_ndarray(&columns);
+        let max_error = xs
+            .iter()
+            .zip(&ys)
+            .zip(&vectorized)
+            .map(|((&x, &y), value)| {
+                let at = HashMap::from([("x".to_string(), x), ("y".to_string(), y)]);
+                (f.evaluate(&at) - value).abs()
+            })
+            .fold(0.0, f64::max);
+        println!("f = {}", f.to_infix());
+        println!(
+            "{} points, largest difference from scalar evaluation: {}",
+            vectorized.len(),
+            max_error
+        );
+        println!();
+    }
+
+    // f(x) = sin(sin(sin(sin(sin(x))))), checked against central differences
+    let mut f = iterate(sin, var("x"), 5);
+    let df_dx = f.diff("x");
+    let at = |x: f64| HashMap::from([("x".to_string(), x)]);
+    let h = 1e-6;
+    println!("f = {}", f.to_infix());
+    for x in [0.0, 0.7, 2.0] {
+        let numeric = (f.evaluate(&at(x + h)) - f.evaluate(&at(x - h))) / (2.0 * h);
+        let derivative = df_dx.evaluate(&at(x));
+        assert!((derivative - numeric).abs() < 1e-8);
+        println!("df/dx({}) = {} (numeric {})", x, derivative, numeric);
+    }
+    // Deep nesting: differentiation recurses once per level, with large stack frames in debug
+    // builds, which overflow the default main-thread stack at around 300 levels
+    let mut f = iterate(|node| cos(node) + 0.5, var("x"), 200);
+    let df_dx = f.partial_derivative(&"x".to_string());
+    println!(
+        "200 nested cos(... + 0.5): f(1) = {}, df/dx(1) = {}",
+        f.evaluate(&at(1.0)),
+        df_dx.evaluate(&at(1.0))
+    );
+    println!();
+
+    // f(x_1, ..., x_100000) = x_1 + ... + x_100000, 100000 levels deep. Built without
+    // simplifying, which would copy the growing sum at every step.
+    let f = (1..=100_000).fold(c(0.0), |sum, i| Node {
+        op: Operation::Add,
+        args: vec![Rc::new(sum), Rc::new(var(&format!("x_{}", i)))],
+    });
+    println!(
+        "x_1 + ... + x_100000 at x_i = 0.5: {}",
+        f.evaluate_with_default(&HashMap::new(), 0.5)
+    );
+    drop(f);
+    let f = (0..100_000).fold(var("x"), |node, _| Node {
+        op: Operation::Sin,
+        args: vec![Rc::new(node)],
+    });
+    let at = HashMap::from([("x".to_string(), 1.0)]);
+    println!(
+        "sin(sin(...(x))) 100000 times at x = 1: {}",
+        f.evaluate(&at)
+    );
+    drop(f);
+    println!();
+
+    // f(x, y) = 5x - 3y and -x + y print with subtractions and a leading minus
+    let x = var("x");
+    let y = var("y");
+    let mut f = 5.0 * x.clone() + -3.0 * y.clone();
+    println!("f = {} = {}", f.to_infix(), f.to_latex());
+    let gradient = ["x", "y"].map(|name| f.diff(name).to_infix());
+    println!("grad f = [{}]", gradient.join(", "));
+    let f = -1.0 * x.clone() + y.clone() + -1.0 * (x.clone() + sin(y.clone())) + -2.0;
+    println!("f = {} = {}", f.to_infix(), f.to_latex());
+    println!("parsed back: {}", parse(&f.to_infix()).unwrap().same_as(&f));
+    let mut f = (x.clone() - y.clone()) / -x.clone() - 1.0 / y.clone();
+    println!("f = {}, df/dx = {}", f.to_infix(), f.diff("x").to_infix());
+    let mut f = Node::parse("3*x + 4*sin(y) - x^2").unwrap();
+    println!("f = {}, df/dy = {}", f.to_infix(), f.diff("y").to_infix());
+    match "3*x + (4".parse::<Node>() {
+        Ok(f) => println!("f = {}", f.to_infix()),
+        Err(error) => println!("3*x + (4: {}", error),
+    }
+    println!();
+
+    // f(x, y, r) = 3x + 2sin(x)y + 2pi r, written the way the homework statements write it
+    let mut f = Node::parse("3x + 2sin(x)y + 2pi r").unwrap();
+    println!("f = {}, df/dx = {}", f.to_infix(), f.diff("x").to_infix());
+    println!("2e = {}", Node::parse("2e").unwrap().to_infix());
+    for input in ["1/2x", "sin x", "2 3"] {
+        match Node::parse(input) {
+            Ok(f) => println!("{} = {}", input, f.to_infix()),
+            Err(error) => println!("{}: {}", input, error),
+        }
+    }
+    println!();
+
+    // f(x) = smoothstep(x / 4) * sin(x) with a user-defined smoothstep(t) = 3t^2 - 2t^3,
+    // whose derivative is 6t(1 - t)
+    register_function(
+        "smoothstep",
+        1,
+        |args| 3.0 * args[0].powi(2) - 2.0 * args[0].powi(3),
+        |args, derivatives| {
+            let t = args[0].clone();
+            6.0 * t.clone() * (1.0 - t) * derivatives[0].clone()
+        },
+    );
+    let x = var("x");
+    let mut f = user_fn("smoothstep", vec![0.25 * x.clone()]) * sin(x.clone());
+    let df_dx = f.diff("x");
+    println!("f = {}", f.to_infix());
+    println!("df/dx = {}", df_dx.to_infix());
+    let at = |x: f64| HashMap::from([("x".to_string(), x)]);
+    let h = 1e-6;
+    for x in [0.5, 1.0, 3.0] {
+        let numeric = (f.evaluate(&at(x + h)) - f.evaluate(&at(x - h))) / (2.0 * h);
+        println!(
+            "df/dx({}) = {} (numeric {})",
+            x,
+            df_dx.evaluate(&at(x)),
+            numeric
+        );
+    }
+    println!(
+        "tape gradient at 1: {:?}",
+        Tape::record(&f, &at(1.0)).grad()
+    );
+    println!("parsed: {:?}", parse("smoothstep(x) + 1").unwrap());
+    println!();
+
+    // Locate the first structural difference between two expressions
+    let x = var("x");
+    let f = x.clone() + 1.0;
+    let g = x.clone() + 2.0;
+    println!("x + 1 vs x + 2: {:?}", f.tree_diff(&g));
+    println!(
+        "x + 1 vs sin(x) + 1: {:?}",
+        f.tree_diff(&(sin(x.clone()) + 1.0))
+    );
+    println!("x + 1 vs x + 1: {:?}", f.tree_diff(&(x.clone() + 1.0)));
+    println!();
+
+    // f(x) = sin(x)^2: check identities by evaluating at random points
+    let x = var("x");
+    let mut f = powi(sin(x.clone()), 2);
+    let identity = 2.0 * sin(x.clone()) * cos(x.clone());
+    println!("f' = 2sin(x)cos(x): {}", f.diff("x").equiv(&identity, 100));
+    println!(
+        "sin(x)^2 + cos(x)^2 = 1: {}",
+        (f.clone() + powi(cos(x.clone()), 2)).equiv(&c(1.0), 100)
+    );
+    println!("sqrt(x^2) = x: {}", sqrt(powi(x.clone(), 2)).equiv(&x, 100));
+    println!(
+        "ln(x)/ln(x) = 1: {}",
+        (ln(x.clone()) / ln(x.clone())).equiv(&c(1.0), 100)
+    );
+    println!();
+
+    // f(x, y) = sin(x y) + x^2: traversals without matching on the enum
+    let (x, y) = (var("x"), var("y"));
+    let f = sin(x.clone() * y.clone()) + powi(x.clone(), 2);
+    let mut names = vec![];
+    f.visit(|node| {
+        if let Operation::Var(name) = &node.op {
+            names.push(name.clone());
+        }
+    });
+    println!("variables in visiting order: {:?}", names);
+    let renamed = f.map(|node| match &node.op {
+        Operation::Var(name) => var(&format!("{}_0", name)),
+        _ => node,
+    });
+    println!("renamed: {}", renamed);
+    let at_x = f.map(|node| match &node.op {
+        Operation::Var(name) if name == "x" => c(2.0),
+        _ => node,
+    });
+    println!("x = 2: {}", at_x);
+    let depth = f.fold(|_, args: Vec<usize>| 1 + args.into_iter().max().unwrap_or(0));
+    println!("depth: {}", depth);
+    println!();
+
+    // f(x) = ln(x^2 + 1) / ln(x): pre- and post-order iterators
+    let x = var("x");
+    let f = ln(powi(x.clone(), 2) + 1.0) / ln(x.clone());
+    let logs = f
+        .iter()
+        .filter(|node| matches!(node.op, Operation::Log))
+        .map(|node| node.to_string())
+        .collect::<Vec<String>>();
+    println!("logarithms: {:?}", logs);
+    println!(
+        "operations: {}",
+        f.iter().filter(|node| !node.args.is_empty()).count()
+    );
+    println!(
+        "post-order: {}",
+        f.iter_post()
+            .map(|node| node.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+    println!();
+
+    // f(x) = x sin(x) e^x: expression swell over repeated derivatives
+    let x = var("x");
+    let mut f = x.clone() * sin(x.clone()) * exp(x.clone());
+    for order in 1..=3 {
+        f = f.diff("x");
+        let raw = f.stats();
+        let simplified = f.simplified().stats();
+        println!(
+            "order {}: {} nodes ({} distinct, depth {}), {} simplified",
+            order, raw.nodes, raw.distinct, raw.depth, simplified.nodes
+        );
+    }
+    println!("operations: {:?}", f.stats().operations);
+    println!();
+
+    // f(x, y) = sin(-x) + (y + y) + abs(x^4): rewrite rules added to the default ones
+    let input = "sin(-x) + (y + y) + abs(x^4)";
+    println!(
+        "{} default rules, e.g. {}",
+        default_rules().len(),
+        default_rules()[1]
+    );
+    println!("with the default rules: {}", parse(input).unwrap());
+    register_rule(Rule::new("a + a", "2*a").unwrap());
+    register_rule(Rule::new("sin(-a)", "-sin(a)").unwrap());
+    register_rule(
+        Rule::new("abs(a^k)", "a^k")
+            .unwrap()
+            .when(|b| matches!(b["k"].op, Operation::Const(k) if k % 2.0 == 0.0)),
+    );
+    println!("with user rules: {}", parse(input).unwrap());
+    println!(
+        "{}",
+        Rule::new("a + b", "2*c").map_or_else(|error| error.to_string(), |rule| rule.to_string())
+    );
+    set_rewrite_rules(default_rules());
+    println!();
+
+    // f(x) = x sin(x) e^x: equality saturation on its third derivative
+    let x = var("x");
+    let mut f = x.clone() * sin(x.clone()) * exp(x.clone());
+    let d3 = f.diff("x").diff("x").diff("x");
+    let saturated = d3.simplify_egraph(3);
+    println!(
+        "f''' from {} to {} nodes, equivalent: {}",
+        d3.stats().nodes,
+        saturated.stats().nodes,
+        saturated.equiv(&d3, 100)
+    );
+    println!("f''' = {}", saturated);
+    for input in [
+        "x*(y + 1) - x*y",
+        "2*(x + y) - 2*x",
+        "(x + 1)*(x + 1) - x*x",
+    ] {
+        let g = parse(input).unwrap();
+        println!("{} = {}", g, g.simplify_egraph(6));
+    }
+    println!();
+
+    // f(x) = (x * 1 + 0)^1 * exp(ln(x)): built as written, then simplified in one call
+    let config = simplify_config();
+    set_simplify_config(SimplifyConfig {
+        disabled: true,
+        ..config
+    });
+    let x = var("x");
+    let f = pow(x.clone() * 1.0 + 0.0, c(1.0)) * exp(ln(x.clone()));
+    set_simplify_config(config);
+    println!("f = {} = {}", f, f.simplified());
+    println!();
+
+    // f(x, y) = x * 3 * y + 2 + y * x + 1: sums and products are flattened and sorted, so the
+    // order operands are written in doesn't matter and constants meet to be folded
+    let (x, y) = (var("x"), var("y"));
+    println!("x * 3 == 3 * x: {}", x.clone() * 3.0 == 3.0 * x.clone());
+    let f = x.clone() * 3.0 * y.clone() + 2.0 + y.clone() * x.clone() + 1.0;
+    println!("f = {:?} = {}", f, f);
+    println!();
+
+    // f(x) = (x^2 + x) * x^3: like terms are collected, in f and in its derivatives
+    let x = var("x");
+    println!("x + x = {}", x.clone() + x.clone());
+    println!("3x + 5x = {}", 3.0 * x.clone() + 5.0 * x.clone());
+    println!("x * x = {}", x.clone() * x.clone());
+    let mut f = (powi(x.clone(), 2) + x.clone()) * powi(x.clone(), 3);
+    let df = f.diff("x");
+    println!("f = {}, df/dx = {}", f, df);
+    println!();
+
+    // Inverse pairs cancel: exp(ln(x)) = x (for x > 0), ln(exp(x)) = x, log_2(2^x) = x
+    let x = var("x");
+    let pairs = [
+        ("exp(ln(x))", exp(ln(x.clone()))),
+        ("ln(exp(x))", ln(exp(x.clone()))),
+        ("log_2(2^x)", log(c(2.0), pow(c(2.0), x.clone()))),
+    ];
+    set_simplify_config(SimplifyConfig {
+        disabled: true,
+        ..simplify_config()
+    });
+    let unsimplified = [
+        exp(ln(x.clone())),
+        ln(exp(x.clone())),
+        log(c(2.0), pow(c(2.0), x.clone())),
+    ];
+    set_simplify_config(SimplifyConfig {
+        disabled: false,
+        ..simplify_config()
+    });
+    for ((name, f), g) in pairs.iter().zip(&unsimplified) {
+        let values = [0.1, 1.0, 7.5].map(|x| g.evaluate(&HashMap::from([("x".to_string(), x)])));
+        println!(
+            "{} = {:?}, before simplifying at x = 0.1, 1, 7.5: {:?}",
+            name, f, values
+        );
+    }
+    let mut f = pow(x.clone(), x.clone());
+    println!("f = x^x");
+    println!("df/dx = {}", f.diff("x").to_infix());
+
+    // f(x) = sqrt(x^2 + 1) * exp(-x^2), with first-class exp and sqrt rather than powers
+    let mut f = sqrt(powi(x.clone(), 2) + 1.0) * exp(-powi(x.clone(), 2));
+    let mut g = pow(powi(x.clone(), 2) + 1.0, c(0.5)) * pow(e(), -powi(x.clone(), 2));
+    println!("f = {}", f.to_infix());
+    println!("df/dx = {}", f.diff("x").to_infix());
+    println!("written with powers: df/dx = {}", g.diff("x").to_infix());
+    println!();
+
+    // f(x) = k * x with the parameter k = 2.5
+    // df/dk = x
+    let mut f = param("k", 2.5) * var("x");
+    let df_dk = f.partial_derivative_param("k");
+    let df_dx = f.partial_derivative(&"x".to_string());
+    let at = HashMap::from([("x".to_string(), 4.0)]);
+    println!("f = {:?}", f);
+    println!("df/dk = {:?}", df_dk);
+    println!("df/dx = {:?}", df_dx);
+    println!("f(4) = {}", f.evaluate(&at));
+    println!();
+
+    // f(x, y) = x^2 * sin(y) + 3, evaluated over rows produced one at a time
+    let f = powi(var("x"), 2) * sin(var("y")) + 3.0;
+    let var_order = ["x".to_string(), "y".to_string()];
+    let rows = (0..5).map(|i| vec![i as f64, 0.5 * i as f64]);
+    let streamed = f
+        .evaluate_stream(&var_order, rows.clone())
+        .collect::<Vec<f64>>();
+    let one_by_one = rows
+        .map(|row| f.evaluate(&var_order.iter().cloned().zip(row).collect()))
+        .collect::<Vec<f64>>();
+    println!("f = {}", f.to_infix());
+    println!("streamed:   {:?}", streamed);
+    println!("one by one: {:?}", one_by_one);
+    // The same function over 100000 points, column by column and point by point
+    let xs = (0..100_000).map(|i| i as f64 * 1e-4).collect::<Vec<f64>>();
+    let ys = xs.iter().map(|x| 1.0 - x).collect::<Vec<f64>>();
+    let columns = HashMap::from([("x".to_string(), &xs[..]), ("y".to_string(), &ys[..])]);
+    let start = std::time::Instant::now();
+    let batch = f.evaluate_batch(&columns);
+    let batched = start.elapsed();
+    let start = std::time::Instant::now();
+    let pointwise = xs
+        .iter()
+        .zip(&ys)
+        .map(|(&x, &y)| f.evaluate(&HashMap::from([("x".to_string(), x), ("y".to_string(), y)])))
+        .collect::<Vec<f64>>();
+    let elapsed = start.elapsed();
+    println!(
+        "{} points, same as point by point: {}",
+        batch.len(),
+        batch == pointwise
+    );
+    println!("batch took {:?}, point by point {:?}", batched, elapsed);
+    println!();
+
+    // f(x, y) = (1 - x)^2 + 100(y - x^2)^2, gradient descent with variables bound to indices
+    let mut f = powi(1.0 - var("x"), 2) + 100.0 * powi(var("y") - powi(var("x"), 2), 2);
+    let gradient = [f.diff("x"), f.diff("y")];
+    let bound = gradient.each_ref().map(|g| g.bind(&["x", "y"]));
+    let steps = 20_000;
+    let start = std::time::Instant::now();
+    let mut point = [-1.0, 1.0];
+    for _ in 0..steps {
+        let g = bound.each_ref().map(|g| g.evaluate(&point));
+        point = [point[0] - 1e-3 * g[0], point[1] - 1e-3 * g[1]];
+    }
+    let with_indices = start.elapsed();
+    let start = std::time::Instant::now();
+    let mut named = [-1.0, 1.0];
+    for _ in 0..steps {
+        let at = HashMap::from([("x".to_string(), named[0]), ("y".to_string(), named[1])]);
+        let g = gradient.each_ref().map(|g| g.evaluate(&at));
+        named = [named[0] - 1e-3 * g[0], named[1] - 1e-3 * g[1]];
+    }
+    let with_names = start.elapsed();
+    println!("after {} steps: {:?} (by name {:?})", steps, point, named);
+    println!(
+        "bound took {:?}, looking up names {:?}",
+        with_indices, with_names
+    );
+    println!();
+
+    // f(x, y) = ln(x^2 + 1) * sin(y) + exp(-y) x^3, compiled to bytecode
+    let mut f = ln(powi(var("x"), 2) + 1.0) * sin(var("y")) + exp(-var("y")) * powi(var("x"), 3);
+    let program = f.compile_bytecode(&["x", "y"]);
+    let bound = f.bind(&["x", "y"]);
+    println!("f = {}", f.to_infix());
+    println!("{}", program.disassemble().replace('\n', "; "));
+    let points = (0..100_000)
+        .map(|i| [(i % 100) as f64 * 0.05, (i / 1000) as f64 * 0.1])
+        .collect::<Vec<[f64; 2]>>();
+    let start = std::time::Instant::now();
+    let compiled = points.iter().map(|x| program.run(x)).collect::<Vec<f64>>();
+    let by_program = start.elapsed();
+    let start = std::time::Instant::now();
+    let planned = points
+        .iter()
+        .map(|x| bound.evaluate(x))
+        .collect::<Vec<f64>>();
+    let by_plan = start.elapsed();
+    let start = std::time::Instant::now();
+    let walked = points
+        .iter()
+        .map(|&[x, y]| f.evaluate(&HashMap::from([("x".to_string(), x), ("y".to_string(), y)])))
+        .collect::<Vec<f64>>();
+    let by_tree = start.elapsed();
+    let max_difference = compiled
+        .iter()
+        .zip(&planned)
+        .zip(&walked)
+        .map(|((a, b), c)| (a - b).abs().max((a - c).abs()))
+        .fold(0.0, f64::max);
+    println!("largest difference from the tree: {}", max_difference);
+    println!(
+        "bytecode took {:?}, bound {:?}, tree walk {:?}",
+        by_program, by_plan, by_tree
+    );
+    println!();
+
+    // The same f compiled to a closure, its central differences against the symbolic gradient
+    let compiled = f.compile();
+    let x_0 = [1.3, 0.4];
+    let at = HashMap::from([("x".to_string(), x_0[0]), ("y".to_string(), x_0[1])]);
+    for (i, name) in f.variables().iter().enumerate() {
+        let numeric = numerical_partial(&compiled, &x_0, i);
+        let symbolic = f.diff(name).evaluate(&at);
+        println!(
+            "df/d{} = {} (central difference {})",
+            name, symbolic, numeric
+        );
+    }
+    println!();
+
+    // f(x, y) = x^2 sin(y) / (1 + e^-x) as Rust source, with its gradient
+    let g = powi(var("x"), 2) * sin(var("y")) / (1.0 + exp(-var("x")));
+    print!("{}", g.to_rust_fn_with_gradient("f"));
+    println!();
+
+    // The same g as C, where the gradient computes the repeated 1 + e^-x and sin(y) only once
+    print!("{}", codegen::c::function(&g, "f"));
+    print!("{}", codegen::c::gradient(&g, "f"));
+    println!();
+
+    // The same g as GLSL and WGSL shader functions, to draw it and its gradient on the GPU
+    print!("{}", codegen::glsl::function(&g, "f", &["x", "y"]).unwrap());
+    print!("{}", codegen::glsl::gradient(&g, "f", &["x", "y"]).unwrap());
+    print!("{}", codegen::wgsl::gradient(&g, "f", &["x", "y"]).unwrap());
+    match codegen::wgsl::function(&erf(var("x")), "f", &["x"]) {
+        Ok(code) => print!("{}", code),
+        Err(error) => println!("erf(x): {}", error),
+    }
+    println!();
+
+    // f(x, y) = ln(x^2 + 1) sin(y) + exp(-y) x^3 + tanh(xy) as native code, against bytecode
+    #[cfg(feature = "jit")]
+    {
+        let f = f + tanh(var("x") * var("y"));
+        let native = f.compile_jit().unwrap();
+        let interpreted = f.compile();
+        let points = (0..1_000_000)
+            .map(|i| [(i % 1000) as f64 * 0.005, (i / 1000) as f64 * 0.002])
+            .collect::<Vec<[f64; 2]>>();
+        let start = std::time::Instant::now();
+        let jitted = points.iter().map(|x| native(x)).collect::<Vec<f64>>();
+        let by_jit = start.elapsed();
+        let start = std::time::Instant::now();
+        let compiled = points.iter().map(|x| interpreted(x)).collect::<Vec<f64>>();
+        let by_bytecode = start.elapsed();
+        let max_difference = jitted
+            .iter()
+            .zip(&compiled)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f64::max);
+        println!("f = {}", f.to_infix());
+        println!("largest difference from bytecode: {}", max_difference);
+        println!("JIT took {:?}, bytecode {:?}", by_jit, by_bytecode);
+        println!();
+    }
+
+    // Named constants print symbolically and ln(e) = 1
+    println!("ln(e) = {:?}", ln(e()));
+    let f = pi() * powi(var("r"), 2);
+    println!("f = {:?} = {} = {}", f, f.to_infix(), f.to_latex());
+    println!("parsed back: {:?}", parse(&f.to_infix()).unwrap());
+    println!();
+
+    // f(x_1, x2, alpha) = alpha x_1^2 / (1 + x_1) + sqrt(x2), with its derivatives as LaTeX
+    let mut f = Node::parse("alpha * x_1^2 / (1 + x_1) + x2^0.5").unwrap();
+    println!("f = {}", f.to_latex());
+    for variable in ["x_1", "x2"] {
+        let derivative = f.diff(variable).simplified();
+        println!("df/d{} = {}", variable, derivative.to_latex());
+    }
+    // Subscripts come back as part of the name, so x2 is read back as x_2
+    println!(
+        "parsed back: {}",
+        from_latex(&f.to_latex()).unwrap().to_infix()
+    );
+    println!();
+
+    // f(x, theta) = \frac{x^2}{2} + \log_2(x) \sin^2(\theta_0 x), typed the way a write-up has it
+    let input = r"\frac{x^2}{2} + \log_2 x \cdot \sin^{2}\left(\theta_0 x\right) - \sqrt[3]{x}";
+    let mut f = Node::from_latex(input).unwrap();
+    println!("f = {}, df/dx = {}", f.to_infix(), f.diff("x").to_latex());
+    for input in [r"\log x", r"\frac{1}{x", r"\foo(x)"] {
+        if let Err(error) = from_latex(input) {
+            println!("{}: {}", input, error);
+        }
+    }
+    println!();
+
+    // f(x) = sin(x), stationary points in [0, 2π]
+    let mut f = sin(var("x"));
+    let bounds = HashMap::from([("x".to_string(), (0.0, 2.0 * std::f64::consts::PI))]);
+    let d2f_dx2 = f.diff("x").diff("x");
+    for point in critical_points(&mut f, &bounds, 20) {
+        let kind = if d2f_dx2.evaluate(&point) < 0.0 {
+            "maximum"
+        } else {
+            "minimum"
+        };
+        println!(
+            "{} at x = {} (f = {})",
+            kind,
+            point["x"],
+            f.evaluate(&point)
+        );
+    }
+    println!();
+
+    // f(x_0, ..., x_11) = x_0 + ... + x_11 with x_0 = 1e16, x_11 = -1e16 and ones in between
+    let values = std::iter::once(1e16)
+        .chain([1.0; 10])
+        .chain(std::iter::once(-1e16))
+        .collect::<Vec<f64>>();
+    let names = (0..values.len())
+        .map(|i| format!("x_{}", i))
+        .collect::<Vec<String>>();
+    let f = names
+        .iter()
+        .skip(1)
+        .fold(var(&names[0]), |sum, name| sum + var(name));
+    let at = names.iter().cloned().zip(values.iter().copied()).collect();
+    println!("f = {}", f.to_infix());
+    println!(
+        "f(1e16, 1, ..., 1, -1e16) = {} (naive summation {}, exact 10)",
+        f.evaluate_compensated(&at),
+        f.evaluate(&at)
+    );
+    println!();
+
+    // f(x, y) = 2x + 2y, with the common factor pulled out
+    let x = var("x");
+    let y = var("y");
+    let f = 2.0 * x.clone() + 2.0 * y.clone();
+    println!("f = {:?}", f);
+    println!("factored: {:?}", f.factor_common());
+    // f(x, y) = sin(x) * x + sin(x) * y
+    let f = sin(x.clone()) * x.clone() + sin(x.clone()) * y.clone();
+    println!("f = {}", f.to_infix());
+    println!("factored: {}", f.factor_common().to_infix());
+    println!();
+
+    // loss(w, b, v) = (Σ v_i tanh(w_i x + b_i) - y)^2 for a network with one hidden layer of
+    // three tanh units, at the sample x = 1, y = 0.5
+    let units = ["1", "2", "3"];
+    let output = units.iter().fold(c(0.0), |sum, i| {
+        let hidden = tanh(var(&format!("w{}", i)) * 1.0 + var(&format!("b{}", i)));
+        sum + var(&format!("v{}", i)) * hidden
+    });
+    let mut loss = powi(output - 0.5, 2);
+    let weights = HashMap::from(
+        [
+            ("w1", 0.5),
+            ("w2", -0.3),
+            ("w3", 0.8),
+            ("b1", 0.1),
+            ("b2", 0.0),
+            ("b3", -0.2),
+            ("v1", 1.0),
+            ("v2", -0.7),
+            ("v3", 0.4),
+        ]
+        .map(|(name, value)| (name.to_string(), value)),
+    );
+    let gradient = Tape::record(&loss, &weights).grad();
+    println!("loss = {}", loss.to_infix());
+    println!("loss = {}", loss.evaluate(&weights));
+    println!(
+        "dloss/dw1 = {} (symbolic {})",
+        gradient["w1"],
+        loss.diff("w1").evaluate(&weights)
+    );
+    println!();
+
+    // Activations as primitives stay finite where the composed formulas overflow, and their
+    // derivatives stay small
+    let x = var("x");
+    let mut composed = ln(1.0 + exp(x.clone()));
+    let mut primitive = softplus(x.clone());
+    let (d_composed, d_primitive) = (composed.diff("x"), primitive.diff("x"));
+    println!(
+        "d/dx {} = {} ({} flops), d/dx {} = {} ({} flops)",
+        primitive.to_infix(),
+        d_primitive.to_infix(),
+        d_primitive.flop_count(),
+        composed.to_infix(),
+        d_composed.to_infix(),
+        d_composed.flop_count()
+    );
+    for x in [-1000.0, 0.0, 1000.0] {
+        let at = HashMap::from([("x".to_string(), x)]);
+        println!(
+            "at x = {}: softplus {} (composed {}), derivative {} (composed {})",
+            x,
+            primitive.evaluate(&at),
+            composed.evaluate(&at),
+            d_primitive.evaluate(&at),
+            d_composed.evaluate(&at)
+        );
+    }
+    let mut f = relu(x.clone() - 1.0);
+    println!("d/dx {} = {}", f.to_infix(), f.diff("x").to_infix());
+    println!();
+
+    // f(x_0, ..., x_49) = Σ x_i^2 sin(x_(i+1)), one output of many inputs: a single reverse
+    // sweep gives the whole gradient, where symbolic differentiation runs once per variable
+    let n = 50;
+    let names = (0..n).map(|i| format!("x_{}", i)).collect::<Vec<String>>();
+    let mut f = (0..n - 1).fold(c(0.0), |sum, i| {
+        sum + powi(var(&names[i]), 2) * sin(var(&names[i + 1]))
+    });
+    let at = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (name.clone(), 0.1 * i as f64))
+        .collect::<HashMap<String, f64>>();
+    let start = std::time::Instant::now();
+    let reverse = Tape::record(&f, &at).grad();
+    let reverse_time = start.elapsed();
+    let start = std::time::Instant::now();
+    let symbolic = names
+        .iter()
+        .map(|name| (name.clone(), f.partial_derivative(name).evaluate(&at)))
+        .collect::<HashMap<String, f64>>();
+    let symbolic_time = start.elapsed();
+    let start = std::time::Instant::now();
+    let gradient = f
+        .gradient()
+        .into_iter()
+        .map(|(name, derivative)| (name, derivative.evaluate(&at)))
+        .collect::<HashMap<String, f64>>();
+    let gradient_time = start.elapsed();
+    let max_difference = names
+        .iter()
+        .map(|name| (reverse[name] - symbolic[name]).abs())
+        .chain(
+            names
+                .iter()
+                .map(|name| (gradient[name] - symbolic[name]).abs()),
+        )
+        .fold(0.0, f64::max);
+    println!(
+        "gradient of {} inputs: reverse mode {:?}, per-variable derivatives {:?}, gradient() {:?}, largest difference {}",
+        n, reverse_time, symbolic_time, gradient_time, max_difference
+    );
+    println!();
+
+    // f(x_0, ..., x_(n-1)) = Σ x_i^2 x_(i+1) + sin(x_0 x_1): Hessian-vector products without the
+    // Hessian, checked against the full symbolic Hessian for n = 6 and timed for n = 2000
+    let chain = |n: usize| {
+        let names = (0..n).map(|i| format!("x_{}", i)).collect::<Vec<String>>();
+        let f = (0..n - 1).fold(sin(var(&names[0]) * var(&names[1])), |sum, i| {
+            sum + powi(var(&names[i]), 2) * var(&names[i + 1])
+        });
+        (f, names)
     };
-}
+    let (mut f, names) = chain(6);
+    let x = (0..6).map(|i| 0.3 * i as f64 - 0.5).collect::<Vec<f64>>();
+    let v = (0..6).map(|i| 1.0 / (i + 1) as f64).collect::<Vec<f64>>();
+    let at = names.iter().cloned().zip(x.iter().copied()).collect();
+    let full = names
+        .iter()
+        .map(|row| {
+            let mut d_row = f.diff(row);
+            names
+                .iter()
+                .zip(&v)
+                .map(|(column, v)| d_row.diff(column).evaluate(&at) * v)
+                .sum::<f64>()
+        })
+        .collect::<Vec<f64>>();
+    let product = hvp(&f, &names, &x, &v);
+    println!("H·v = {:?}", product);
+    println!("full Hessian times v = {:?}", full);
+    let (f, names) = chain(2000);
+    let x = vec![0.5; 2000];
+    let v = vec![1.0; 2000];
+    let start = std::time::Instant::now();
+    let product = hvp(&f, &names, &x, &v);
+    println!(
+        "H·v for 2000 variables in {:?}, first entries {:?}",
+        start.elapsed(),
+        &product[..3]
+    );
+    println!();
+
+    // f(x, y) = [xy, sin(x) + y^2, exp(x - y)]: J·t and uᵀJ without the Jacobian, against the
+    // Jacobian of partial derivatives
+    let (x, y) = (var("x"), var("y"));
+    let mut f = [
+        x.clone() * y.clone(),
+        sin(x.clone()) + powi(y.clone(), 2),
+        exp(x - y),
+    ];
+    let names = ["x".to_string(), "y".to_string()];
+    let point = [0.5, -1.5];
+    let at = names.iter().cloned().zip(point).collect();
+    let jacobian = f
+        .iter_mut()
+        .map(|f| {
+            names
+                .iter()
+                .map(|name| f.diff(name).evaluate(&at))
+                .collect()
+        })
+        .collect::<Vec<Vec<f64>>>();
+    let (t, u) = ([2.0, -1.0], [1.0, 0.5, -3.0]);
+    let jt = jacobian
+        .iter()
+        .map(|row| row.iter().zip(t).map(|(j, t)| j * t).sum())
+        .collect::<Vec<f64>>();
+    let utj = (0..2)
+        .map(|i| jacobian.iter().zip(u).map(|(row, u)| u * row[i]).sum())
+        .collect::<Vec<f64>>();
+    println!(
+        "J·t = {:?} (Jacobian {:?})",
+        jvp(&f, &names, &point, &t),
+        jt
+    );
+    println!(
+        "uᵀJ = {:?} (Jacobian {:?})",
+        vjp(&f, &names, &point, &u),
+        utj
+    );
+    let symbolic = vjp_symbolic(&f, &names, &u)
+        .iter()
+        .map(|d| d.to_infix())
+        .collect::<Vec<String>>();
+    println!("uᵀJ = {:?}", symbolic);
+    println!();
+
+    // f(x) = x / x, cancelled when the tree is built (valid for x != 0)
+    let x = var("x");
+    let f = x.clone() / x.clone();
+    println!("f = x / x = {:?}", f);
+    // f(x) = sin(x) / cos(x); the cos(x) / cos(x) term of the quotient rule cancels
+    let mut f = sin(x.clone()) / cos(x.clone());
+    let df_dx = f.diff("x");
+    println!("f = {}", f.to_infix());
+    println!(
+        "df/dx = {} ({} flops)",
+        df_dx.to_infix(),
+        df_dx.flop_count()
+    );
+    // f(x) = tan(x) as a primitive has the short derivative 1 / cos(x)^2
+    let mut f = tan(x.clone());
+    let df_dx = f.diff("x");
+    println!("f = {}", f.to_infix());
+    println!(
+        "df/dx = {} ({} flops)",
+        df_dx.to_infix(),
+        df_dx.flop_count()
+    );
+    // f(x) = asin(x) + acos(x) = π/2, so df/dx = 0
+    let mut f = asin(x.clone()) + acos(x.clone());
+    let at = HashMap::from([("x".to_string(), 0.3)]);
+    println!(
+        "f = {}, f(0.3) = {}, df/dx(0.3) = {}",
+        f.to_infix(),
+        f.evaluate(&at),
+        f.diff("x").evaluate(&at)
+    );
+    println!();
 
-impl_op!(Add, add, Operation::Add);
-impl_op!(Mul, mul, Operation::Mul);
+    // f(x, y, z) = Σ sin(k x y) + log_2(k + z^2) + atan2(y, k + x), reverse mode from a recorded tape
+    let x = var("x");
+    let y = var("y");
+    let z = var("z");
+    let mut f = c(0.0);
+    for k in 1..=20 {
+        let k = k as f64;
+        f = f
+            + sin(k * x.clone() * y.clone())
+            + log(c(2.0), k + powi(z.clone(), 2))
+            + atan2(y.clone(), k + x.clone());
+    }
+    let at = HashMap::from([
+        ("x".to_string(), 0.3),
+        ("y".to_string(), -1.2),
+        ("z".to_string(), 2.0),
+    ]);
+    let tape = Tape::record(&f, &at);
+    let gradient = tape.grad();
+    println!(
+        "f(0.3, -1.2, 2) = {} (tape {})",
+        f.evaluate(&at),
+        tape.value()
+    );
+    for name in f.variables() {
+        println!(
+            "df/d{} = {} (symbolic {})",
+            name,
+            gradient[&name],
+            f.diff(&name).evaluate(&at)
+        );
+    }
+    let queries = 10;
+    let start = std::time::Instant::now();
+    for _ in 0..queries {
+        std::hint::black_box(tape.grad());
+    }
+    let reused = start.elapsed();
+    let start = std::time::Instant::now();
+    for _ in 0..queries {
+        std::hint::black_box(Tape::record(&f, &at).grad());
+    }
+    let recomputed = start.elapsed();
+    println!(
+        "{} gradients: {:?} reusing the tape, {:?} recording it each time",
+        queries, reused, recomputed
+    );
+    println!();
+}
@@ -0,0 +1,52 @@
+use std::process::{Command, Output};
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_forwardAutoDiff"))
+        .args(args)
+        .output()
+        .expect("the binary runs")
+}
+
+fn stdout(args: &[&str]) -> String {
+    let output = run(args);
+    assert!(output.status.success(), "{:?} failed: {:?}", args, output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn derivative_and_its_value() {
+    let output = stdout(&["3*x + 4*y", "--diff", "x", "--at", "x=1,y=2"]);
+    assert_eq!(output, "df/dx = 3\ndf/dx(x=1, y=2) = 3\n");
+}
+
+#[test]
+fn expression_without_diff() {
+    let output = stdout(&["3*x*y + 5", "--at", "x=1,y=2"]);
+    assert_eq!(output.lines().last(), Some("f(x=1, y=2) = 11"));
+}
+
+#[test]
+fn latex_output() {
+    let output = stdout(&["sin(x)^2", "--diff", "x", "--latex"]);
+    assert!(output.contains(r"\sin"), "{}", output);
+    assert!(output.contains(r"\cos"), "{}", output);
+}
+
+#[test]
+fn simplification_toggles() {
+    let raw = stdout(&["x*1 + 0", "--no-simplify"]);
+    let simplified = stdout(&["x*1 + 0"]);
+    assert_eq!(simplified, "f = x\n");
+    assert_ne!(raw, simplified);
+}
+
+#[test]
+fn invalid_input_fails() {
+    let output = run(&["3*x + (4"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("parse error"));
+    assert!(!run(&["x", "--at", "x1"]).status.success());
+    let output = run(&["x+y", "--at", "x=1"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Variable y not found"));
+}
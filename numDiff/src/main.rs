@@ -1,10 +1,13 @@
+use forward_auto_diff::{Dual, Scalar, forward_derivative, grad};
+use std::ops::{Add, Div, Mul, Sub};
+
 fn main() {
     // f(x) = x^3
     // f'(x) = 3x^2
     // f'(6) = 3*6^2 = 108
     print!("f(x) = x^3");
     let f = |x: &[f64]| x.iter().map(|&i| i.powi(3)).collect();
-    let derivative = numerical_derivative(&f, &[6.0], None);
+    let derivative = numerical_derivative(&f, &[6.0], None, FiniteDiffMode::Forward);
     test(derivative, &[&[108.0]]);
 
     // f(x) = x^3 + 4x^2 - 12
@@ -16,7 +19,7 @@ fn main() {
             .map(|&i| i.powi(3) + 4.0 * i.powi(2) - 12.0)
             .collect()
     };
-    let derivative = numerical_derivative(&f, &[2.0], Some(1e-5));
+    let derivative = numerical_derivative(&f, &[2.0], Some(1e-5), FiniteDiffMode::Forward);
     test(derivative, &[&[28.0]]);
 
     // f([x, y]) = [sin(x) + cos(y), cos(x) - sin(y)]
@@ -25,8 +28,14 @@ fn main() {
     print!("f([x, y]) = [sin(x) + cos(y), cos(x) - sin(y)]");
     let f = |x: &[f64]| vec![x[0].sin() + x[1].cos(), x[0].cos() - x[1].sin()];
     let v = [std::f64::consts::FRAC_PI_4, std::f64::consts::FRAC_PI_3];
-    let derivative = numerical_derivative(&f, &v, None);
-    let expected: &[&[f64]] = &[&[0.7071, -0.7071], &[-0.8660, -0.5]];
+    let derivative = numerical_derivative(&f, &v, None, FiniteDiffMode::Forward);
+    let expected: &[&[f64]] = &[
+        &[
+            std::f64::consts::FRAC_1_SQRT_2,
+            -std::f64::consts::FRAC_1_SQRT_2,
+        ],
+        &[-(3f64.sqrt()) / 2.0, -0.5],
+    ];
     test(derivative, expected);
 
     // f([x, y, z]) = [x^2 + y^2 + z^2, x + y + z]
@@ -40,7 +49,7 @@ fn main() {
         ]
     };
     let v = [1.0, 2.0, 3.0];
-    let derivative = numerical_derivative(&f, &v, None);
+    let derivative = numerical_derivative(&f, &v, None, FiniteDiffMode::Forward);
     let expected: &[&[f64]] = &[&[2.0, 1.0], &[4.0, 1.0], &[6.0, 1.0]];
     test(derivative, expected);
 
@@ -63,51 +72,885 @@ fn main() {
         2.0,
         3.0,
     ];
-    let derivative = numerical_derivative(&f, &v, None);
+    let derivative = numerical_derivative(&f, &v, None, FiniteDiffMode::Forward);
     let expected: &[&[f64]] = &[
         &[3.84391697914949, 41.5692193816531, 1.0, 0.0, 0.0],
         &[4.18879020478639, 75.398223686155, 0.0, 0.0, 0.0],
         &[1.09662271123215, 48.9725828343239, 0.0, 0.0, -5.0],
         &[0.578468789354558, 10.8827961854053, 0.0, 0.0, 0.0],
     ];
-    test(derivative, expected);
+    test(derivative.clone(), expected);
+    let errors = relative_jacobian_error(&derivative, expected);
+    let worst_per_output = (0..expected[0].len())
+        .map(|j| errors.iter().map(|row| row[j]).fold(0.0, f64::max))
+        .collect::<Vec<f64>>();
+    println!("  worst relative error per output: {:?}", worst_per_output);
+
+    // f(x) = x^3 with each stencil
+    // f'(6) = 108
+    for mode in [
+        FiniteDiffMode::Forward,
+        FiniteDiffMode::Backward,
+        FiniteDiffMode::Central,
+    ] {
+        print!("f(x) = x^3 ({:?})", mode);
+        let f = |x: &[f64]| x.iter().map(|&i| i.powi(3)).collect();
+        let derivative = numerical_derivative(&f, &[6.0], None, mode);
+        test(derivative, &[&[108.0]]);
+    }
+
+    // f([x, y]) = 1e-6 sin(1e6 x) cos(y / 1e3) with x in microns and y in kilometers
+    // f'(1e-6, 1e3) = [cos(1)^2, -1e-9 sin(1)^2], compared as ratios to the exact values
+    print!("f([x, y]) on coordinates 1e9 apart (per-variable steps)");
+    let f = |x: &[f64]| vec![1e-6 * (1e6 * x[0]).sin() * (x[1] / 1e3).cos()];
+    let exact = [1f64.cos().powi(2), -1e-9 * 1f64.sin().powi(2)];
+    let x = [1e-6, 1e3];
+    let derivative =
+        numerical_derivative_scaled(&f, &x, &[Some(1e-14), None], FiniteDiffMode::Central);
+    let ratios = derivative
+        .iter()
+        .zip(exact)
+        .map(|(row, exact)| vec![row[0] / exact])
+        .collect();
+    test(ratios, &[&[1.0], &[1.0]]);
+    let global = numerical_derivative(&f, &x, Some(1e-14), FiniteDiffMode::Central);
+    println!(
+        "  a global step of 1e-14 is lost next to y = 1e3: {:?}",
+        global
+    );
+
+    // f(x) = x^3, only defined for x <= 1
+    // f'(1) = 3
+    print!("f(x) = x^3 on (-inf, 1] (Backward)");
+    let f = |x: &[f64]| {
+        x.iter()
+            .map(|&i| if i > 1.0 { f64::NAN } else { i.powi(3) })
+            .collect()
+    };
+    let derivative = numerical_derivative(&f, &[1.0], Some(1e-6), FiniteDiffMode::Backward);
+    test(derivative, &[&[3.0]]);
+    let forward = numerical_derivative(&f, &[1.0], Some(1e-6), FiniteDiffMode::Forward);
+    println!("  forward difference leaves the domain: {:?}", forward);
+
+    // f(x) = x^2 - 2
+    // root: sqrt(2)
+    print!("secant root of x^2 - 2");
+    let f = |x: f64| x.powi(2) - 2.0;
+    let root = secant_root(&f, 1.0, 2.0, 1e-12, 50).unwrap();
+    test(vec![vec![root]], &[&[std::f64::consts::SQRT_2]]);
+
+    // f(-1) = f(1), so the first secant is horizontal
+    print!("secant root of x^2 - 2 from a symmetric start");
+    match secant_root(&f, -1.0, 1.0, 1e-12, 50) {
+        Err(e) => println!(" OK 👍 ({})", e),
+        Ok(root) => println!(" FAIL ❌ (expected an error, got {})", root),
+    }
+
+    // f([x, y]) = x^2y + sin(x)
+    // f''([x, y]) = [[2y - sin(x), 2x], [2x, 0]]
+    // f''([1, 2]) = [[4 - sin(1), 2], [2, 0]]
+    print!("hyper-dual Hessian of x^2y + sin(x)");
+    let f = |x: &[HyperDual]| x[0] * x[0] * x[1] + x[0].sin();
+    let hessian = hyperdual_hessian(&f, &[1.0, 2.0]);
+    test(hessian.clone(), &[&[4.0 - 1f64.sin(), 2.0], &[2.0, 0.0]]);
+    let f = |x: &[f64]| vec![x[0].powi(2) * x[1] + x[0].sin()];
+    let gradient = |x: &[f64]| {
+        numerical_derivative(&f, x, Some(1e-5), FiniteDiffMode::Central)
+            .iter()
+            .map(|column| column[0])
+            .collect()
+    };
+    let numerical =
+        numerical_derivative(&gradient, &[1.0, 2.0], Some(1e-4), FiniteDiffMode::Central);
+    println!("  hyper-dual: {:?}", hessian);
+    println!("  numerical:  {:?}", numerical);
+
+    // f([x, y]) = exp(x)ln(y) + cos(x)y^3 - x/y
+    // f''([x, y]) = [[exp(x)ln(y) - cos(x)y^3, exp(x)/y - 3sin(x)y^2 + 1/y^2],
+    //                [exp(x)/y - 3sin(x)y^2 + 1/y^2, -exp(x)/y^2 + 6cos(x)y - 2x/y^3]]
+    print!("hyper-dual Hessian of exp(x)ln(y) + cos(x)y^3 - x/y");
+    let (x, y) = (0.5f64, 2.0f64);
+    let f = |x: &[HyperDual]| x[0].exp() * x[1].ln() + x[0].cos() * x[1].powi(3) - x[0] / x[1];
+    let mixed = x.exp() / y - 3.0 * x.sin() * y.powi(2) + 1.0 / y.powi(2);
+    let exact: &[&[f64]] = &[
+        &[x.exp() * y.ln() - x.cos() * y.powi(3), mixed],
+        &[
+            mixed,
+            -x.exp() / y.powi(2) + 6.0 * x.cos() * y - 2.0 * x / y.powi(3),
+        ],
+    ];
+    test(hyperdual_hessian(&f, &[x, y]), exact);
 
-    println!("All tests passed 🎉");
+    // f([x, y]) = [sin(x) + cos(y), cos(x) - sin(y)] in forward mode, compared to the exact
+    // f'([π/4, π/3]) = [[√2/2, -√2/2], [-√3/2, -1/2]]
+    print!("dual numbers on [sin(x) + cos(y), cos(x) - sin(y)]");
+    let f = |x: &[Dual<f64>]| vec![x[0].sin() + x[1].cos(), x[0].cos() - x[1].sin()];
+    let v = [std::f64::consts::FRAC_PI_4, std::f64::consts::FRAC_PI_3];
+    let derivative = vec![forward_derivative(f, &v, 0), forward_derivative(f, &v, 1)];
+    let exact: &[&[f64]] = &[
+        &[
+            std::f64::consts::FRAC_1_SQRT_2,
+            -std::f64::consts::FRAC_1_SQRT_2,
+        ],
+        &[-(3f64.sqrt()) / 2.0, -0.5],
+    ];
+    test(derivative, exact);
+    let f = |x: &[f64]| vec![x[0].sin() + x[1].cos(), x[0].cos() - x[1].sin()];
+    print!("finite differences on the same function");
+    test(
+        numerical_derivative(&f, &v, None, FiniteDiffMode::Forward),
+        exact,
+    );
+
+    // f([x, y]) = x^y + y ln(x)
+    // ∇f([x, y]) = [yx^(y-1) + y/x, x^y ln(x) + ln(x)]
+    // ∇f([2, 3]) = [13.5, 9 ln(2)]
+    print!("dual-number gradient of x^y + y ln(x)");
+    let f = |x: &[Dual<f64>]| x[0].powf(x[1]) + x[1] * x[0].ln();
+    test(vec![grad(f, &[2.0, 3.0])], &[&[13.5, 9.0 * 2f64.ln()]]);
+
+    // f(x) = x^3 without choosing h
+    // f'(6) = 108
+    print!("f(x) = x^3 (adaptive step)");
+    let f = |x: &[f64]| x.iter().map(|&i| i.powi(3)).collect();
+    let derivative = adaptive_derivative(&f, &[6.0]);
+    test(derivative, &[&[108.0]]);
+
+    // f([x, y]) = [x + y, x + (1 + ε)y]
+    // f' = [[1, 1], [1, 1 + ε]] with singular values σ = sqrt(λ) for the eigenvalues λ of f'ᵀf'
+    print!("condition number of a nearly singular Jacobian");
+    let epsilon = 1e-4;
+    let f = |x: &[f64]| vec![x[0] + x[1], x[0] + (1.0 + epsilon) * x[1]];
+    let condition = jacobian_condition_number(&f, &[1.0, 2.0], None).unwrap();
+    let trace = 2.0 + 1.0 + (1.0 + epsilon).powi(2);
+    let det = epsilon.powi(2);
+    let disc = (trace.powi(2) / 4.0 - det).sqrt();
+    let exact = ((trace / 2.0 + disc) / (trace / 2.0 - disc)).sqrt();
+    test(vec![vec![condition / exact]], &[&[1.0]]);
+    print!("condition number of a singular Jacobian");
+    let f = |x: &[f64]| vec![x[0] + x[1], 2.0 * x[0] + 2.0 * x[1]];
+    let condition = jacobian_condition_number(&f, &[1.0, 2.0], None).unwrap();
+    if condition.is_infinite() {
+        println!(" OK 👍 ({})", condition);
+    } else {
+        println!(
+            " FAIL ❌ (expected an infinite condition number, got {})",
+            condition
+        );
+    }
+
+    // A derivative around 1e6 is judged relative to its size, but a sign error is not forgiven
+    print!("close() on a 1e6-scale derivative");
+    let (abs_tol, rel_tol) = (1e-8, 1e-5);
+    if close(1e6 + 1.0, 1e6, abs_tol, rel_tol) && !close(-1e6, 1e6, abs_tol, rel_tol) {
+        println!(" OK 👍 (off by 1.0 accepted, sign error rejected)");
+    } else {
+        println!(" FAIL ❌");
+    }
 }
 
-/// Pretty print test results
+/// Pretty print how close a result is to the expected one; the checks themselves are the
+/// `#[test]`s below
 fn test(actual: Vec<Vec<f64>>, expected: &[&[f64]]) {
-    let mut err = 0.0;
-    for (a, e) in actual.iter().zip(expected.iter()) {
-        for (a, e) in a.iter().zip(e.iter()) {
-            // assert!((a - e).abs() < 1e-3);
-            if (a - e).abs() > 1e-3 {
-                println!(" FAIL ❌");
-                println!(" expected: {}, got: {}", e, a);
-                std::process::exit(1);
-            }
-            err += (a - e).abs();
-        }
+    if let Some(report) = jacobian_mismatch(&actual, expected, 1e-3, 1e-3) {
+        println!(" FAIL ❌");
+        println!("{}", report);
+        return;
     }
+    let err = relative_jacobian_error(&actual, expected)
+        .iter()
+        .flatten()
+        .sum::<f64>();
     println!(" OK 👍 ({})", err);
 }
 
+/// Panic with a row/column report if any entry differs from the expected one by more than
+/// `tol + tol * |expected|`, i.e. `tol` is absolute near zero and relative for large entries
+#[cfg(test)]
+fn assert_jacobian_eq(actual: &[Vec<f64>], expected: &[&[f64]], tol: f64) {
+    if let Some(report) = jacobian_mismatch(actual, expected, tol, tol) {
+        panic!("{}", report);
+    }
+}
+
+/// Describe how two Jacobians differ, pointing out the worst entry, or `None` if they match
+fn jacobian_mismatch(
+    actual: &[Vec<f64>],
+    expected: &[&[f64]],
+    abs_tol: f64,
+    rel_tol: f64,
+) -> Option<String> {
+    let shape = |rows: Vec<usize>| format!("{} rows of lengths {:?}", rows.len(), rows);
+    let actual_shape = actual.iter().map(|row| row.len()).collect::<Vec<usize>>();
+    let expected_shape = expected.iter().map(|row| row.len()).collect::<Vec<usize>>();
+    if actual_shape != expected_shape {
+        return Some(format!(
+            "shape mismatch: expected {}, got {}",
+            shape(expected_shape),
+            shape(actual_shape)
+        ));
+    }
+    // (row, column, error in units of the allowed error) of every entry that isn't close;
+    // NaN never matches
+    let mismatches = actual
+        .iter()
+        .zip(expected.iter())
+        .enumerate()
+        .flat_map(|(i, (a, e))| {
+            a.iter()
+                .zip(e.iter())
+                .enumerate()
+                .filter_map(move |(j, (a, e))| {
+                    let allowed = abs_tol + rel_tol * e.abs();
+                    (!close(*a, *e, abs_tol, rel_tol)).then(|| (i, j, (a - e).abs() / allowed))
+                })
+        })
+        .collect::<Vec<(usize, usize, f64)>>();
+    let (i, j, _) = *mismatches.iter().max_by(|a, b| a.2.total_cmp(&b.2))?;
+    let rows = actual
+        .iter()
+        .zip(expected.iter())
+        .enumerate()
+        .map(|(r, (a, e))| {
+            let marker = if mismatches.iter().any(|m| m.0 == r) {
+                ">"
+            } else {
+                " "
+            };
+            format!("{} [{}] got {:?}, expected {:?}", marker, r, a, e)
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    let (a, e) = (actual[i][j], expected[i][j]);
+    Some(format!(
+        "{} entries differ by more than {} + {} * |expected|; worst at [{}][{}]: expected {}, got {} \
+         (absolute error {}, relative error {})\n{}",
+        mismatches.len(),
+        abs_tol,
+        rel_tol,
+        i,
+        j,
+        e,
+        a,
+        (a - e).abs(),
+        (a - e).abs() / e.abs(),
+        rows
+    ))
+}
+
+/// Whether `a` matches the expected `e` within `abs_tol + rel_tol * |e|`, which is an absolute
+/// tolerance near zero and a relative one for large values. NaN is never close.
+fn close(a: f64, e: f64, abs_tol: f64, rel_tol: f64) -> bool {
+    (a - e).abs() <= abs_tol + rel_tol * e.abs()
+}
+
+/// Error of each entry relative to the reference entry, or the absolute error where the
+/// reference is smaller than 1 in magnitude, so that small and large outputs are judged alike
+fn relative_jacobian_error(actual: &[Vec<f64>], reference: &[&[f64]]) -> Vec<Vec<f64>> {
+    actual
+        .iter()
+        .zip(reference.iter())
+        .map(|(a, r)| {
+            a.iter()
+                .zip(r.iter())
+                .map(|(a, r)| (a - r).abs() / r.abs().max(1.0))
+                .collect()
+        })
+        .collect()
+}
+
+/// Finite difference stencil
+#[derive(Debug, Clone, Copy, Default)]
+enum FiniteDiffMode {
+    /// (f(x + h) - f(x)) / h
+    #[default]
+    Forward,
+    /// (f(x) - f(x - h)) / h, for points near an upper domain boundary
+    Backward,
+    /// (f(x + h) - f(x - h)) / 2h, second order accurate
+    Central,
+}
+
+/// Jacobian of `f` at `x` by finite differences, as `jacobian[input][output]`. Without `h`,
+/// each coordinate gets the default step of `numerical_derivative_scaled`.
 fn numerical_derivative(
     f: &dyn Fn(&[f64]) -> Vec<f64>,
     x: &[f64],
     h: Option<f64>,
+    mode: FiniteDiffMode,
+) -> Vec<Vec<f64>> {
+    numerical_derivative_scaled(f, x, &vec![h; x.len()], mode)
+}
+
+/// Like `numerical_derivative`, but with a separate step for each coordinate, for inputs
+/// on very different scales. A missing step defaults to `sqrt(EPSILON) * max(|x_i|, 1)`.
+fn numerical_derivative_scaled(
+    f: &dyn Fn(&[f64]) -> Vec<f64>,
+    x: &[f64],
+    h_per_dim: &[Option<f64>],
+    mode: FiniteDiffMode,
 ) -> Vec<Vec<f64>> {
     let f_x = f(x);
     let mut jacobian = vec![vec![0.0; 0]; x.len()];
     for i in 0..x.len() {
-        let h = h.unwrap_or(f64::sqrt(f64::EPSILON) * x[i]);
-        let mut x_h = x.to_vec();
-        x_h[i] += h;
-        jacobian[i] = f(&x_h)
+        let h = h_per_dim[i].unwrap_or(f64::sqrt(f64::EPSILON) * x[i].abs().max(1.0));
+        let step = |delta: f64| {
+            let mut x_h = x.to_vec();
+            x_h[i] += delta;
+            f(&x_h)
+        };
+        let (upper, lower, width) = match mode {
+            FiniteDiffMode::Forward => (step(h), f_x.clone(), h),
+            FiniteDiffMode::Backward => (f_x.clone(), step(-h), h),
+            FiniteDiffMode::Central => (step(h), step(-h), 2.0 * h),
+        };
+        jacobian[i] = upper
             .iter()
-            .zip(f_x.iter())
-            .map(|(a, b)| (a - b) / h)
+            .zip(lower.iter())
+            .map(|(a, b)| (a - b) / width)
             .collect();
     }
     jacobian
 }
+
+/// Central differences with the step chosen per entry: shrink `h` geometrically from
+/// `0.1 * max(|x_i|, 1)` to `1e-8 * max(|x_i|, 1)` and keep the estimate that changed least
+/// from the previous step, which balances truncation error (large `h`) against rounding
+/// error (small `h`)
+fn adaptive_derivative(f: &dyn Fn(&[f64]) -> Vec<f64>, x: &[f64]) -> Vec<Vec<f64>> {
+    (0..x.len())
+        .map(|i| {
+            let scale = x[i].abs().max(1.0);
+            let step = |delta: f64| {
+                let mut x_h = x.to_vec();
+                x_h[i] += delta;
+                f(&x_h)
+            };
+            let mut h = 0.1 * scale;
+            let mut previous: Option<Vec<f64>> = None;
+            // (estimate, estimated error) per output
+            let mut best: Vec<(f64, f64)> = vec![];
+            while h > 1e-8 * scale {
+                let column = step(h)
+                    .iter()
+                    .zip(step(-h))
+                    .map(|(a, b)| (a - b) / (2.0 * h))
+                    .collect::<Vec<f64>>();
+                if let Some(previous) = &previous {
+                    best.resize(column.len(), (0.0, f64::INFINITY));
+                    for (j, (estimate, before)) in column.iter().zip(previous).enumerate() {
+                        let error = (estimate - before).abs();
+                        if error < best[j].1 {
+                            best[j] = (*estimate, error);
+                        }
+                    }
+                }
+                previous = Some(column);
+                h /= std::f64::consts::SQRT_2;
+            }
+            best.into_iter().map(|(estimate, _)| estimate).collect()
+        })
+        .collect()
+}
+
+/// Estimate the 2-norm condition number `σ_max / σ_min` of the Jacobian of `f` at `x` from its
+/// singular values. These come from one-sided Jacobi rotations of the Jacobian itself, so unlike
+/// the eigenvalues of `JᵀJ` they are not squared and `κ` up to about `1 / EPSILON` is resolved.
+/// Singular values below `max(outputs, inputs) * EPSILON * σ_max` count as zero, so a rank deficient
+/// Jacobian gives infinity. The finite differences are only accurate to about `sqrt(EPSILON)`
+/// with the default step, which also bounds the accuracy of a large `κ`.
+fn jacobian_condition_number(
+    f: &dyn Fn(&[f64]) -> Vec<f64>,
+    x: &[f64],
+    h: Option<f64>,
+) -> Result<f64, ConditionError> {
+    if x.is_empty() {
+        return Err(ConditionError::NoInputs);
+    }
+    let jacobian = numerical_derivative(f, x, h, FiniteDiffMode::Central);
+    if jacobian[0].is_empty() {
+        return Err(ConditionError::NoOutputs);
+    }
+    // The rows or the columns of J, whichever are fewer, so that there is one vector per
+    // nonzero singular value
+    let mut vectors = if jacobian.len() <= jacobian[0].len() {
+        jacobian.clone()
+    } else {
+        (0..jacobian[0].len())
+            .map(|j| jacobian.iter().map(|row| row[j]).collect())
+            .collect()
+    };
+    let dot = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>();
+    // Rotate pairs of vectors until all are orthogonal; their norms are then the singular values
+    for _ in 0..100 {
+        let mut rotated = false;
+        for p in 0..vectors.len() {
+            for q in p + 1..vectors.len() {
+                let (alpha, beta) = (dot(&vectors[p], &vectors[p]), dot(&vectors[q], &vectors[q]));
+                let gamma = dot(&vectors[p], &vectors[q]);
+                if gamma.abs() <= f64::EPSILON * (alpha * beta).sqrt() {
+                    continue;
+                }
+                rotated = true;
+                let zeta = (beta - alpha) / (2.0 * gamma);
+                let t = zeta.signum() / (zeta.abs() + (1.0 + zeta * zeta).sqrt());
+                let cos = 1.0 / (1.0 + t * t).sqrt();
+                let sin = cos * t;
+                for k in 0..vectors[p].len() {
+                    let (a, b) = (vectors[p][k], vectors[q][k]);
+                    vectors[p][k] = cos * a - sin * b;
+                    vectors[q][k] = sin * a + cos * b;
+                }
+            }
+        }
+        if !rotated {
+            break;
+        }
+    }
+    let singular_values = vectors.iter().map(|v| dot(v, v).sqrt());
+    let largest = singular_values.clone().fold(0.0, f64::max);
+    let smallest = singular_values.fold(f64::INFINITY, f64::min);
+    let size = jacobian.len().max(jacobian[0].len());
+    if smallest <= size as f64 * f64::EPSILON * largest {
+        return Ok(f64::INFINITY);
+    }
+    Ok(largest / smallest)
+}
+
+#[derive(Debug)]
+enum ConditionError {
+    /// `x` is empty, so there is no Jacobian
+    NoInputs,
+    /// `f` has no outputs, so there is no Jacobian
+    NoOutputs,
+}
+impl std::fmt::Display for ConditionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConditionError::NoInputs => write!(f, "no inputs to differentiate"),
+            ConditionError::NoOutputs => write!(f, "the function has no outputs"),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum SolveError {
+    /// f(x0) == f(x1), so the secant through the last two iterates is horizontal
+    Stalled { x: f64 },
+    /// No iterate met the tolerance within the iteration budget
+    NotConverged { x: f64 },
+}
+impl std::fmt::Display for SolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SolveError::Stalled { x } => write!(f, "secant is horizontal at x = {}", x),
+            SolveError::NotConverged { x } => write!(f, "not converged, last x = {}", x),
+        }
+    }
+}
+
+/// Find a root of `f` with the secant method, starting from `x0` and `x1`
+fn secant_root(
+    f: &dyn Fn(f64) -> f64,
+    x0: f64,
+    x1: f64,
+    tol: f64,
+    max_iter: usize,
+) -> Result<f64, SolveError> {
+    let (mut x0, mut x1) = (x0, x1);
+    let (mut f0, mut f1) = (f(x0), f(x1));
+    for _ in 0..max_iter {
+        if f1 == f0 {
+            return Err(SolveError::Stalled { x: x1 });
+        }
+        let x2 = x1 - f1 * (x1 - x0) / (f1 - f0);
+        (x0, f0) = (x1, f1);
+        (x1, f1) = (x2, f(x2));
+        if (x1 - x0).abs() < tol || f1.abs() < tol {
+            return Ok(x1);
+        }
+    }
+    Err(SolveError::NotConverged { x: x1 })
+}
+
+/// Hyper-dual number `re + e1 ε1 + e2 ε2 + e1e2 ε1ε2` with `ε1² = ε2² = 0`.
+/// Seeding `e1` and `e2` with directions `u` and `v` makes `e1e2` the exact
+/// second directional derivative `uᵀ H v`.
+#[derive(Debug, Clone, Copy)]
+struct HyperDual {
+    re: f64,
+    e1: f64,
+    e2: f64,
+    e1e2: f64,
+}
+
+impl HyperDual {
+    fn constant(value: f64) -> Self {
+        Self {
+            re: value,
+            e1: 0.0,
+            e2: 0.0,
+            e1e2: 0.0,
+        }
+    }
+
+    /// Apply a scalar function given its value, first and second derivative at `re`
+    fn apply(self, value: f64, d: f64, d2: f64) -> Self {
+        Self {
+            re: value,
+            e1: d * self.e1,
+            e2: d * self.e2,
+            e1e2: d * self.e1e2 + d2 * self.e1 * self.e2,
+        }
+    }
+
+    fn sin(self) -> Self {
+        self.apply(self.re.sin(), self.re.cos(), -self.re.sin())
+    }
+
+    fn cos(self) -> Self {
+        self.apply(self.re.cos(), -self.re.sin(), -self.re.cos())
+    }
+
+    fn exp(self) -> Self {
+        let e = self.re.exp();
+        self.apply(e, e, e)
+    }
+
+    fn ln(self) -> Self {
+        self.apply(self.re.ln(), 1.0 / self.re, -1.0 / self.re.powi(2))
+    }
+
+    fn powi(self, n: i32) -> Self {
+        let n_f = n as f64;
+        self.apply(
+            self.re.powi(n),
+            n_f * self.re.powi(n - 1),
+            n_f * (n_f - 1.0) * self.re.powi(n - 2),
+        )
+    }
+
+    fn recip(self) -> Self {
+        self.apply(1.0 / self.re, -1.0 / self.re.powi(2), 2.0 / self.re.powi(3))
+    }
+}
+
+impl Add for HyperDual {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self {
+            re: self.re + other.re,
+            e1: self.e1 + other.e1,
+            e2: self.e2 + other.e2,
+            e1e2: self.e1e2 + other.e1e2,
+        }
+    }
+}
+
+impl Sub for HyperDual {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Self {
+            re: self.re - other.re,
+            e1: self.e1 - other.e1,
+            e2: self.e2 - other.e2,
+            e1e2: self.e1e2 - other.e1e2,
+        }
+    }
+}
+
+impl Mul for HyperDual {
+    type Output = Self;
+    fn mul(self, other: Self) -> Self {
+        Self {
+            re: self.re * other.re,
+            e1: self.re * other.e1 + self.e1 * other.re,
+            e2: self.re * other.e2 + self.e2 * other.re,
+            e1e2: self.re * other.e1e2
+                + self.e1 * other.e2
+                + self.e2 * other.e1
+                + self.e1e2 * other.re,
+        }
+    }
+}
+
+impl Div for HyperDual {
+    type Output = Self;
+    #[allow(clippy::suspicious_arithmetic_impl)]
+    fn div(self, other: Self) -> Self {
+        self * other.recip()
+    }
+}
+
+/// Exact Hessian of a scalar function, from one hyper-dual evaluation per entry
+fn hyperdual_hessian(f: &dyn Fn(&[HyperDual]) -> HyperDual, x: &[f64]) -> Vec<Vec<f64>> {
+    let second_derivative = |i: usize, j: usize| {
+        let point = x
+            .iter()
+            .enumerate()
+            .map(|(k, &value)| HyperDual {
+                e1: if k == i { 1.0 } else { 0.0 },
+                e2: if k == j { 1.0 } else { 0.0 },
+                ..HyperDual::constant(value)
+            })
+            .collect::<Vec<HyperDual>>();
+        f(&point).e1e2
+    };
+    (0..x.len())
+        .map(|i| (0..x.len()).map(|j| second_derivative(i, j)).collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::{FRAC_1_SQRT_2, FRAC_PI_3, FRAC_PI_4};
+
+    fn cube(x: &[f64]) -> Vec<f64> {
+        x.iter().map(|&i| i.powi(3)).collect()
+    }
+
+    #[test]
+    fn polynomials() {
+        let derivative = numerical_derivative(&cube, &[6.0], None, FiniteDiffMode::Forward);
+        assert_jacobian_eq(&derivative, &[&[108.0]], 1e-3);
+        let f = |x: &[f64]| {
+            x.iter()
+                .map(|&i| i.powi(3) + 4.0 * i.powi(2) - 12.0)
+                .collect()
+        };
+        let derivative = numerical_derivative(&f, &[2.0], Some(1e-5), FiniteDiffMode::Forward);
+        assert_jacobian_eq(&derivative, &[&[28.0]], 1e-3);
+    }
+
+    #[test]
+    fn vector_functions() {
+        let f = |x: &[f64]| vec![x[0].sin() + x[1].cos(), x[0].cos() - x[1].sin()];
+        let derivative =
+            numerical_derivative(&f, &[FRAC_PI_4, FRAC_PI_3], None, FiniteDiffMode::Forward);
+        assert_jacobian_eq(
+            &derivative,
+            &[
+                &[FRAC_1_SQRT_2, -FRAC_1_SQRT_2],
+                &[-(3f64.sqrt()) / 2.0, -0.5],
+            ],
+            1e-3,
+        );
+
+        let f = |x: &[f64]| {
+            vec![
+                x[0].powi(2) + x[1].powi(2) + x[2].powi(2),
+                x[0] + x[1] + x[2],
+            ]
+        };
+        let derivative = numerical_derivative(&f, &[1.0, 2.0, 3.0], None, FiniteDiffMode::Forward);
+        assert_jacobian_eq(&derivative, &[&[2.0, 1.0], &[4.0, 1.0], &[6.0, 1.0]], 1e-3);
+
+        let f = |x: &[f64]| {
+            vec![
+                x[3] * (x[0].powi(2).sin()) + x[1].powi(2) * x[2],
+                x[0] * x[3] * x[2].powi(3) * x[1].tan(),
+                x[0],
+                69.0,
+                -5.0 * x[2],
+            ]
+        };
+        let v = [FRAC_PI_4, FRAC_PI_3, 2.0, 3.0];
+        let derivative = numerical_derivative(&f, &v, None, FiniteDiffMode::Forward);
+        let expected: &[&[f64]] = &[
+            &[3.84391697914949, 41.5692193816531, 1.0, 0.0, 0.0],
+            &[4.18879020478639, 75.398223686155, 0.0, 0.0, 0.0],
+            &[1.09662271123215, 48.9725828343239, 0.0, 0.0, -5.0],
+            &[0.578468789354558, 10.8827961854053, 0.0, 0.0, 0.0],
+        ];
+        assert_jacobian_eq(&derivative, expected, 1e-3);
+        let worst = relative_jacobian_error(&derivative, expected)
+            .into_iter()
+            .flatten()
+            .fold(0.0, f64::max);
+        assert!(worst < 1e-5);
+    }
+
+    #[test]
+    fn every_stencil() {
+        for mode in [
+            FiniteDiffMode::Forward,
+            FiniteDiffMode::Backward,
+            FiniteDiffMode::Central,
+        ] {
+            let derivative = numerical_derivative(&cube, &[6.0], None, mode);
+            assert_jacobian_eq(&derivative, &[&[108.0]], 1e-3);
+        }
+    }
+
+    #[test]
+    fn default_step_at_zero_and_negative_inputs() {
+        // The default step is relative to max(|x_i|, 1), so it is positive even at x_i <= 0
+        let f = |x: &[f64]| vec![x[0].powi(3) + x[1].powi(2)];
+        for mode in [FiniteDiffMode::Forward, FiniteDiffMode::Central] {
+            let derivative = numerical_derivative(&f, &[0.0, -2.0], None, mode);
+            assert_jacobian_eq(&derivative, &[&[0.0], &[-4.0]], 1e-6);
+        }
+        // A forward difference stays on the right of a kink at a negative x
+        let f = |x: &[f64]| vec![x[0].max(-1.0)];
+        let derivative = numerical_derivative(&f, &[-1.0], None, FiniteDiffMode::Forward);
+        assert_jacobian_eq(&derivative, &[&[1.0]], 1e-6);
+    }
+
+    #[test]
+    fn per_variable_steps() {
+        // x in microns and y in kilometers
+        let f = |x: &[f64]| vec![1e-6 * (1e6 * x[0]).sin() * (x[1] / 1e3).cos()];
+        let exact = [1f64.cos().powi(2), -1e-9 * 1f64.sin().powi(2)];
+        let x = [1e-6, 1e3];
+        let derivative =
+            numerical_derivative_scaled(&f, &x, &[Some(1e-14), None], FiniteDiffMode::Central);
+        let ratios = derivative
+            .iter()
+            .zip(exact)
+            .map(|(row, exact)| vec![row[0] / exact])
+            .collect::<Vec<Vec<f64>>>();
+        assert_jacobian_eq(&ratios, &[&[1.0], &[1.0]], 1e-3);
+    }
+
+    #[test]
+    fn backward_difference_at_a_domain_boundary() {
+        let f = |x: &[f64]| {
+            x.iter()
+                .map(|&i| if i > 1.0 { f64::NAN } else { i.powi(3) })
+                .collect()
+        };
+        let derivative = numerical_derivative(&f, &[1.0], Some(1e-6), FiniteDiffMode::Backward);
+        assert_jacobian_eq(&derivative, &[&[3.0]], 1e-3);
+        let forward = numerical_derivative(&f, &[1.0], Some(1e-6), FiniteDiffMode::Forward);
+        assert!(forward[0][0].is_nan());
+    }
+
+    #[test]
+    fn secant_roots() {
+        let f = |x: f64| x.powi(2) - 2.0;
+        let root = secant_root(&f, 1.0, 2.0, 1e-12, 50).unwrap();
+        assert!((root - std::f64::consts::SQRT_2).abs() < 1e-12);
+        // f(-1) = f(1), so the first secant is horizontal
+        assert!(matches!(
+            secant_root(&f, -1.0, 1.0, 1e-12, 50),
+            Err(SolveError::Stalled { .. })
+        ));
+    }
+
+    #[test]
+    fn hyperdual_hessian_matches_finite_differences() {
+        let f = |x: &[HyperDual]| x[0] * x[0] * x[1] + x[0].sin();
+        let hessian = hyperdual_hessian(&f, &[1.0, 2.0]);
+        assert_jacobian_eq(&hessian, &[&[4.0 - 1f64.sin(), 2.0], &[2.0, 0.0]], 1e-12);
+        let f = |x: &[f64]| vec![x[0].powi(2) * x[1] + x[0].sin()];
+        let gradient = |x: &[f64]| {
+            numerical_derivative(&f, x, Some(1e-5), FiniteDiffMode::Central)
+                .iter()
+                .map(|column| column[0])
+                .collect()
+        };
+        let numerical =
+            numerical_derivative(&gradient, &[1.0, 2.0], Some(1e-4), FiniteDiffMode::Central);
+        let hessian = hessian.iter().map(Vec::as_slice).collect::<Vec<&[f64]>>();
+        assert_jacobian_eq(&numerical, &hessian, 1e-5);
+    }
+
+    #[test]
+    fn hyperdual_transcendental_functions() {
+        // f([x, y]) = exp(x)ln(y) + cos(x)y^3 - x/y
+        let (x, y) = (0.5f64, 2.0f64);
+        let f = |x: &[HyperDual]| x[0].exp() * x[1].ln() + x[0].cos() * x[1].powi(3) - x[0] / x[1];
+        let mixed = x.exp() / y - 3.0 * x.sin() * y.powi(2) + 1.0 / y.powi(2);
+        let exact: &[&[f64]] = &[
+            &[x.exp() * y.ln() - x.cos() * y.powi(3), mixed],
+            &[
+                mixed,
+                -x.exp() / y.powi(2) + 6.0 * x.cos() * y - 2.0 * x / y.powi(3),
+            ],
+        ];
+        assert_jacobian_eq(&hyperdual_hessian(&f, &[x, y]), exact, 1e-12);
+    }
+
+    #[test]
+    fn dual_numbers() {
+        let f = |x: &[Dual<f64>]| vec![x[0].sin() + x[1].cos(), x[0].cos() - x[1].sin()];
+        let v = [FRAC_PI_4, FRAC_PI_3];
+        let derivative = vec![forward_derivative(f, &v, 0), forward_derivative(f, &v, 1)];
+        let exact: &[&[f64]] = &[
+            &[FRAC_PI_4.cos(), -FRAC_PI_4.sin()],
+            &[-FRAC_PI_3.sin(), -FRAC_PI_3.cos()],
+        ];
+        assert_jacobian_eq(&derivative, exact, 1e-15);
+
+        let f = |x: &[Dual<f64>]| x[0].powf(x[1]) + x[1] * x[0].ln();
+        let gradient = grad(f, &[2.0, 3.0]);
+        assert_jacobian_eq(&[gradient], &[&[13.5, 9.0 * 2f64.ln()]], 1e-15);
+    }
+
+    #[test]
+    fn adaptive_step() {
+        assert_jacobian_eq(&adaptive_derivative(&cube, &[6.0]), &[&[108.0]], 1e-8);
+        // Each trial step moves one coordinate, so the cost is linear in the number of inputs
+        let calls = std::cell::Cell::new(0);
+        let f = |x: &[f64]| {
+            calls.set(calls.get() + 1);
+            vec![x.iter().map(|x_i| x_i.powi(3)).sum()]
+        };
+        adaptive_derivative(&f, &[1.0]);
+        let one_input = calls.replace(0);
+        let derivative = adaptive_derivative(&f, &[1.0, 2.0, 3.0, 4.0]);
+        assert_jacobian_eq(&derivative, &[&[3.0], &[12.0], &[27.0], &[48.0]], 1e-8);
+        assert_eq!(calls.get(), 4 * one_input);
+    }
+
+    #[test]
+    fn condition_numbers() {
+        // f' = [[1, 1], [1, 1 + ε]] with singular values σ = sqrt(λ) for the eigenvalues λ of
+        // f'ᵀf', so κ = sqrt(λ_max / λ_min) = λ_max / sqrt(det(f'ᵀf'))
+        let nearly_singular = |epsilon: f64| {
+            let f = move |x: &[f64]| vec![x[0] + x[1], x[0] + (1.0 + epsilon) * x[1]];
+            let trace = 2.0 + 1.0 + (1.0 + epsilon).powi(2);
+            let det = epsilon.powi(2);
+            let largest = trace / 2.0 + (trace.powi(2) / 4.0 - det).sqrt();
+            (f, largest / det.sqrt())
+        };
+        let (f, exact) = nearly_singular(1e-4);
+        let condition = jacobian_condition_number(&f, &[1.0, 2.0], None).unwrap();
+        assert!((condition / exact - 1.0).abs() < 1e-3);
+        // Beyond 1 / sqrt(EPSILON), still finite; f is linear, so a large step is exact enough
+        let (f, exact) = nearly_singular(1e-9);
+        let condition = jacobian_condition_number(&f, &[1.0, 2.0], Some(0.5)).unwrap();
+        assert!((condition / exact - 1.0).abs() < 1e-3);
+
+        let f = |x: &[f64]| vec![x[0] + x[1], 2.0 * x[0] + 2.0 * x[1]];
+        let condition = jacobian_condition_number(&f, &[1.0, 2.0], None).unwrap();
+        assert!(condition.is_infinite());
+
+        assert!(matches!(
+            jacobian_condition_number(&|_| vec![1.0], &[], None),
+            Err(ConditionError::NoInputs)
+        ));
+        assert!(matches!(
+            jacobian_condition_number(&|_| vec![], &[1.0], None),
+            Err(ConditionError::NoOutputs)
+        ));
+    }
+
+    #[test]
+    fn tolerances_scale_with_the_expected_value() {
+        assert!(close(1e6 + 1.0, 1e6, 1e-8, 1e-5));
+        assert!(!close(-1e6, 1e6, 1e-8, 1e-5));
+        assert!(!close(f64::NAN, 0.0, 1.0, 1.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "worst at [1][0]")]
+    fn jacobian_mismatches_point_at_the_worst_entry() {
+        assert_jacobian_eq(
+            &[vec![1.0, 2.0], vec![3.5, 4.0]],
+            &[&[1.0, 2.1], &[3.0, 4.0]],
+            1e-3,
+        );
+    }
+}